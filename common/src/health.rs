@@ -0,0 +1,159 @@
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+// process wide state of the long running loops, keyed by name
+static REGISTRY: Lazy<Mutex<BTreeMap<String, State>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone)]
+struct State {
+    live: bool,
+    ready: bool,
+    detail: String,
+    // the probe owning the state, a loop restarted under the same name takes it over
+    generation: u64,
+}
+
+// Probe is held by a long running loop, e.g. a worker, a stream or an output, the loop
+// is live while it holds the probe and dead once the probe is dropped, as on a panic
+pub struct Probe {
+    name: String,
+    generation: u64,
+}
+
+impl Probe {
+    // a live and ready loop
+    pub fn new(name: &str) -> Self {
+        let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+        with_registry(|registry| {
+            registry.insert(
+                name.to_string(),
+                State {
+                    live: true,
+                    ready: true,
+                    detail: "".to_string(),
+                    generation,
+                },
+            );
+        });
+        Self {
+            name: name.to_string(),
+            generation,
+        }
+    }
+
+    pub fn ready(&self) {
+        self.set(true, "")
+    }
+
+    // live, but not able to do its work, e.g. the stream is reconnecting
+    pub fn unready(&self, detail: &str) {
+        self.set(false, detail)
+    }
+
+    fn set(&self, ready: bool, detail: &str) {
+        with_registry(|registry| {
+            if let Some(state) = registry.get_mut(&self.name) {
+                if state.generation == self.generation {
+                    state.ready = ready;
+                    state.detail = detail.to_string();
+                }
+            }
+        })
+    }
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        let detail = if thread::panicking() {
+            "panicked"
+        } else {
+            "exited"
+        };
+        with_registry(|registry| {
+            if let Some(state) = registry.get_mut(&self.name) {
+                if state.generation == self.generation {
+                    state.live = false;
+                    state.ready = false;
+                    state.detail = detail.to_string();
+                }
+            }
+        })
+    }
+}
+
+fn with_registry<F: FnOnce(&mut BTreeMap<String, State>)>(f: F) {
+    let mut registry = match REGISTRY.lock() {
+        Ok(it) => it,
+        Err(e) => e.into_inner(),
+    };
+    f(&mut registry)
+}
+
+// whether every loop is live and ready, and the state of each of them,
+// the expected loops that never started are neither
+pub fn check(expected: &[&str]) -> (bool, bool, Value) {
+    let mut registry = BTreeMap::new();
+    with_registry(|it| registry = it.clone());
+    for name in expected.iter() {
+        registry.entry(name.to_string()).or_insert(State {
+            live: false,
+            ready: false,
+            detail: "not started".to_string(),
+            generation: 0,
+        });
+    }
+    let mut components = Map::new();
+    for (name, state) in registry.iter() {
+        components.insert(
+            name.clone(),
+            json!({ "live": state.live, "ready": state.ready, "detail": state.detail }),
+        );
+    }
+    (
+        registry.values().all(|state| state.live),
+        registry.values().all(|state| state.live && state.ready),
+        Value::Object(components),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_tracks_loops() {
+        let worker = Probe::new("test-worker");
+        let stream = Probe::new("test-stream");
+        stream.unready("connecting");
+
+        let expected = ["test-worker", "test-stream", "test-scanner"];
+        let (_, _, components) = check(&expected);
+        assert_eq!(
+            components["test-stream"],
+            json!({"live": true, "ready": false, "detail": "connecting"})
+        );
+        assert_eq!(components["test-scanner"]["detail"], "not started");
+
+        // a restarted loop takes the name over, the old probe does not mark it dead
+        let restarted = Probe::new("test-worker");
+        drop(worker);
+        assert_eq!(check(&[]).2["test-worker"]["live"], true);
+
+        let panicked = thread::spawn(move || {
+            let _probe = restarted;
+            panic!("worker failed");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert_eq!(
+            check(&[]).2["test-worker"],
+            json!({"live": false, "ready": false, "detail": "panicked"})
+        );
+    }
+}
@@ -1,8 +1,12 @@
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex, RwLock};
 
 use serde_json::Value;
 
+pub mod health;
+pub mod metrics;
+
 pub fn new_arc_rwlock<T>(t: T) -> Arc<RwLock<T>> {
     Arc::new(RwLock::new(t))
 }
@@ -11,6 +15,27 @@ pub fn new_arc_mutex<T>(t: T) -> Arc<Mutex<T>> {
     Arc::new(Mutex::new(t))
 }
 
+// Runtime is the container runtime whose log layout and line format are collected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Runtime {
+    // <docker_dir>/<id>/config.v2.json + <id>-json.log in json-file format
+    Docker,
+    // /var/log/pods/<ns>_<pod>_<uid>/<container>/N.log in CRI format
+    Cri,
+}
+
+impl FromStr for Runtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "docker" => Ok(Runtime::Docker),
+            "cri" | "containerd" => Ok(Runtime::Cri),
+            _ => Err(format!("unknown runtime `{}`, expect docker or cri", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Item {
     JSON(Value),
@@ -28,6 +53,13 @@ impl<'a> From<&'a str> for Item {
     }
 }
 
+// the envelope built by the reader is shipped as it is, without a round trip through a string
+impl From<Value> for Item {
+    fn from(value: Value) -> Self {
+        Item::JSON(value)
+    }
+}
+
 impl Item {
     pub fn is_valid_json(str: &str) -> Result<(bool, Value)> {
         match serde_json::from_str::<Value>(str) {
@@ -43,6 +75,16 @@ impl Item {
         }
     }
 
+    // the value of the dotted path, e.g. custom.namespace, in a json item
+    pub fn field(&self, path: &str) -> Option<&Value> {
+        match self {
+            Item::JSON(value) => path
+                .split('.')
+                .try_fold(value, |value, key| value.as_object()?.get(key)),
+            Item::Default(_) => None,
+        }
+    }
+
     pub fn string(&self) -> String {
         match self {
             Item::JSON(_str) => _str.to_string(),
@@ -69,5 +111,15 @@ mod tests {
         if !item.is_json() {
             panic!(r#"not expect json object"#)
         }
+        assert_eq!(item.field("age"), Some(&Value::from(88)));
+        assert_eq!(item.field("name.first"), None);
+        assert_eq!(Item::from("raw").field("name"), None);
+    }
+
+    #[test]
+    fn runtime_from_str() {
+        assert_eq!("docker".parse::<Runtime>(), Ok(Runtime::Docker));
+        assert_eq!("containerd".parse::<Runtime>(), Ok(Runtime::Cri));
+        assert!("rkt".parse::<Runtime>().is_err());
     }
 }
@@ -0,0 +1,207 @@
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// process wide registry of counters and gauges, keyed by name and labels
+static REGISTRY: Lazy<Mutex<BTreeMap<(String, Labels), Entry>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub type Labels = Vec<(String, String)>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+struct Entry {
+    kind: Kind,
+    help: &'static str,
+    value: Arc<AtomicI64>,
+}
+
+// Metric is a handle on a registered value, clones share it
+#[derive(Debug, Clone)]
+pub struct Metric(Arc<AtomicI64>);
+
+impl Metric {
+    pub fn inc(&self) {
+        self.add(1)
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, n: i64) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Sample is the value of one metric at the time of the snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub kind: Kind,
+    pub help: &'static str,
+    pub labels: Labels,
+    pub value: i64,
+}
+
+fn register(kind: Kind, name: &str, help: &'static str, labels: &[(&str, &str)]) -> Metric {
+    let labels = labels
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<Labels>();
+    let mut registry = match REGISTRY.lock() {
+        Ok(it) => it,
+        Err(e) => e.into_inner(),
+    };
+    let entry = registry
+        .entry((name.to_string(), labels))
+        .or_insert_with(|| Entry {
+            kind,
+            help,
+            value: Arc::new(AtomicI64::new(0)),
+        });
+    Metric(entry.value.clone())
+}
+
+// the counter of name and labels, registered on first use
+pub fn counter(name: &str, help: &'static str, labels: &[(&str, &str)]) -> Metric {
+    register(Kind::Counter, name, help, labels)
+}
+
+// the gauge of name and labels, registered on first use
+pub fn gauge(name: &str, help: &'static str, labels: &[(&str, &str)]) -> Metric {
+    register(Kind::Gauge, name, help, labels)
+}
+
+// remove the metrics carrying every one of labels, e.g. of a pod that is gone,
+// a metric still held by a handle stays as its owner keeps updating it
+pub fn unregister(labels: &[(&str, &str)]) {
+    if let Ok(mut registry) = REGISTRY.lock() {
+        registry.retain(|(_, registered), entry| {
+            let matched = labels
+                .iter()
+                .all(|(k, v)| registered.iter().any(|(rk, rv)| rk == k && rv == v));
+            !matched || Arc::strong_count(&entry.value) > 1
+        });
+    }
+}
+
+// all metrics sorted by name and labels
+pub fn snapshot() -> Vec<Sample> {
+    let registry = match REGISTRY.lock() {
+        Ok(it) => it,
+        Err(e) => e.into_inner(),
+    };
+    registry
+        .iter()
+        .map(|((name, labels), entry)| Sample {
+            name: name.clone(),
+            kind: entry.kind,
+            help: entry.help,
+            labels: labels.clone(),
+            value: entry.value.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+// all metrics in the prometheus text exposition format
+pub fn render() -> String {
+    let mut text = String::new();
+    let mut name = "".to_string();
+    for sample in snapshot() {
+        if sample.name != name {
+            let kind = match sample.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            let help = sample.help.replace('\\', "\\\\").replace('\n', "\\n");
+            text.push_str(&format!("# HELP {} {}\n", sample.name, help));
+            text.push_str(&format!("# TYPE {} {}\n", sample.name, kind));
+            name = sample.name.clone();
+        }
+        text.push_str(&sample.name);
+        if !sample.labels.is_empty() {
+            let labels = sample
+                .labels
+                .iter()
+                .map(|(k, v)| {
+                    let v = v
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    format!("{}=\"{}\"", k, v)
+                })
+                .collect::<Vec<String>>();
+            text.push_str(&format!("{{{}}}", labels.join(",")));
+        }
+        text.push_str(&format!(" {}\n", sample.value));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_registers_once() {
+        let a = counter("test_lines_total", "lines", &[("pod", "a")]);
+        let b = counter("test_lines_total", "lines", &[("pod", "a")]);
+        a.inc();
+        b.add(2);
+        gauge("test_depth", "depth", &[("pod", "a")]).set(7);
+
+        let samples = snapshot()
+            .into_iter()
+            .filter(|s| s.name.starts_with("test_"))
+            .collect::<Vec<Sample>>();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].name, "test_depth");
+        assert_eq!(samples[0].value, 7);
+        assert_eq!(samples[1].kind, Kind::Counter);
+        assert_eq!(samples[1].value, 3);
+
+        // the counter is still held
+        unregister(&[("pod", "a")]);
+        assert_eq!(
+            snapshot()
+                .iter()
+                .filter(|s| s.name.starts_with("test_"))
+                .count(),
+            1
+        );
+        drop((a, b));
+        unregister(&[("pod", "a")]);
+        assert!(!snapshot().iter().any(|s| s.name.starts_with("test_")));
+    }
+
+    #[test]
+    fn it_renders_text_format() {
+        counter("render_lines_total", "lines read", &[("pod", "a\"b")]).add(2);
+        counter("render_lines_total", "lines read", &[("pod", "c")]).inc();
+        gauge("render_depth", "queue depth", &[]).set(4);
+
+        let text = render();
+        let start = text.find("# HELP render_depth").unwrap();
+        let end = text.find("# HELP render_lines_total").unwrap();
+        assert_eq!(
+            &text[start..end],
+            "# HELP render_depth queue depth\n# TYPE render_depth gauge\nrender_depth 4\n"
+        );
+        assert!(text[end..].starts_with(
+            "# HELP render_lines_total lines read\n\
+             # TYPE render_lines_total counter\n\
+             render_lines_total{pod=\"a\\\"b\"} 2\n\
+             render_lines_total{pod=\"c\"} 1\n"
+        ));
+    }
+}
@@ -0,0 +1,163 @@
+use common::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+const CHECKPOINT_FILE_NAME: &str = "offsets.log";
+// rewrite the log once it holds this many stale records more than live ones
+const COMPACT_THRESHOLD: usize = 1024;
+
+// one line of the append-only log, a `None` offset marks the path as deleted
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    path: String,
+    offset: Option<i64>,
+}
+
+// Checkpoint is an append-only offset log under the state dir,
+// the last record of a path wins when the log is replayed.
+pub struct Checkpoint {
+    path: PathBuf,
+    file: File,
+    offsets: HashMap<String, i64>,
+    records: usize,
+}
+
+impl Checkpoint {
+    pub fn open(state_dir: &str) -> Result<Self> {
+        fs::create_dir_all(state_dir)?;
+        let path = Path::new(state_dir).join(CHECKPOINT_FILE_NAME);
+
+        let mut offsets = HashMap::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                // a torn write at the tail of the log is skipped
+                let record = match serde_json::from_str::<Record>(&line) {
+                    Ok(it) => it,
+                    Err(_) => continue,
+                };
+                match record.offset {
+                    Some(offset) => offsets.insert(record.path, offset),
+                    None => offsets.remove(&record.path),
+                };
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut checkpoint = Self {
+            path,
+            file,
+            offsets,
+            records: 0,
+        };
+        checkpoint.compact()?;
+
+        Ok(checkpoint)
+    }
+
+    pub fn get(&self, uuid: &str) -> Option<i64> {
+        self.offsets.get(uuid).copied()
+    }
+
+    pub fn offsets(&self) -> &HashMap<String, i64> {
+        &self.offsets
+    }
+
+    pub fn set(&mut self, uuid: &str, offset: i64) -> Result<()> {
+        if self.offsets.get(uuid) == Some(&offset) {
+            return Ok(());
+        }
+        self.offsets.insert(uuid.to_string(), offset);
+        self.append(Record {
+            path: uuid.to_string(),
+            offset: Some(offset),
+        })
+    }
+
+    pub fn remove(&mut self, uuid: &str) -> Result<()> {
+        if self.offsets.remove(uuid).is_none() {
+            return Ok(());
+        }
+        self.append(Record {
+            path: uuid.to_string(),
+            offset: None,
+        })
+    }
+
+    fn append(&mut self, record: Record) -> Result<()> {
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.records += 1;
+
+        if self.records > self.offsets.len() + COMPACT_THRESHOLD {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // rewrite the log with only the live offsets, then swap it in place
+    fn compact(&mut self) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        for (path, offset) in self.offsets.iter() {
+            let mut line = serde_json::to_string(&Record {
+                path: path.clone(),
+                offset: Some(*offset),
+            })?;
+            line.push('\n');
+            tmp.write_all(line.as_bytes())?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = self.offsets.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Checkpoint;
+    use std::fs;
+
+    fn state_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("harvest-checkpoint-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn it_reloads_offsets() {
+        let dir = state_dir("reload");
+        {
+            let mut checkpoint = Checkpoint::open(&dir).unwrap();
+            checkpoint.set("/a-json.log", 10).unwrap();
+            checkpoint.set("/a-json.log", 20).unwrap();
+            checkpoint.set("/b-json.log", 5).unwrap();
+            checkpoint.remove("/b-json.log").unwrap();
+        }
+
+        let checkpoint = Checkpoint::open(&dir).unwrap();
+        assert_eq!(checkpoint.get("/a-json.log"), Some(20));
+        assert_eq!(checkpoint.get("/b-json.log"), None);
+        assert_eq!(checkpoint.offsets().len(), 1);
+    }
+
+    #[test]
+    fn it_compacts() {
+        let dir = state_dir("compact");
+        let mut checkpoint = Checkpoint::open(&dir).unwrap();
+        for offset in 0..3000 {
+            checkpoint.set("/a-json.log", offset).unwrap();
+        }
+        assert!(checkpoint.records <= super::COMPACT_THRESHOLD + 1);
+
+        let checkpoint = Checkpoint::open(&dir).unwrap();
+        assert_eq!(checkpoint.get("/a-json.log"), Some(2999));
+    }
+}
@@ -1,8 +1,9 @@
-use super::{new_arc_rwlock, Pod};
+use super::{new_arc_rwlock, Checkpoint, Pod};
 use async_std::task;
+use common::health::Probe;
 use crossbeam_channel::{unbounded, Sender};
 use event::{Dispatch, Listener};
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use std::{collections::HashMap, sync::Arc};
 use strum::AsRefStr;
 
@@ -16,6 +17,10 @@ pub enum Event {
     Delete,
     #[strum(serialize = "offset")]
     IncrOffset,
+    #[strum(serialize = "set_offset")]
+    SetOffset,
+    #[strum(serialize = "set_lag")]
+    SetLag,
     #[strum(serialize = "close")]
     Close,
 }
@@ -81,6 +86,8 @@ pub struct MemDatabase {
     pub(crate) tx: Sender<Message>,
     // db event dispatchers
     pub(crate) dispatchers: Arc<RwLock<MemDatabaseEventDispatcher>>,
+    // durable offsets, written through by the db thread once opened
+    pub(crate) checkpoint: Arc<Mutex<Option<Checkpoint>>>,
 }
 
 impl MemDatabase {
@@ -89,7 +96,10 @@ impl MemDatabase {
         let hm = new_arc_rwlock(HashMap::<UUID, Pod>::new());
         let t_hm = Arc::clone(&hm);
         let t_dispatchers = Arc::clone(&dispatchers);
+        let checkpoint = Arc::new(Mutex::new(None::<Checkpoint>));
+        let t_checkpoint = Arc::clone(&checkpoint);
         task::spawn(async move {
+            let _probe = Probe::new("db");
             while let Ok(msg) = rx.recv() {
                 let evt = msg.event;
                 let pod = msg.pod;
@@ -102,11 +112,24 @@ impl MemDatabase {
                     }
                 };
 
+                let mut checkpoint = match t_checkpoint.lock() {
+                    Ok(it) => it,
+                    Err(e) => {
+                        eprintln!("MemDatabase thread lock checkpoint failed, error:{:?}", e);
+                        continue;
+                    }
+                };
+
                 match evt {
                     Event::Update => {
-                        m.entry(pod.path.to_string())
-                            .or_insert(pod.clone())
-                            .merge_with(&pod);
+                        let inner = m.entry(pod.path.to_string()).or_insert(pod.clone());
+                        // the offset only moves by IncrOffset and SetOffset, the pod of an update
+                        // may be a copy taken before the reader committed its last records
+                        let offset = inner.offset;
+                        inner.merge_with(&pod);
+                        inner.offset = offset;
+                        let mut pod = pod;
+                        pod.offset = offset;
                         match pod.state {
                             crate::State::Running => {
                                 match t_dispatchers.write() {
@@ -128,16 +151,62 @@ impl MemDatabase {
                         }
                     }
                     Event::Delete => {
-                        if pod.ns != "" && pod.path == "" && pod.pod_name != "" {
-                            m.retain(|_, inner| !inner.compare_ns_pod(&pod));
-                            continue;
+                        let paths = if !pod.ns.is_empty()
+                            && pod.path.is_empty()
+                            && !pod.pod_name.is_empty()
+                        {
+                            m.iter()
+                                .filter(|(_, inner)| inner.compare_ns_pod(&pod))
+                                .map(|(path, _)| path.clone())
+                                .collect::<Vec<String>>()
+                        } else {
+                            vec![pod.path.clone()]
+                        };
+                        for path in paths.iter() {
+                            m.remove(path);
+                            if let Some(checkpoint) = checkpoint.as_mut() {
+                                if let Err(e) = checkpoint.remove(path) {
+                                    eprintln!(
+                                        "MemDatabase thread checkpoint delete failed, error:{:?}",
+                                        e
+                                    )
+                                }
+                            }
                         }
-                        m.remove(&pod.path);
                     }
                     Event::IncrOffset => {
                         if let Some(inner) = m.get_mut(&pod.path) {
                             inner.last_offset = pod.last_offset;
-                            inner.offset += pod.last_offset
+                            inner.offset += pod.last_offset;
+                            if let Some(checkpoint) = checkpoint.as_mut() {
+                                if let Err(e) = checkpoint.set(&inner.path, inner.offset) {
+                                    eprintln!(
+                                        "MemDatabase thread checkpoint offset failed, error:{:?}",
+                                        e
+                                    )
+                                }
+                            }
+                        };
+                    }
+                    Event::SetOffset => {
+                        if let Some(inner) = m.get_mut(&pod.path) {
+                            inner.last_offset = 0;
+                            inner.offset = pod.offset;
+                            if let Some(checkpoint) = checkpoint.as_mut() {
+                                if let Err(e) = checkpoint.set(&inner.path, inner.offset) {
+                                    eprintln!(
+                                        "MemDatabase thread checkpoint offset failed, error:{:?}",
+                                        e
+                                    )
+                                }
+                            }
+                        };
+                    }
+                    Event::SetLag => {
+                        if let Some(inner) = m.get_mut(&pod.path) {
+                            inner.size = pod.size;
+                            inner.lag = pod.lag;
+                            inner.lagging = pod.lagging;
                         };
                     }
 
@@ -145,7 +214,15 @@ impl MemDatabase {
                         break;
                     }
                     Event::Insert => {
-                        m.insert(pod.path.clone(), pod);
+                        let mut pod = pod;
+                        // resume from the last checkpointed offset after a restart
+                        if let Some(offset) = checkpoint.as_ref().and_then(|c| c.get(&pod.path)) {
+                            if pod.offset == 0 {
+                                pod.offset = offset;
+                            }
+                        }
+                        // a rotated log is recreated on the same path, keep the tracked pod
+                        m.entry(pod.path.clone()).or_insert(pod);
                     }
                 };
             }
@@ -155,16 +232,46 @@ impl MemDatabase {
             pods: hm,
             tx,
             dispatchers,
+            checkpoint,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Event;
+    use crate::{Event, Pod};
+    use std::thread;
+    use std::time::Duration;
+
     #[test]
     fn event_it_works() {
         assert_eq!(Event::Insert.as_ref(), "insert");
         assert_eq!(Event::Delete.as_ref(), "delete");
     }
+
+    #[test]
+    fn it_keeps_the_offset_on_update() {
+        let pod = Pod {
+            ns: "test-update".to_string(),
+            pod_name: "web-0".to_string(),
+            path: "/var/log/pods/test-update_web-0/web/0.log".to_string(),
+            ..Default::default()
+        };
+        crate::insert(&pod);
+        crate::set_offset(&pod.path, 100);
+        // a stale copy of the pod does not move the offset back
+        crate::update(&pod);
+        // the events are handled in order, the lag marks the update as done
+        crate::set_lag(&pod.path, 1, 0, false);
+        let mut tracked = None;
+        for _ in 0..100 {
+            tracked = crate::get(&pod.path).filter(|pod| pod.size == 1);
+            if tracked.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(tracked.map(|pod| pod.offset), Some(100));
+        crate::delete(&pod.path);
+    }
 }
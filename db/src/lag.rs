@@ -0,0 +1,161 @@
+use crate::Pod;
+use common::metrics;
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, Instant};
+
+const LAG_BYTES: &str = "harvest_file_lag_bytes";
+const LAGGING: &str = "harvest_file_lagging";
+
+// LagTracker stats the tracked files, the lag of a file is its size less the committed
+// offset, a file whose lag keeps growing for longer than the threshold is flagged lagging
+pub struct LagTracker {
+    threshold: Duration,
+    // the last lag of every path and since when it grows
+    growing: HashMap<String, (i64, Option<Instant>)>,
+    // the namespace, pod and container of every path, the labels of its gauges
+    labels: HashMap<String, [String; 3]>,
+}
+
+impl LagTracker {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            growing: HashMap::new(),
+            labels: HashMap::new(),
+        }
+    }
+
+    // stat every tracked path and record its size and lag on the pod
+    pub fn check(&mut self) {
+        let now = Instant::now();
+        let pods = crate::all_to_json().0;
+        for pod in pods.iter() {
+            let size = match fs::metadata(&pod.path) {
+                Ok(it) => it.len() as i64,
+                Err(_) => continue,
+            };
+            let (lag, lagging) = self.observe(now, pod, size);
+            crate::set_lag(&pod.path, size, lag, lagging);
+        }
+        self.forget(&pods);
+    }
+
+    // forget the paths that are not tracked anymore, with the gauges of their container
+    // unless another tracked path of the container still sets them
+    pub(crate) fn forget(&mut self, pods: &[Pod]) {
+        self.growing
+            .retain(|path, _| pods.iter().any(|pod| &pod.path == path));
+        let gone = self
+            .labels
+            .keys()
+            .filter(|path| !self.growing.contains_key(*path))
+            .cloned()
+            .collect::<Vec<String>>();
+        for path in gone {
+            let labels = match self.labels.remove(&path) {
+                Some(it) => it,
+                None => continue,
+            };
+            if self.labels.values().any(|other| other == &labels) {
+                continue;
+            }
+            let [ns, pod, container] = &labels;
+            metrics::unregister(&[
+                ("namespace", ns.as_str()),
+                ("pod", pod.as_str()),
+                ("container", container.as_str()),
+            ]);
+        }
+    }
+
+    // the lag of the pod at now and whether it is lagging
+    pub(crate) fn observe(&mut self, now: Instant, pod: &Pod, size: i64) -> (i64, bool) {
+        // a file rotated or truncated below the offset is read from its start again
+        let lag = (size - pod.offset).max(0);
+        let (last, since) = self.growing.entry(pod.path.clone()).or_insert((lag, None));
+        if lag == 0 || lag < *last {
+            *since = None;
+        } else if lag > *last && since.is_none() {
+            *since = Some(now);
+        }
+        *last = lag;
+        let lagging =
+            since.is_some_and(|since| now.saturating_duration_since(since) >= self.threshold);
+        self.labels.insert(
+            pod.path.clone(),
+            [pod.ns.clone(), pod.pod_name.clone(), pod.container.clone()],
+        );
+
+        let labels = [
+            ("namespace", pod.ns.as_str()),
+            ("pod", pod.pod_name.as_str()),
+            ("container", pod.container.as_str()),
+        ];
+        metrics::gauge(LAG_BYTES, "bytes of the file behind its end", &labels).set(lag);
+        metrics::gauge(
+            LAGGING,
+            "1 while the lag of the file keeps growing",
+            &labels,
+        )
+        .set(lagging as i64);
+        (lag, lagging)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LagTracker;
+    use crate::Pod;
+    use common::metrics;
+    use std::time::{Duration, Instant};
+
+    fn lag_of(pod: &Pod) -> Option<i64> {
+        metrics::snapshot()
+            .into_iter()
+            .find(|s| {
+                s.name == "harvest_file_lag_bytes"
+                    && s.labels
+                        .iter()
+                        .any(|(k, v)| k == "namespace" && v == &pod.ns)
+            })
+            .map(|s| s.value)
+    }
+
+    #[test]
+    fn it_flags_growing_lag() {
+        let now = Instant::now();
+        let at = |secs: u64| now + Duration::from_secs(secs);
+        let mut pod = Pod {
+            ns: "test-lag".to_string(),
+            pod_name: "web".to_string(),
+            path: "/var/log/pods/web.log".to_string(),
+            offset: 100,
+            ..Default::default()
+        };
+        let mut tracker = LagTracker::new(Duration::from_secs(60));
+        assert_eq!(tracker.observe(at(0), &pod, 100), (0, false));
+        assert_eq!(tracker.observe(at(10), &pod, 200), (100, false));
+        assert_eq!(tracker.observe(at(40), &pod, 300), (200, false));
+        // a steady lag does not end the growth, 60s after it started the file is lagging
+        assert_eq!(tracker.observe(at(70), &pod, 300), (200, true));
+
+        assert_eq!(lag_of(&pod), Some(200));
+
+        // an untracked file is not exported anymore
+        let mut other = pod.clone();
+        other.path = "/var/log/pods/web-1.log".to_string();
+        tracker.observe(at(70), &other, 300);
+        tracker.forget(&[other.clone()]);
+        assert!(lag_of(&pod).is_some());
+        tracker.forget(&[]);
+        assert_eq!(lag_of(&pod), None);
+
+        // catching up ends it
+        pod.offset = 250;
+        assert_eq!(tracker.observe(at(80), &pod, 300), (50, false));
+        assert_eq!(tracker.observe(at(90), &pod, 350), (100, false));
+        // a file truncated below the offset has no lag
+        assert_eq!(tracker.observe(at(100), &pod, 10), (0, false));
+    }
+}
@@ -1,15 +1,20 @@
 #[macro_use]
 extern crate lazy_static;
+mod checkpoint;
 mod database;
+mod lag;
 
 mod pod;
+use common::Result;
 use database::Message;
 use event::Listener;
 pub use pod::{GetPod, Pod, PodList, PodListMarshaller, State};
 
+pub use checkpoint::Checkpoint;
 pub use common::new_arc_rwlock;
 pub use database::Event;
 pub(crate) use database::{MemDatabase, MemDatabaseEventDispatcher};
+pub use lag::LagTracker;
 
 lazy_static! {
     static ref MEM: MemDatabase = {
@@ -18,6 +23,17 @@ lazy_static! {
     };
 }
 
+// open the offset checkpoint under state_dir, must be called before any pod is inserted
+// so that inserted pods resume from their checkpointed offset
+pub fn open_checkpoint(state_dir: &str) -> Result<()> {
+    let checkpoint = Checkpoint::open(state_dir)?;
+    match MEM.checkpoint.lock() {
+        Ok(mut it) => *it = Some(checkpoint),
+        Err(e) => eprintln!("{:?}", e),
+    }
+    Ok(())
+}
+
 pub fn incr_offset(uuid: &str, offset: i64) {
     MEM.tx
         .send(Message {
@@ -31,6 +47,36 @@ pub fn incr_offset(uuid: &str, offset: i64) {
         .unwrap()
 }
 
+// set_offset overrides the offset of the path, e.g. after the log file was rotated or truncated
+pub fn set_offset(uuid: &str, offset: i64) {
+    MEM.tx
+        .send(Message {
+            event: Event::SetOffset,
+            pod: Pod {
+                path: uuid.to_string(),
+                offset,
+                ..Default::default()
+            },
+        })
+        .unwrap()
+}
+
+// set_lag records the size of the path at its last stat and the lag of its committed offset
+pub fn set_lag(uuid: &str, size: i64, lag: i64, lagging: bool) {
+    MEM.tx
+        .send(Message {
+            event: Event::SetLag,
+            pod: Pod {
+                path: uuid.to_string(),
+                size,
+                lag,
+                lagging,
+                ..Default::default()
+            },
+        })
+        .unwrap()
+}
+
 pub fn update(pod: &Pod) {
     MEM.tx
         .send(Message {
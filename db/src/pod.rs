@@ -20,6 +20,14 @@ pub struct Pod {
     pub ips: Vec<String>,
     pub last_offset: i64,
     pub node_name: String,
+    // the size of the file at its last stat and how far the committed offset is behind it
+    #[serde(default)]
+    pub size: i64,
+    #[serde(default)]
+    pub lag: i64,
+    // the lag kept growing for longer than the lag threshold
+    #[serde(default)]
+    pub lagging: bool,
 }
 
 impl Pod {
@@ -106,6 +114,9 @@ impl Default for Pod {
             ips: Vec::new(),
             last_offset: 0,
             node_name: "".to_string(),
+            size: 0,
+            lag: 0,
+            lagging: false,
         }
     }
 }
@@ -0,0 +1,268 @@
+use common::Runtime;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// docker splits a log line longer than 16KB into several partial entries,
+// a reassembled line is capped so one runaway writer cannot exhaust memory.
+const MAX_LINE_SIZE: usize = 1024 * 1024;
+
+// LogRecord is one decoded line of a container log
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct LogRecord {
+    pub(crate) message: String,
+    pub(crate) stream: String,
+    pub(crate) time: String,
+}
+
+// Decode turns the lines of a log file into records
+pub(crate) trait Decode: Send {
+    // decode one line of the file, None means the line is a partial one kept for later
+    fn decode(&mut self, line: &str) -> Option<LogRecord>;
+
+    // whether a partial line of some stream waits for its continuation
+    fn pending(&self) -> bool;
+
+    // forget the partial lines, the file is read from another offset
+    fn reset(&mut self);
+}
+
+pub(crate) fn new_decoder(runtime: Runtime) -> Box<dyn Decode> {
+    match runtime {
+        Runtime::Docker => Box::new(DockerDecoder::new()),
+        Runtime::Cri => Box::new(CriDecoder::new()),
+    }
+}
+
+// {"log":"hello\n","stream":"stdout","time":"2021-03-16T09:05:01.461813069Z"}
+#[derive(Debug, Deserialize)]
+struct DockerLine {
+    log: String,
+    #[serde(default)]
+    stream: String,
+    #[serde(default)]
+    time: String,
+}
+
+// DockerDecoder decodes the json-file log driver envelope
+#[derive(Default)]
+pub(crate) struct DockerDecoder {
+    // the partial line of each stream, stdout and stderr interleave in one file
+    partial: HashMap<String, LogRecord>,
+}
+
+impl DockerDecoder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Decode for DockerDecoder {
+    fn decode(&mut self, line: &str) -> Option<LogRecord> {
+        let line = line.trim_end_matches('\n');
+        let docker_line = match serde_json::from_str::<DockerLine>(line) {
+            Ok(it) => it,
+            // not written by docker, ship the line as it is
+            Err(_) => {
+                return Some(LogRecord {
+                    message: line.to_string(),
+                    ..Default::default()
+                })
+            }
+        };
+
+        let complete = docker_line.log.ends_with('\n');
+        let record = match self.partial.remove(&docker_line.stream) {
+            Some(mut record) => {
+                record.message.push_str(&docker_line.log);
+                record
+            }
+            None => LogRecord {
+                message: docker_line.log,
+                stream: docker_line.stream,
+                time: docker_line.time,
+            },
+        };
+
+        if !complete && record.message.len() < MAX_LINE_SIZE {
+            self.partial.insert(record.stream.clone(), record);
+            return None;
+        }
+
+        Some(LogRecord {
+            message: record.message.trim_end_matches('\n').to_string(),
+            ..record
+        })
+    }
+
+    fn pending(&self) -> bool {
+        !self.partial.is_empty()
+    }
+
+    fn reset(&mut self) {
+        self.partial.clear()
+    }
+}
+
+// CriDecoder decodes the CRI log format written by containerd and cri-o:
+// <time> <stream> <P|F> <message>, P marks a partial line continued by the next one.
+#[derive(Default)]
+pub(crate) struct CriDecoder {
+    // the partial line of each stream
+    partial: HashMap<String, LogRecord>,
+}
+
+impl CriDecoder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Decode for CriDecoder {
+    fn decode(&mut self, line: &str) -> Option<LogRecord> {
+        let line = line.trim_end_matches('\n');
+        let parts = line.splitn(4, ' ').collect::<Vec<&str>>();
+        if parts.len() < 3 || (parts[2] != "P" && parts[2] != "F") {
+            // not written by the runtime, ship the line as it is
+            return Some(LogRecord {
+                message: line.to_string(),
+                ..Default::default()
+            });
+        }
+        let message = parts.get(3).copied().unwrap_or("");
+
+        let record = match self.partial.remove(parts[1]) {
+            Some(mut record) => {
+                record.message.push_str(message);
+                record
+            }
+            None => LogRecord {
+                message: message.to_string(),
+                stream: parts[1].to_string(),
+                time: parts[0].to_string(),
+            },
+        };
+
+        if parts[2] == "P" && record.message.len() < MAX_LINE_SIZE {
+            self.partial.insert(record.stream.clone(), record);
+            return None;
+        }
+        Some(record)
+    }
+
+    fn pending(&self) -> bool {
+        !self.partial.is_empty()
+    }
+
+    fn reset(&mut self) {
+        self.partial.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CriDecoder, Decode, DockerDecoder, LogRecord};
+
+    #[test]
+    fn it_decodes_docker_lines() {
+        let mut decoder = DockerDecoder::new();
+        let record = decoder.decode(
+            "{\"log\":\"hello\\n\",\"stream\":\"stderr\",\"time\":\"2021-03-16T09:05:01.461813069Z\"}\n",
+        );
+        assert_eq!(
+            record,
+            Some(LogRecord {
+                message: "hello".to_string(),
+                stream: "stderr".to_string(),
+                time: "2021-03-16T09:05:01.461813069Z".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn it_reassembles_partial_lines() {
+        let mut decoder = DockerDecoder::new();
+        assert_eq!(
+            decoder.decode("{\"log\":\"abc\",\"stream\":\"stdout\",\"time\":\"t1\"}"),
+            None
+        );
+        assert!(decoder.pending());
+        assert_eq!(
+            decoder.decode("{\"log\":\"def\",\"stream\":\"stdout\",\"time\":\"t2\"}"),
+            None
+        );
+        let record = decoder
+            .decode("{\"log\":\"ghi\\n\",\"stream\":\"stdout\",\"time\":\"t3\"}")
+            .unwrap();
+        assert_eq!(record.message, "abcdefghi");
+        assert_eq!(record.time, "t1");
+        assert!(!decoder.pending());
+    }
+
+    #[test]
+    fn it_keeps_raw_lines() {
+        let mut decoder = DockerDecoder::new();
+        let record = decoder.decode("plain text\n").unwrap();
+        assert_eq!(record.message, "plain text");
+        assert_eq!(record.stream, "");
+    }
+
+    #[test]
+    fn it_decodes_cri_lines() {
+        let mut decoder = CriDecoder::new();
+        let record = decoder
+            .decode("2021-03-16T09:05:01.461813069Z stderr F hello world\n")
+            .unwrap();
+        assert_eq!(
+            record,
+            LogRecord {
+                message: "hello world".to_string(),
+                stream: "stderr".to_string(),
+                time: "2021-03-16T09:05:01.461813069Z".to_string(),
+            }
+        );
+        assert_eq!(decoder.decode("t1 stdout F \n").unwrap().message, "");
+    }
+
+    #[test]
+    fn it_merges_cri_partial_lines() {
+        let mut decoder = CriDecoder::new();
+        assert_eq!(decoder.decode("t1 stdout P abc\n"), None);
+        assert_eq!(decoder.decode("t2 stdout P def\n"), None);
+        let record = decoder.decode("t3 stdout F ghi\n").unwrap();
+        assert_eq!(record.message, "abcdefghi");
+        assert_eq!(record.time, "t1");
+        assert!(!decoder.pending());
+    }
+
+    #[test]
+    fn it_keeps_partial_lines_per_stream() {
+        let mut decoder = CriDecoder::new();
+        assert_eq!(decoder.decode("t1 stdout P abc\n"), None);
+        let record = decoder.decode("t2 stderr F oops\n").unwrap();
+        assert_eq!(
+            (record.message.as_str(), record.stream.as_str()),
+            ("oops", "stderr")
+        );
+        assert!(decoder.pending());
+        assert_eq!(
+            decoder.decode("t3 stdout F def\n").unwrap().message,
+            "abcdef"
+        );
+
+        let mut decoder = DockerDecoder::new();
+        assert_eq!(
+            decoder.decode("{\"log\":\"abc\",\"stream\":\"stdout\"}"),
+            None
+        );
+        let record = decoder
+            .decode("{\"log\":\"oops\\n\",\"stream\":\"stderr\"}")
+            .unwrap();
+        assert_eq!(record.message, "oops");
+        // a reopened file does not continue the lines of the old one
+        decoder.reset();
+        let record = decoder
+            .decode("{\"log\":\"def\\n\",\"stream\":\"stdout\"}")
+            .unwrap();
+        assert_eq!(record.message, "def");
+    }
+}
@@ -0,0 +1,198 @@
+use crate::rules::{FieldOp, FieldPredicate, FilterRule};
+use common::{Item, Result};
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+
+// json fields holding the level of a structured log line
+const LEVEL_FIELDS: [&str; 3] = ["level", "severity", "lvl"];
+
+enum Predicate {
+    Eq(String, Value),
+    Ne(String, Value),
+    Exists(String),
+    Missing(String),
+    Regex(String, Regex),
+}
+
+// Filter decides whether a record of a pod is shipped
+pub(crate) struct Filter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    levels: Vec<String>,
+    level_regex: Option<Regex>,
+    predicates: Vec<Predicate>,
+}
+
+impl Filter {
+    pub(crate) fn new(rule: &FilterRule) -> Result<Self> {
+        let compile = |patterns: &Vec<String>| -> Result<Vec<Regex>> {
+            let mut regexes = vec![];
+            for pattern in patterns.iter() {
+                regexes.push(Regex::new(pattern)?);
+            }
+            Ok(regexes)
+        };
+
+        let level_regex = if rule.levels.is_empty() {
+            None
+        } else {
+            let levels = rule
+                .levels
+                .iter()
+                .map(|level| regex::escape(level))
+                .collect::<Vec<String>>();
+            Some(
+                RegexBuilder::new(&format!(r"\b({})\b", levels.join("|")))
+                    .case_insensitive(true)
+                    .build()?,
+            )
+        };
+
+        let mut predicates = vec![];
+        for predicate in rule.fields.iter() {
+            predicates.push(Self::predicate(predicate)?);
+        }
+
+        Ok(Self {
+            include: compile(&rule.include)?,
+            exclude: compile(&rule.exclude)?,
+            levels: rule
+                .levels
+                .iter()
+                .map(|level| level.to_lowercase())
+                .collect(),
+            level_regex,
+            predicates,
+        })
+    }
+
+    fn predicate(predicate: &FieldPredicate) -> Result<Predicate> {
+        let field = predicate.field.clone();
+        let value = || -> Result<Value> {
+            match &predicate.value {
+                Some(value) => Ok(value.clone()),
+                None => Err(format!("field predicate on `{}` needs a value", field).into()),
+            }
+        };
+        Ok(match predicate.op {
+            FieldOp::Eq => Predicate::Eq(field.clone(), value()?),
+            FieldOp::Ne => Predicate::Ne(field.clone(), value()?),
+            FieldOp::Exists => Predicate::Exists(field),
+            FieldOp::Missing => Predicate::Missing(field),
+            FieldOp::Regex => match value()? {
+                Value::String(pattern) => Predicate::Regex(field, Regex::new(&pattern)?),
+                _ => return Err(format!("regex predicate on `{}` needs a string", field).into()),
+            },
+        })
+    }
+
+    pub(crate) fn is_match(&self, message: &str) -> bool {
+        if !self.include.is_empty() && !self.include.iter().any(|r| r.is_match(message)) {
+            return false;
+        }
+        if self.exclude.iter().any(|r| r.is_match(message)) {
+            return false;
+        }
+        if self.level_regex.is_none() && self.predicates.is_empty() {
+            return true;
+        }
+
+        let item = Item::from(message);
+        if let Some(level_regex) = &self.level_regex {
+            let matched = match Self::level_of(&item) {
+                Some(level) => self.levels.contains(&level.to_lowercase()),
+                None => level_regex.is_match(message),
+            };
+            if !matched {
+                return false;
+            }
+        }
+        if self.predicates.is_empty() {
+            return true;
+        }
+
+        // field predicates only hold on structured lines
+        let value = match &item {
+            Item::JSON(value) => value,
+            Item::Default(_) => return false,
+        };
+        self.predicates.iter().all(|predicate| match predicate {
+            Predicate::Eq(field, expect) => lookup(value, field) == Some(expect),
+            Predicate::Ne(field, expect) => lookup(value, field) != Some(expect),
+            Predicate::Exists(field) => lookup(value, field).is_some(),
+            Predicate::Missing(field) => lookup(value, field).is_none(),
+            Predicate::Regex(field, regex) => match lookup(value, field) {
+                Some(Value::String(s)) => regex.is_match(s),
+                Some(other) => regex.is_match(&other.to_string()),
+                None => false,
+            },
+        })
+    }
+
+    fn level_of(item: &Item) -> Option<&str> {
+        match item {
+            Item::JSON(value) => LEVEL_FIELDS
+                .iter()
+                .find_map(|field| value.get(field).and_then(|level| level.as_str())),
+            Item::Default(_) => None,
+        }
+    }
+}
+
+// look up a dotted field path, e.g. `http.status`
+fn lookup<'a>(value: &'a Value, field: &str) -> Option<&'a Value> {
+    field
+        .split('.')
+        .try_fold(value, |value, key| value.as_object()?.get(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use crate::rules::Rules;
+
+    fn filter(rules: &str) -> Filter {
+        Filter::new(&Rules::parse(rules).unwrap().filter.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn it_filters_with_regex() {
+        let filter = filter(r#"{"filter":{"include":["order"],"exclude":["healthz"]}}"#);
+        assert!(filter.is_match("create order 1"));
+        assert!(!filter.is_match("GET /healthz order"));
+        assert!(!filter.is_match("create user 1"));
+    }
+
+    #[test]
+    fn it_filters_with_levels() {
+        let filter = filter(r#"{"filter":{"levels":["ERROR","warn"]}}"#);
+        assert!(filter.is_match("2021-03-16 ERROR boom"));
+        assert!(filter.is_match("[Warn] slow"));
+        assert!(!filter.is_match("2021-03-16 INFO ERRORS=0"));
+        assert!(filter.is_match(r#"{"level":"error","msg":"boom"}"#));
+        assert!(!filter.is_match(r#"{"level":"info","msg":"error"}"#));
+    }
+
+    #[test]
+    fn it_filters_with_fields() {
+        let filter = filter(
+            r#"{"filter":{"fields":[
+                {"field":"http.status","op":"ne","value":200},
+                {"field":"path","op":"regex","value":"^/api/"},
+                {"field":"debug","op":"missing"}
+            ]}}"#,
+        );
+        assert!(filter.is_match(r#"{"http":{"status":500},"path":"/api/order"}"#));
+        assert!(!filter.is_match(r#"{"http":{"status":200},"path":"/api/order"}"#));
+        assert!(!filter.is_match(r#"{"http":{"status":500},"path":"/static"}"#));
+        assert!(!filter.is_match(r#"{"http":{"status":500},"path":"/api/","debug":1}"#));
+        assert!(!filter.is_match("plain text"));
+    }
+
+    #[test]
+    fn it_rejects_bad_rules() {
+        assert!(Rules::parse(r#"{"filter":{"include":["("]}}"#).is_err());
+        assert!(Rules::parse(r#"{"filter":{"fields":[{"field":"a","op":"eq"}]}}"#).is_err());
+        assert!(Rules::parse(r#"{"filter":{"fields":[{"field":"a","op":"like"}]}}"#).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum FileChange {
+    Unchanged,
+    // the path now points at a new file (rename + recreate)
+    Rotated,
+    // the same file shrank below our offset (copytruncate)
+    Truncated,
+}
+
+// FileHandle is an open log file together with its identity,
+// so that rotation and truncation of the path can be detected.
+pub(crate) struct FileHandle {
+    path: String,
+    reader: BufReader<File>,
+    dev: u64,
+    ino: u64,
+    offset: i64,
+}
+
+impl FileHandle {
+    pub(crate) fn open(path: &str, offset: i64) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let mut handle = Self {
+            path: path.to_string(),
+            reader: BufReader::new(file),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            offset: 0,
+        };
+        // the file was truncated or replaced while nobody tracked it
+        if offset > 0 && offset <= metadata.len() as i64 {
+            handle.reader.seek(SeekFrom::Start(offset as u64))?;
+            handle.offset = offset;
+        }
+        Ok(handle)
+    }
+
+    pub(crate) fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    // read one line into bf and return its size, 0 means there is no complete line yet.
+    // a trailing line without newline is only returned when partial is set,
+    // otherwise it is left for the next read since the writer may still be appending.
+    pub(crate) fn read_line(&mut self, bf: &mut String, partial: bool) -> io::Result<usize> {
+        let mut line = vec![];
+        let size = self.reader.read_until(b'\n', &mut line)?;
+        if size > 0 && !partial && !line.ends_with(b"\n") {
+            self.reader.seek(SeekFrom::Current(-(size as i64)))?;
+            return Ok(0);
+        }
+        // invalid utf-8 must not stall the file, the offset still counts raw bytes
+        bf.push_str(&String::from_utf8_lossy(&line));
+        self.offset += size as i64;
+        Ok(size)
+    }
+
+    pub(crate) fn check(&self) -> io::Result<FileChange> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(it) => it,
+            // renamed away and not recreated yet, keep reading the old file
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(FileChange::Unchanged),
+            Err(e) => return Err(e),
+        };
+        if metadata.dev() != self.dev || metadata.ino() != self.ino {
+            return Ok(FileChange::Rotated);
+        }
+        if (metadata.len() as i64) < self.offset {
+            return Ok(FileChange::Truncated);
+        }
+        Ok(FileChange::Unchanged)
+    }
+
+    // open the file currently at path from the beginning
+    pub(crate) fn reopen(&mut self) -> io::Result<()> {
+        *self = Self::open(&self.path, 0)?;
+        Ok(())
+    }
+
+    // start over from the beginning of the same file
+    pub(crate) fn rewind(&mut self) -> io::Result<()> {
+        self.seek(0)
+    }
+
+    // read the same file again from offset
+    pub(crate) fn seek(&mut self, offset: i64) -> io::Result<()> {
+        self.reader.seek(SeekFrom::Start(offset as u64))?;
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileChange, FileHandle};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    fn log_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join("harvest-file-handle");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn append(path: &str, content: &str) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn it_waits_for_complete_lines() {
+        let path = log_path("partial-json.log");
+        append(&path, "a\nb");
+
+        let mut handle = FileHandle::open(&path, 0).unwrap();
+        let mut bf = String::new();
+        assert_eq!(handle.read_line(&mut bf, false).unwrap(), 2);
+        bf.clear();
+        assert_eq!(handle.read_line(&mut bf, false).unwrap(), 0);
+
+        append(&path, "c\n");
+        assert_eq!(handle.read_line(&mut bf, false).unwrap(), 3);
+        assert_eq!(bf, "bc\n");
+        assert_eq!(handle.offset(), 5);
+    }
+
+    #[test]
+    fn it_detects_rotation() {
+        let path = log_path("rotate-json.log");
+        append(&path, "a\n");
+
+        let mut handle = FileHandle::open(&path, 0).unwrap();
+        fs::rename(&path, format!("{}.1", path)).unwrap();
+        assert_eq!(handle.check().unwrap(), FileChange::Unchanged);
+
+        append(&path, "b\n");
+        assert_eq!(handle.check().unwrap(), FileChange::Rotated);
+
+        // the old file is still readable until it is drained
+        let mut bf = String::new();
+        handle.read_line(&mut bf, true).unwrap();
+        assert_eq!(bf, "a\n");
+
+        handle.reopen().unwrap();
+        assert_eq!(handle.offset(), 0);
+        assert_eq!(handle.check().unwrap(), FileChange::Unchanged);
+    }
+
+    #[test]
+    fn it_detects_truncation() {
+        let path = log_path("truncate-json.log");
+        append(&path, "aaaa\nbbbb\n");
+
+        let mut handle = FileHandle::open(&path, 5).unwrap();
+        let mut bf = String::new();
+        handle.read_line(&mut bf, false).unwrap();
+        assert_eq!(bf, "bbbb\n");
+
+        OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        append(&path, "c\n");
+        assert_eq!(handle.check().unwrap(), FileChange::Truncated);
+
+        handle.rewind().unwrap();
+        bf.clear();
+        handle.read_line(&mut bf, false).unwrap();
+        assert_eq!(bf, "c\n");
+    }
+
+    #[test]
+    fn it_ignores_offset_past_end() {
+        let path = log_path("short-json.log");
+        append(&path, "a\n");
+        assert_eq!(FileHandle::open(&path, 100).unwrap().offset(), 0);
+    }
+}
@@ -1,46 +1,117 @@
-#![feature(seek_stream_len)]
 extern crate crossbeam_channel;
-use async_std::task;
-use crossbeam_channel::{unbounded as async_channel, Sender};
+use common::metrics::{self, Metric};
+use common::Runtime;
+use crossbeam_channel::{
+    unbounded as async_channel, Receiver, RecvTimeoutError, SendError, Sender,
+};
 use db::Pod;
-use output::OTS;
-use serde_json::json;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+mod decoder;
+mod filter;
+mod handle;
+mod limit;
+mod mask;
+mod multiline;
+mod offsets;
+mod process;
+mod reader;
+mod rules;
+use handle::FileHandle;
+pub use limit::rate_limited;
+pub use mask::masked;
+use reader::{Reader, IDLE_TICK};
+pub use rules::{
+    FieldOp, FieldPredicate, FilterRule, MaskPattern, MaskingRule, MultilineRule, ProcessorRule,
+    RateLimitAction, RateLimitRule, Rules,
+};
 
 pub enum SendFileEvent {
     Close,
     Other,
 }
 
+// Command is sent to the worker following a file
+enum Command {
+    Open(Box<Reader>),
+    Event(SendFileEvent),
+}
+
 pub struct FileReaderWriter {
-    file_handles: HashMap<String, Sender<SendFileEvent>>,
+    // the worker following each file
+    file_handles: HashMap<String, usize>,
+    workers: Vec<Sender<(String, Command)>>,
+    // the files each worker follows
+    load: Vec<usize>,
+    runtime: Runtime,
+    // the masking of the pods of a namespace whose task has none
+    masking: Arc<HashMap<String, MaskingRule>>,
+    // the rate limits of the pods whose task has none
+    rate_limits: Arc<Vec<RateLimitRule>>,
+    open_files: Metric,
 }
 
 impl FileReaderWriter {
-    pub fn new(num_workers: usize) -> Self {
-        let _ = num_workers;
+    // num_workers threads follow the files, one per cpu when 0
+    pub fn new(num_workers: usize, runtime: Runtime) -> Self {
+        let num_workers = match num_workers {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        let mut workers = vec![];
+        for i in 0..num_workers {
+            let (tx, rx) = async_channel::<(String, Command)>();
+            match thread::Builder::new()
+                .name(format!("frw-{}", i))
+                .spawn(move || follow(rx))
+            {
+                Ok(_) => workers.push(tx),
+                Err(e) => eprintln!("frw start worker {} error: {:?}", i, e),
+            }
+        }
         Self {
             file_handles: HashMap::new(),
+            load: vec![0; workers.len()],
+            workers,
+            runtime,
+            masking: Arc::new(HashMap::new()),
+            rate_limits: Arc::new(vec![]),
+            open_files: metrics::gauge("harvest_open_files", "log files followed by a reader", &[]),
         }
     }
 
+    // mask the logs of the namespaces, `*` is any other namespace
+    pub fn masking(mut self, masking: HashMap<String, MaskingRule>) -> Self {
+        self.masking = Arc::new(masking);
+        self
+    }
+
+    // limit the pods matching the rate limits, the first one matching a pod applies
+    pub fn rate_limits(mut self, rate_limits: Vec<RateLimitRule>) -> Self {
+        self.rate_limits = Arc::new(rate_limits);
+        self
+    }
+
     pub fn close_event(&mut self, pod: &Pod) {
-        if let Some(tx) = self.file_handles.get(&pod.path) {
-            if let Err(e) = tx.send(SendFileEvent::Close) {
+        if let Some(worker) = self.file_handles.remove(&pod.path) {
+            if let Err(e) = self.send(worker, &pod.path, SendFileEvent::Close) {
                 eprintln!("frw send close to {:?} handle error: {:?}", &pod.path, e);
             }
-            self.file_handles.remove(&pod.path);
+            self.load[worker] -= 1;
+            self.open_files.set(self.file_handles.len() as i64);
         }
     }
 
     pub fn remove_event(&mut self, pod: &Pod) {
-        if let Some(tx) = self.file_handles.get(&pod.path) {
-            if let Err(e) = tx.send(SendFileEvent::Close) {
+        if let Some(worker) = self.file_handles.remove(&pod.path) {
+            if let Err(e) = self.send(worker, &pod.path, SendFileEvent::Close) {
                 eprintln!("frw send remove to {:?} handle error: {:?}", &pod.path, e);
             }
-            self.file_handles.remove(&pod.path);
+            self.load[worker] -= 1;
+            self.open_files.set(self.file_handles.len() as i64);
         };
 
         db::delete(&pod.path);
@@ -54,106 +125,122 @@ impl FileReaderWriter {
     }
 
     pub fn write_event(&mut self, pod: &mut Pod) {
-        let handle = match self.file_handles.get(&pod.path) {
-            Some(it) => it,
+        let worker = match self.file_handles.get(&pod.path) {
+            Some(it) => *it,
             _ => {
                 return;
             }
         };
-        if let Err(e) = handle.send(SendFileEvent::Other) {
+        if let Err(e) = self.send(worker, &pod.path, SendFileEvent::Other) {
             eprintln!("frw send write event error: {}, path: {}", e, &pod.path)
         }
     }
 
+    fn send(
+        &self,
+        worker: usize,
+        path: &str,
+        event: SendFileEvent,
+    ) -> std::result::Result<(), SendError<(String, Command)>> {
+        self.workers[worker].send((path.to_string(), Command::Event(event)))
+    }
+
     fn open(&mut self, pod: &mut Pod) {
-        let mut offset = pod.offset;
-        let mut file = match File::open(&pod.path) {
-            Ok(file) => file,
+        // the least busy worker follows the file
+        let worker = match (0..self.workers.len()).min_by_key(|i| self.load[*i]) {
+            Some(it) => it,
+            None => {
+                eprintln!("frw no worker to follow {:?}", pod.path);
+                return;
+            }
+        };
+        let handle = match FileHandle::open(&pod.path, pod.offset) {
+            Ok(it) => it,
             Err(e) => {
                 eprintln!("frw open file {:?} error: {:?}", pod.path, e);
                 return;
             }
         };
-        if let Err(e) = file.seek(SeekFrom::Current(offset)) {
-            eprintln!("frw open event seek failed, error: {}", e);
+
+        // the offset must be recorded before the reader starts to increase it
+        pod.offset = handle.offset();
+        db::set_offset(&pod.path, pod.offset);
+        db::update(pod.set_state_run());
+
+        let reader = Reader::new(
+            handle,
+            pod.clone(),
+            self.runtime,
+            &self.masking,
+            &self.rate_limits,
+        );
+        let open = (pod.path.to_string(), Command::Open(Box::new(reader)));
+        if let Err(e) = self.workers[worker].send(open) {
+            eprintln!("frw start reader of {:?} error: {:?}", pod.path, e);
             return;
         }
 
-        let file_size = file.stream_len().unwrap();
-        let mut br = BufReader::new(file);
-        let mut bf = String::new();
-        let outputs = OTS.clone();
+        self.load[worker] += 1;
+        self.file_handles.insert(pod.path.to_string(), worker);
+        self.open_files.set(self.file_handles.len() as i64);
+    }
+}
 
-        loop {
-            let cur_size = br.read_line(&mut bf).unwrap();
-            if let Ok(mut ot) = outputs.lock() {
-                ot.output(&pod.output, &encode_message(&pod, bf.as_str()))
+// a worker follows the files of its readers until the FileReaderWriter is dropped,
+// a reader blocks its worker while the queue of its output is full
+fn follow(rx: Receiver<(String, Command)>) {
+    let mut readers: HashMap<String, Reader> = HashMap::new();
+    let mut ticked = Instant::now();
+    loop {
+        let tick = readers
+            .values()
+            .map(|reader| reader.tick())
+            .min()
+            .unwrap_or(IDLE_TICK);
+        let wait = tick.checked_sub(ticked.elapsed()).unwrap_or_default();
+        match rx.recv_timeout(wait) {
+            Ok((path, Command::Open(reader))) => {
+                let mut reader = *reader;
+                reader.read_lines(false);
+                readers.insert(path, reader);
             }
-            db::incr_offset(&pod.path, cur_size as i64);
-            bf.clear();
-
-            offset += cur_size as i64;
-            if offset >= file_size as i64 {
-                break;
+            Ok((path, Command::Event(SendFileEvent::Close))) => {
+                if let Some(mut reader) = readers.remove(&path) {
+                    reader.flush();
+                    reader.close();
+                }
+            }
+            Ok((path, Command::Event(SendFileEvent::Other))) => {
+                if let Some(reader) = readers.get_mut(&path) {
+                    reader.follow();
+                }
             }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
         }
-
-        pod.offset = offset;
-        let thread_pod = pod.clone();
-        let (tx, rx) = async_channel::<SendFileEvent>();
-        task::spawn(async move {
-            while let Ok(evt) = rx.recv() {
-                match evt {
-                    SendFileEvent::Close => {
-                        break;
-                    }
-                    _ => {
-                        let incr_offset = br.read_line(&mut bf).unwrap();
-                        if let Ok(mut ot) = outputs.lock() {
-                            ot.output(
-                                &thread_pod.output,
-                                &encode_message(&thread_pod, bf.as_str()),
-                            )
-                        }
-                        db::incr_offset(&thread_pod.path, incr_offset as i64);
-                        bf.clear();
-                    }
-                };
+        // busy files must not hold up the pending multiline events and replays of the others
+        if ticked.elapsed() >= tick {
+            for reader in readers.values_mut() {
+                reader.flush_expired();
+                reader.replay();
             }
-        });
-
-        self.file_handles.insert(pod.path.to_string(), tx);
-
-        db::update(&pod.set_state_run())
+            ticked = Instant::now();
+        }
     }
-}
-
-fn encode_message<'a>(pod: &'a Pod, message: &'a str) -> String {
-    if message.len() == 0 {
-        return "".to_string();
+    for (_, reader) in readers {
+        reader.close();
     }
-    json!({
-        "custom":
-            {
-              "nodeId":pod.pod_name,
-              "container":pod.container,
-              "serviceName":pod.service_name,
-              "ips":pod.ips,
-              "version":"v1.0.0",
-            },
-        "message":message}
-    )
-    .to_string()
 }
 
 #[cfg(test)]
 mod tests {
     use crate::FileReaderWriter;
+    use common::Runtime;
     use db::Pod;
 
     #[test]
     fn it_works() {
-        let mut input = FileReaderWriter::new(10);
+        let mut input = FileReaderWriter::new(10, Runtime::Docker);
         input.open_event(&mut Pod::default());
     }
 }
@@ -0,0 +1,430 @@
+use crate::rules::{RateLimitAction, RateLimitRule};
+use common::metrics::{self, Metric};
+use common::Result;
+use db::Pod;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DROPPED_LINES: &str = "harvest_rate_limit_dropped_lines_total";
+const DROPPED_BYTES: &str = "harvest_rate_limit_dropped_bytes_total";
+const DELAYED_LINES: &str = "harvest_rate_limit_delayed_lines_total";
+
+// Bucket holds up to burst seconds of its rate in tokens, refilled continuously
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    // no bucket for a rate of 0, which is no limit
+    fn new(rate: f64, burst: f64) -> Option<Self> {
+        if rate <= 0.0 {
+            return None;
+        }
+        let capacity = (rate * burst).max(1.0);
+        Some(Self {
+            rate,
+            capacity,
+            tokens: capacity,
+        })
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+    }
+
+    // how long until n tokens are there, a take larger than the bucket waits for a full one
+    fn wait(&self, n: f64) -> Duration {
+        let missing = n.min(self.capacity) - self.tokens;
+        if missing <= 0.0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64(missing / self.rate)
+    }
+
+    // the tokens of a large take go below zero, which delays the next ones
+    fn take(&mut self, n: f64) {
+        self.tokens -= n;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Admit {
+    Ship,
+    Drop,
+    // ship once waited for this long
+    Wait(Duration),
+}
+
+// the buckets of every rate limit in use, keyed by the rule and the pods sharing it
+static SHARED: Lazy<Mutex<HashMap<String, Weak<Mutex<Buckets>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Buckets are the token buckets of one rate limit, shared by the readers of every pod it limits
+struct Buckets {
+    lines: Option<Bucket>,
+    bytes: Option<Bucket>,
+    refilled: Instant,
+}
+
+impl Buckets {
+    // the buckets of key, created for rule unless another reader holds them
+    fn shared(key: String, rule: &RateLimitRule) -> Arc<Mutex<Self>> {
+        let mut shared = match SHARED.lock() {
+            Ok(it) => it,
+            Err(e) => e.into_inner(),
+        };
+        if let Some(buckets) = shared.get(&key).and_then(Weak::upgrade) {
+            return buckets;
+        }
+        shared.retain(|_, buckets| buckets.strong_count() > 0);
+        let buckets = Arc::new(Mutex::new(Self {
+            lines: Bucket::new(rule.lines_per_sec, rule.burst),
+            bytes: Bucket::new(rule.bytes_per_sec, rule.burst),
+            refilled: Instant::now(),
+        }));
+        shared.insert(key, Arc::downgrade(&buckets));
+        buckets
+    }
+
+    // how long until the line of bytes fits, the clock of the readers only moves forward
+    fn wait(&mut self, now: Instant, bytes: usize) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.refilled = self.refilled.max(now);
+        let mut wait = Duration::from_secs(0);
+        for (bucket, n) in [(&mut self.lines, 1.0), (&mut self.bytes, bytes as f64)] {
+            if let Some(bucket) = bucket {
+                bucket.refill(elapsed);
+                wait = wait.max(bucket.wait(n));
+            }
+        }
+        wait
+    }
+
+    fn take(&mut self, bytes: usize) {
+        if let Some(lines) = &mut self.lines {
+            lines.take(1.0);
+        }
+        if let Some(buckets) = &mut self.bytes {
+            buckets.take(bytes as f64);
+        }
+    }
+}
+
+// Limit is one rate limit applying to a pod
+struct Limit {
+    buckets: Arc<Mutex<Buckets>>,
+    action: RateLimitAction,
+    sample: u64,
+}
+
+impl Limit {
+    fn new(key: String, rule: &RateLimitRule) -> Self {
+        Self {
+            buckets: Buckets::shared(key, rule),
+            action: rule.action.clone(),
+            sample: rule.sample,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Buckets> {
+        match self.buckets.lock() {
+            Ok(it) => it,
+            Err(e) => e.into_inner(),
+        }
+    }
+}
+
+// Limiter enforces the rate limits of one pod in its read loop, the limit of its task
+// is shared by the files of the pod and a limit of the node by the pods with the same values
+// of the fields the limit matches on, e.g. every pod of a namespace. a line goes past both.
+pub(crate) struct Limiter {
+    limits: Vec<Limit>,
+    // lines over the limit since the start, every sample-th of them is shipped
+    excess: u64,
+    // the current line waits for tokens
+    delaying: bool,
+    dropped_lines: Metric,
+    dropped_bytes: Metric,
+    delayed_lines: Metric,
+}
+
+impl Limiter {
+    // the limiter of the pod, counted under its namespace, pod and container,
+    // None when neither its task nor the node limit it
+    pub(crate) fn new(
+        task: Option<&RateLimitRule>,
+        node: Option<&RateLimitRule>,
+        pod: &Pod,
+    ) -> Result<Option<Self>> {
+        let mut limits = vec![];
+        if let Some(rule) = task {
+            Self::check(rule)?;
+            let key = format!("task {:?} {}/{}", rule, pod.ns, pod.pod_name);
+            limits.push(Limit::new(key, rule));
+        }
+        if let Some(rule) = node {
+            Self::check(rule)?;
+            let mut key = format!("node {:?}", rule);
+            for (values, value) in [
+                (&rule.ns, &pod.ns),
+                (&rule.service, &pod.service_name),
+                (&rule.pod, &pod.pod_name),
+            ] {
+                if !values.is_empty() {
+                    key.push_str(&format!(" {}", value));
+                }
+            }
+            limits.push(Limit::new(key, rule));
+        }
+        if limits.is_empty() {
+            return Ok(None);
+        }
+        let labels = crate::reader::labels(pod);
+        Ok(Some(Self {
+            limits,
+            excess: 0,
+            delaying: false,
+            dropped_lines: metrics::counter(DROPPED_LINES, "lines dropped by rate limits", &labels),
+            dropped_bytes: metrics::counter(DROPPED_BYTES, "bytes dropped by rate limits", &labels),
+            delayed_lines: metrics::counter(DELAYED_LINES, "lines delayed by rate limits", &labels),
+        }))
+    }
+
+    pub(crate) fn check(rule: &RateLimitRule) -> Result<()> {
+        if rule.lines_per_sec < 0.0 || rule.bytes_per_sec < 0.0 {
+            return Err(format!("rate limit {:?} is negative", rule).into());
+        }
+        if rule.lines_per_sec == 0.0 && rule.bytes_per_sec == 0.0 {
+            return Err(format!("rate limit {:?} limits neither lines nor bytes", rule).into());
+        }
+        if rule.burst <= 0.0 || rule.sample == 0 {
+            return Err(format!("rate limit {:?} needs a burst and sample above 0", rule).into());
+        }
+        Ok(())
+    }
+
+    // whether the line of bytes is shipped, the read loop of the pod sleeps while it is delayed
+    pub(crate) fn admit(&mut self, bytes: usize) -> bool {
+        loop {
+            match self.admit_at(Instant::now(), bytes) {
+                Admit::Ship => return true,
+                Admit::Drop => return false,
+                Admit::Wait(wait) => thread::sleep(wait),
+            }
+        }
+    }
+
+    // the line is admitted once every limit has room for it,
+    // the first limit without room decides what happens to it
+    pub(crate) fn admit_at(&mut self, now: Instant, bytes: usize) -> Admit {
+        let mut buckets = self.limits.iter().map(Limit::lock).collect::<Vec<_>>();
+        let exceeded = buckets
+            .iter_mut()
+            .map(|buckets| buckets.wait(now, bytes))
+            .enumerate()
+            .find(|(_, wait)| wait.as_nanos() > 0);
+        let (i, wait) = match exceeded {
+            Some(it) => it,
+            None => {
+                buckets.iter_mut().for_each(|buckets| buckets.take(bytes));
+                self.delaying = false;
+                return Admit::Ship;
+            }
+        };
+        drop(buckets);
+        let limit = &self.limits[i];
+        match limit.action {
+            RateLimitAction::Delay => {
+                // counted once per line, not per wait
+                if !self.delaying {
+                    self.delaying = true;
+                    self.delayed_lines.inc();
+                }
+                Admit::Wait(wait)
+            }
+            RateLimitAction::Sample => {
+                self.excess += 1;
+                if (self.excess - 1).is_multiple_of(limit.sample) {
+                    return Admit::Ship;
+                }
+                self.drop(bytes)
+            }
+            RateLimitAction::Drop => self.drop(bytes),
+        }
+    }
+
+    fn drop(&mut self, bytes: usize) -> Admit {
+        self.dropped_lines.inc();
+        self.dropped_bytes.add(bytes as i64);
+        Admit::Drop
+    }
+}
+
+// the lines and bytes dropped and the lines delayed by the rate limits of every pod
+pub fn rate_limited() -> Value {
+    let mut limited: Vec<Value> = vec![];
+    for sample in metrics::snapshot().into_iter() {
+        let field = match sample.name.as_str() {
+            DROPPED_LINES => "dropped_lines",
+            DROPPED_BYTES => "dropped_bytes",
+            DELAYED_LINES => "delayed_lines",
+            _ => continue,
+        };
+        let mut pod = json!({ "dropped_lines": 0, "dropped_bytes": 0, "delayed_lines": 0 });
+        for (label, value) in sample.labels.iter() {
+            pod[label] = json!(value);
+        }
+        match limited.iter_mut().find(|it| {
+            ["namespace", "pod", "container"]
+                .iter()
+                .all(|label| it[label] == pod[label])
+        }) {
+            Some(it) => it[field] = json!(sample.value),
+            None => {
+                pod[field] = json!(sample.value);
+                limited.push(pod);
+            }
+        }
+    }
+    json!(limited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rate_limited, Admit, Limiter};
+    use crate::rules::{RateLimitRule, Rules};
+    use db::Pod;
+    use std::time::{Duration, Instant};
+
+    fn rule(rule: &str) -> RateLimitRule {
+        let rules = Rules::parse(&format!(r#"{{"rate_limit":{}}}"#, rule)).unwrap();
+        rules.rate_limit.unwrap()
+    }
+
+    fn pod(ns: &str, pod: &str) -> Pod {
+        Pod {
+            ns: ns.to_string(),
+            pod_name: pod.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn limiter(rule: &str, pod: &str) -> Limiter {
+        Limiter::new(Some(&self::rule(rule)), None, &self::pod("test-limit", pod))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn it_drops_and_samples_excess_lines() {
+        let mut drop = limiter(r#"{"lines_per_sec":2}"#, "drop");
+        let now = Instant::now();
+        let admitted = (0..4)
+            .map(|_| drop.admit_at(now, 10))
+            .collect::<Vec<Admit>>();
+        assert_eq!(
+            admitted,
+            vec![Admit::Ship, Admit::Ship, Admit::Drop, Admit::Drop]
+        );
+        // half a second refills a line
+        assert_eq!(
+            drop.admit_at(now + Duration::from_millis(500), 10),
+            Admit::Ship
+        );
+        assert_eq!(
+            drop.admit_at(now + Duration::from_millis(500), 10),
+            Admit::Drop
+        );
+
+        let mut sample = limiter(
+            r#"{"bytes_per_sec":100,"action":"sample","sample":2}"#,
+            "sample",
+        );
+        let admitted = (0..4)
+            .map(|_| sample.admit_at(now, 60))
+            .collect::<Vec<Admit>>();
+        assert_eq!(
+            admitted,
+            vec![Admit::Ship, Admit::Ship, Admit::Drop, Admit::Ship]
+        );
+
+        let limited = rate_limited();
+        let pod = |name: &str| {
+            limited
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|it| it["pod"] == name)
+                .cloned()
+                .unwrap()
+        };
+        assert_eq!(pod("drop")["dropped_lines"], 3);
+        assert_eq!(pod("drop")["dropped_bytes"], 30);
+        assert_eq!(pod("sample")["dropped_bytes"], 60);
+    }
+
+    #[test]
+    fn it_delays_excess_lines() {
+        let mut delay = limiter(r#"{"lines_per_sec":10,"action":"delay"}"#, "delay");
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(delay.admit_at(now, 1), Admit::Ship);
+        }
+        assert_eq!(
+            delay.admit_at(now, 1),
+            Admit::Wait(Duration::from_millis(100))
+        );
+        assert_eq!(
+            delay.admit_at(now + Duration::from_millis(100), 1),
+            Admit::Ship
+        );
+
+        assert!(Limiter::new(None, None, &pod("test-limit", "none"))
+            .unwrap()
+            .is_none());
+        assert!(Rules::parse(r#"{"rate_limit":{"action":"drop"}}"#).is_err());
+        assert!(Rules::parse(r#"{"rate_limit":{"lines_per_sec":1,"sample":0}}"#).is_err());
+        assert!(Rules::parse(r#"{"rate_limit":{"lines_per_sec":1,"action":"queue"}}"#).is_err());
+    }
+
+    #[test]
+    fn it_parses_node_limits() {
+        let limits =
+            RateLimitRule::parse(r#"[{"ns":["batch"],"lines_per_sec":10},{"bytes_per_sec":1}]"#)
+                .unwrap();
+        assert!(!limits[0].matches(&pod("web", "a")));
+        assert!(limits[1].matches(&pod("web", "a")));
+        assert_eq!(limits[1].burst, 1.0);
+        assert!(RateLimitRule::parse(r#"[{"ns":["batch"]}]"#).is_err());
+    }
+
+    #[test]
+    fn it_shares_node_limits() {
+        let node = rule(r#"{"ns":["test-quota","test-other"],"lines_per_sec":2}"#);
+        let task = rule(r#"{"lines_per_sec":1}"#);
+        let mut a = Limiter::new(None, Some(&node), &pod("test-quota", "a"))
+            .unwrap()
+            .unwrap();
+        let mut b = Limiter::new(Some(&task), Some(&node), &pod("test-quota", "b"))
+            .unwrap()
+            .unwrap();
+        let now = Instant::now();
+        // the pods of the namespace share its 2 lines, b has 1 line of its own
+        assert_eq!(b.admit_at(now, 1), Admit::Ship);
+        assert_eq!(b.admit_at(now, 1), Admit::Drop);
+        assert_eq!(a.admit_at(now, 1), Admit::Ship);
+        assert_eq!(a.admit_at(now, 1), Admit::Drop);
+
+        // another namespace matching the limit has a quota of its own
+        let mut c = Limiter::new(None, Some(&node), &pod("test-other", "c"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(c.admit_at(now, 1), Admit::Ship);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::rules::MaskingRule;
+use common::metrics::{self, Metric};
+use common::Result;
+use regex::{Captures, Regex};
+use serde_json::{json, Value};
+
+const MASKED_TOTAL: &str = "harvest_masked_values_total";
+
+// the builtin rules, applied in this order so that an id number or
+// a phone number with its country code is not taken for a card
+const BUILTIN: &[&str] = &["bearer_token", "id_number", "phone", "bank_card"];
+
+enum Matcher {
+    // every match becomes the replacement, `$name` expands the groups
+    Replace(Regex, String),
+    // the characters of every match, or of its first group, but the first and the last ones become `*`
+    KeepEnds(Regex, usize, usize),
+    // like KeepEnds(0, 4), only for digits passing the luhn check
+    Card(Regex),
+}
+
+impl Matcher {
+    fn builtin(name: &str) -> Result<Self> {
+        Ok(match name {
+            "bearer_token" => Matcher::Replace(
+                Regex::new(r"(?i)\b(bearer)\s+[A-Za-z0-9\-._~+/]+=*")?,
+                "$1 ***".to_string(),
+            ),
+            "id_number" => Matcher::KeepEnds(Regex::new(r"\b\d{17}[\dXx]\b")?, 3, 4),
+            "bank_card" => Matcher::Card(Regex::new(r"\b\d(?:[ -]?\d){12,18}\b")?),
+            "phone" => Matcher::KeepEnds(Regex::new(r"(?:\+86[- ]?|\b)(1[3-9]\d{9})\b")?, 3, 4),
+            name => return Err(format!("masking builtin `{}` is unknown", name).into()),
+        })
+    }
+
+    fn regex(&self) -> &Regex {
+        match self {
+            Matcher::Replace(regex, _) | Matcher::KeepEnds(regex, _, _) | Matcher::Card(regex) => {
+                regex
+            }
+        }
+    }
+
+    // the masked text and the number of masked values, None when nothing matched
+    fn mask(&self, text: &str) -> Option<(String, usize)> {
+        let regex = self.regex();
+        if !regex.is_match(text) {
+            return None;
+        }
+        let mut masked = 0;
+        let text = regex.replace_all(text, |captures: &Captures| {
+            let value = &captures[0];
+            let replaced = match self {
+                Matcher::Replace(_, replacement) => {
+                    let mut replaced = String::new();
+                    captures.expand(replacement, &mut replaced);
+                    replaced
+                }
+                Matcher::KeepEnds(_, first, last) => match captures.get(1) {
+                    Some(group) => {
+                        // e.g. the country code of a phone number is kept
+                        let start = group.start() - captures.get(0).map_or(0, |m| m.start());
+                        format!(
+                            "{}{}",
+                            &value[..start],
+                            keep_ends(group.as_str(), *first, *last)
+                        )
+                    }
+                    None => keep_ends(value, *first, *last),
+                },
+                Matcher::Card(_) if luhn(value) => keep_ends(value, 0, 4),
+                Matcher::Card(_) => return value.to_string(),
+            };
+            masked += 1;
+            replaced
+        });
+        match masked {
+            0 => None,
+            masked => Some((text.to_string(), masked)),
+        }
+    }
+}
+
+struct Rule {
+    name: String,
+    matcher: Matcher,
+}
+
+// Masker redacts the sensitive values of the envelope of a record, the text rules
+// apply to every string but the `custom` fields of the pod, the keys to json objects
+// at any depth, including a message that is a json object itself.
+pub(crate) struct Masker {
+    rules: Vec<Rule>,
+    keys: Vec<String>,
+    replacement: String,
+    // masked values per rule, the keys last
+    masked: Vec<Metric>,
+}
+
+impl Masker {
+    // compile the rule, the masked values are counted per namespace and rule
+    pub(crate) fn new(rule: &MaskingRule, namespace: &str) -> Result<Self> {
+        let rules = Self::compile(rule)?;
+        let masked = rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .chain(std::iter::once("key"))
+            .map(|name| {
+                metrics::counter(
+                    MASKED_TOTAL,
+                    "values masked in the shipped records",
+                    &[("namespace", namespace), ("rule", name)],
+                )
+            })
+            .collect();
+        Ok(Self {
+            rules,
+            keys: rule.keys.iter().map(|key| key.to_lowercase()).collect(),
+            replacement: rule.replacement.clone(),
+            masked,
+        })
+    }
+
+    // validate the rule, builtins that are unknown and patterns that do not compile are an error
+    pub(crate) fn check(rule: &MaskingRule) -> Result<()> {
+        Self::compile(rule).map(|_| ())
+    }
+
+    fn compile(rule: &MaskingRule) -> Result<Vec<Rule>> {
+        for name in rule.builtin.iter() {
+            if !BUILTIN.contains(&name.as_str()) {
+                return Err(format!("masking builtin `{}` is unknown", name).into());
+            }
+        }
+        let mut rules = vec![];
+        for name in BUILTIN
+            .iter()
+            .filter(|name| rule.builtin.iter().any(|b| b == *name))
+        {
+            rules.push(Rule {
+                name: name.to_string(),
+                matcher: Matcher::builtin(name)?,
+            });
+        }
+        for pattern in rule.patterns.iter() {
+            rules.push(Rule {
+                name: pattern.name.clone(),
+                matcher: Matcher::Replace(
+                    Regex::new(&pattern.pattern)?,
+                    pattern.replacement.clone(),
+                ),
+            });
+        }
+        Ok(rules)
+    }
+
+    // mask the envelope in place, the number of masked values
+    pub(crate) fn apply(&self, envelope: &mut Value) -> usize {
+        let mut counts = vec![0; self.masked.len()];
+        match envelope {
+            Value::Object(object) => {
+                for (key, value) in object.iter_mut() {
+                    if key != "custom" {
+                        self.mask_key(key, value, &mut counts);
+                    }
+                }
+            }
+            value => self.mask_value(value, &mut counts),
+        }
+        for (metric, count) in self.masked.iter().zip(counts.iter()) {
+            if *count > 0 {
+                metric.add(*count as i64);
+            }
+        }
+        counts.iter().sum()
+    }
+
+    fn mask_key(&self, key: &str, value: &mut Value, counts: &mut [usize]) {
+        if !value.is_null() && self.keys.iter().any(|k| k.eq_ignore_ascii_case(key)) {
+            *value = Value::from(self.replacement.as_str());
+            if let Some(count) = counts.last_mut() {
+                *count += 1;
+            }
+            return;
+        }
+        self.mask_value(value, counts)
+    }
+
+    fn mask_value(&self, value: &mut Value, counts: &mut [usize]) {
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object.iter_mut() {
+                    self.mask_key(key, value, counts);
+                }
+            }
+            Value::Array(values) => {
+                for value in values.iter_mut() {
+                    self.mask_value(value, counts);
+                }
+            }
+            Value::String(text) => {
+                if let Some(masked) = self.mask_text(text, counts) {
+                    *text = masked;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn mask_text(&self, text: &str, counts: &mut [usize]) -> Option<String> {
+        // a json object is masked by key as well, it is written back only when it changed
+        if !self.keys.is_empty() && text.trim_start().starts_with('{') {
+            if let Ok(mut value @ Value::Object(_)) = serde_json::from_str::<Value>(text) {
+                let before: usize = counts.iter().sum();
+                self.mask_value(&mut value, counts);
+                if counts.iter().sum::<usize>() == before {
+                    return None;
+                }
+                return Some(value.to_string());
+            }
+        }
+        let mut masked = None;
+        for (i, rule) in self.rules.iter().enumerate() {
+            let current = masked.as_deref().unwrap_or(text);
+            if let Some((text, count)) = rule.matcher.mask(current) {
+                counts[i] += count;
+                masked = Some(text);
+            }
+        }
+        masked
+    }
+}
+
+// the alphanumerics of the value but the first and last ones masked, separators are kept
+fn keep_ends(value: &str, first: usize, last: usize) -> String {
+    let len = value.chars().filter(|c| c.is_ascii_alphanumeric()).count();
+    let mut seen = 0;
+    value
+        .chars()
+        .map(|c| {
+            if !c.is_ascii_alphanumeric() {
+                return c;
+            }
+            seen += 1;
+            if seen > first && seen <= len.saturating_sub(last) {
+                '*'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+fn luhn(value: &str) -> bool {
+    let digits = value
+        .chars()
+        .filter_map(|c| c.to_digit(10))
+        .collect::<Vec<u32>>();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| match (i % 2, d * 2) {
+            (1, d) if d > 9 => d - 9,
+            (1, d) => d,
+            _ => *d,
+        })
+        .sum();
+    digits.len() >= 13 && sum.is_multiple_of(10)
+}
+
+// the masked values per namespace and rule, e.g. {"payments":{"bank_card":3}}
+pub fn masked() -> Value {
+    let mut masked = json!({});
+    for sample in metrics::snapshot()
+        .into_iter()
+        .filter(|s| s.name == MASKED_TOTAL)
+    {
+        let label = |name: &str| {
+            sample
+                .labels
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default()
+        };
+        let namespace = masked.as_object_mut().map(|masked| {
+            masked
+                .entry(label("namespace"))
+                .or_insert_with(|| json!({}))
+        });
+        if let Some(Value::Object(rules)) = namespace {
+            rules.insert(label("rule"), Value::from(sample.value));
+        }
+    }
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Masker;
+    use crate::rules::{MaskingRule, Rules};
+    use serde_json::{json, Value};
+
+    fn apply(masking: &str, envelope: Value) -> (Value, usize) {
+        let rules = Rules::parse(&format!(r#"{{"masking":{}}}"#, masking)).unwrap();
+        let mut envelope = envelope;
+        let masked = Masker::new(&rules.masking.unwrap(), "test-mask")
+            .unwrap()
+            .apply(&mut envelope);
+        (envelope, masked)
+    }
+
+    #[test]
+    fn it_masks_builtins() {
+        let builtin = r#"{"builtin":["phone","id_number","bank_card","bearer_token"]}"#;
+        let envelope = json!({
+            "custom":{"nodeId":"pod-13812345678"},
+            "message":"call +86 13812345678 id 11010519491231002X card 4111 1111 1111 1111 \
+                       order 4111111111111112 auth Bearer eyJhbGciOi.J9.x-y_z=="
+        });
+        assert_eq!(
+            apply(builtin, envelope),
+            (
+                json!({
+                    "custom":{"nodeId":"pod-13812345678"},
+                    "message":"call +86 138****5678 id 110***********002X card **** **** **** 1111 \
+                               order 4111111111111112 auth Bearer ***"
+                }),
+                4
+            )
+        );
+    }
+
+    #[test]
+    fn it_masks_keys_and_patterns() {
+        let masking = r#"{
+            "keys":["password","Authorization"],
+            "patterns":[{"name":"email","pattern":"[\\w.]+@[\\w.]+","replacement":"<email>"}]
+        }"#;
+        let envelope = json!({
+            "message":r#"{"user":"a@b.io","password":"secret","headers":{"authorization":"Basic x"}}"#,
+            "stream":"stdout"
+        });
+        let (envelope, masked) = apply(masking, envelope);
+        assert_eq!(masked, 3);
+        let message = serde_json::from_str::<Value>(envelope["message"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            message,
+            json!({"user":"<email>","password":"***","headers":{"authorization":"***"}})
+        );
+
+        let envelope = json!({"message":r#"{"user":"bob"}"#});
+        assert_eq!(apply(masking, envelope.clone()), (envelope, 0));
+
+        assert_eq!(super::masked()["test-mask"]["key"], json!(2));
+        assert!(Rules::parse(r#"{"masking":{"builtin":["passport"]}}"#).is_err());
+    }
+
+    #[test]
+    fn it_parses_namespace_masking() {
+        let masking = MaskingRule::parse_namespaces(r#"{"payments":{"builtin":["bank_card"]}}"#);
+        assert_eq!(masking.unwrap()["payments"].replacement, "***");
+        assert!(MaskingRule::parse_namespaces(r#"{"*":{"keys":"password"}}"#).is_err());
+    }
+
+    #[test]
+    fn it_merges_task_masking() {
+        let namespaces = MaskingRule::parse_namespaces(
+            r#"{"test-mask":{"builtin":["phone"],"keys":["password"],"replacement":"<hidden>"}}"#,
+        )
+        .unwrap();
+        let task = r#"{"keys":["PASSWORD","token"],"replacement":"x"}"#;
+        let task = Rules::parse(&format!(r#"{{"masking":{}}}"#, task)).unwrap();
+        let merged = namespaces["test-mask"].merge(&task.masking.unwrap());
+        assert_eq!(merged.builtin, vec!["phone"]);
+        assert_eq!(merged.keys, vec!["password", "token"]);
+        assert_eq!(merged.replacement, "<hidden>");
+
+        let mut envelope = json!({"message":r#"{"password":"a","token":"b","tel":"13812345678"}"#});
+        Masker::new(&merged, "test-mask")
+            .unwrap()
+            .apply(&mut envelope);
+        let message = serde_json::from_str::<Value>(envelope["message"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            message,
+            json!({"password":"<hidden>","token":"<hidden>","tel":"138****5678"})
+        );
+        assert!(Rules::parse(r#"{"masking":{"patterns":[{"pattern":"("}]}}"#).is_err());
+    }
+}
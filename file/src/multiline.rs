@@ -0,0 +1,190 @@
+use crate::decoder::LogRecord;
+use crate::rules::MultilineRule;
+use common::Result;
+use regex::Regex;
+use std::time::{Duration, Instant};
+
+// Event is a record being aggregated together with the file bytes it spans
+struct Event {
+    record: LogRecord,
+    lines: usize,
+    size: i64,
+    updated: Instant,
+}
+
+// Multiline aggregates the records of one file into multiline events
+pub(crate) struct Multiline {
+    start: Option<Regex>,
+    continuation: Option<Regex>,
+    timeout: Duration,
+    max_lines: usize,
+    pending: Option<Event>,
+}
+
+impl Multiline {
+    pub(crate) fn new(rule: &MultilineRule) -> Result<Self> {
+        if rule.start.is_none() && rule.continuation.is_none() {
+            return Err("multiline rule needs a start or continuation pattern".into());
+        }
+        if rule.timeout_ms == 0 {
+            return Err("multiline rule needs a timeout_ms above 0".into());
+        }
+        Ok(Self {
+            start: match &rule.start {
+                Some(pattern) => Some(Regex::new(pattern)?),
+                None => None,
+            },
+            continuation: match &rule.continuation {
+                Some(pattern) => Some(Regex::new(pattern)?),
+                None => None,
+            },
+            timeout: Duration::from_millis(rule.timeout_ms),
+            max_lines: rule.max_lines.max(1),
+            pending: None,
+        })
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn is_continuation(&self, message: &str) -> bool {
+        if let Some(start) = &self.start {
+            return !start.is_match(message);
+        }
+        match &self.continuation {
+            Some(continuation) => continuation.is_match(message),
+            None => false,
+        }
+    }
+
+    // push a record of size bytes, a finished event is returned with its size
+    pub(crate) fn push(&mut self, record: LogRecord, size: i64) -> Option<(LogRecord, i64)> {
+        if self.is_continuation(&record.message) {
+            if let Some(event) = self.pending.as_mut() {
+                event.record.message.push('\n');
+                event.record.message.push_str(&record.message);
+                event.lines += 1;
+                event.size += size;
+                event.updated = Instant::now();
+                if event.lines >= self.max_lines {
+                    return self.flush();
+                }
+                return None;
+            }
+        }
+
+        let finished = self.flush();
+        self.pending = Some(Event {
+            record,
+            lines: 1,
+            size,
+            updated: Instant::now(),
+        });
+        finished
+    }
+
+    pub(crate) fn flush(&mut self) -> Option<(LogRecord, i64)> {
+        self.pending.take().map(|event| (event.record, event.size))
+    }
+
+    // flush the pending event when nothing was appended within the timeout
+    pub(crate) fn flush_expired(&mut self) -> Option<(LogRecord, i64)> {
+        match &self.pending {
+            Some(event) if event.updated.elapsed() >= self.timeout => self.flush(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Multiline;
+    use crate::decoder::LogRecord;
+    use crate::rules::{MultilineRule, Rules};
+    use std::{thread, time::Duration};
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord {
+            message: message.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_joins_stack_traces() {
+        let mut multiline = Multiline::new(&MultilineRule {
+            continuation: Some(r"^(\s+at |Caused by:)".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(multiline
+            .push(record("java.lang.NullPointerException"), 31)
+            .is_none());
+        assert!(multiline.push(record("    at a.b(C.java:1)"), 21).is_none());
+        assert!(multiline.push(record("Caused by: x"), 13).is_none());
+
+        let (event, size) = multiline.push(record("next"), 5).unwrap();
+        assert_eq!(
+            event.message,
+            "java.lang.NullPointerException\n    at a.b(C.java:1)\nCaused by: x"
+        );
+        assert_eq!(size, 65);
+        assert_eq!(multiline.flush().unwrap().0.message, "next");
+    }
+
+    #[test]
+    fn it_joins_with_start_pattern() {
+        let mut multiline = Multiline::new(&MultilineRule {
+            start: Some(r"^\d{4}-".to_string()),
+            max_lines: 2,
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(multiline.push(record("2021-03-16 error"), 1).is_none());
+        let (event, _) = multiline.push(record("Traceback"), 1).unwrap();
+        assert_eq!(event.message, "2021-03-16 error\nTraceback");
+    }
+
+    #[test]
+    fn it_flushes_expired_events() {
+        let mut multiline = Multiline::new(&MultilineRule {
+            continuation: Some(r"^\s".to_string()),
+            timeout_ms: 10,
+            ..Default::default()
+        })
+        .unwrap();
+
+        multiline.push(record("a"), 1);
+        assert!(multiline.flush_expired().is_none());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(multiline.flush_expired().unwrap().0.message, "a");
+    }
+
+    #[test]
+    fn it_parses_multiline_rules() {
+        let rules = Rules::parse(r#"{"multiline":{"continuation":"^\\s"}}"#).unwrap();
+        let multiline = rules.multiline.unwrap();
+        assert_eq!(multiline.continuation, Some("^\\s".to_string()));
+        assert_eq!(multiline.timeout_ms, 1000);
+        assert!(Rules::parse(r#"{"multiline":{"start":"^\\S","timeout_ms":0}}"#).is_err());
+    }
+
+    #[test]
+    fn it_rejects_bad_rules() {
+        assert!(Multiline::new(&MultilineRule::default()).is_err());
+        assert!(Multiline::new(&MultilineRule {
+            start: Some("(".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(Multiline::new(&MultilineRule {
+            start: Some("^\\S".to_string()),
+            timeout_ms: 0,
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use output::Ack;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+// Offsets commits the offset of one file generation in the order its records were read,
+// the committed offset never moves past a record the output did not deliver.
+pub(crate) struct Offsets {
+    path: String,
+    // end offsets of the shipped records, with their delivery once settled
+    pending: VecDeque<(i64, Option<bool>)>,
+    committed: i64,
+    // a record was not delivered, the reader reads the file again from this offset
+    failed_at: Option<i64>,
+    // the file was rotated or truncated, its offsets are not ours anymore
+    retired: bool,
+}
+
+impl Offsets {
+    pub(crate) fn new(path: &str, committed: i64) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            path: path.to_string(),
+            pending: VecDeque::new(),
+            committed,
+            failed_at: None,
+            retired: false,
+        }))
+    }
+
+    // the ack of the record ending at end
+    pub(crate) fn ack(offsets: &Arc<Mutex<Self>>, end: i64) -> Ack {
+        if let Ok(mut it) = offsets.lock() {
+            it.push(end);
+        }
+        let offsets = offsets.clone();
+        Ack::new(move |ok| {
+            if let Ok(mut it) = offsets.lock() {
+                it.settle(end, ok);
+            }
+        })
+    }
+
+    // a record that was not shipped, e.g. rejected by the filter
+    pub(crate) fn skip(offsets: &Arc<Mutex<Self>>, end: i64) {
+        if let Ok(mut it) = offsets.lock() {
+            it.push(end);
+            it.settle(end, true);
+        }
+    }
+
+    // the offset to read the file again from after a failed delivery,
+    // the acks of the records read before are ignored
+    pub(crate) fn failed_at(&self) -> Option<i64> {
+        self.failed_at
+    }
+
+    pub(crate) fn retire(&mut self) {
+        self.retired = true;
+        self.pending.clear();
+    }
+
+    fn push(&mut self, end: i64) {
+        if self.failed_at.is_some() || self.retired {
+            return;
+        }
+        self.pending.push_back((end, None));
+    }
+
+    fn settle(&mut self, end: i64, ok: bool) {
+        if self.failed_at.is_some() || self.retired {
+            return;
+        }
+        // records shipped while a partial line was pending share their end
+        let first = self.pending.partition_point(|(pending, _)| *pending < end);
+        if let Some(record) = self
+            .pending
+            .iter_mut()
+            .skip(first)
+            .take_while(|(pending, _)| *pending == end)
+            .find(|(_, settled)| settled.is_none())
+        {
+            record.1 = Some(ok);
+        }
+        self.commit();
+    }
+
+    fn commit(&mut self) {
+        let committed = self.committed;
+        while let Some((end, Some(ok))) = self.pending.front().copied() {
+            if !ok {
+                eprintln!(
+                    "frw delivery of {:?} failed, it is read again from {}",
+                    self.path, self.committed
+                );
+                self.failed_at = Some(self.committed);
+                self.pending.clear();
+                break;
+            }
+            self.committed = end;
+            self.pending.pop_front();
+        }
+        if self.committed != committed {
+            db::set_offset(&self.path, self.committed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Offsets;
+
+    #[test]
+    fn it_commits_in_order() {
+        let offsets = Offsets::new("/tmp/harvest-offsets-order.log", 0);
+        let first = Offsets::ack(&offsets, 10);
+        let second = Offsets::ack(&offsets, 20);
+        Offsets::skip(&offsets, 25);
+        let third = Offsets::ack(&offsets, 30);
+
+        second.done();
+        assert_eq!(offsets.lock().unwrap().committed, 0);
+        first.done();
+        assert_eq!(offsets.lock().unwrap().committed, 25);
+        third.done();
+        assert_eq!(offsets.lock().unwrap().committed, 30);
+    }
+
+    #[test]
+    fn it_stops_at_failed_delivery() {
+        let offsets = Offsets::new("/tmp/harvest-offsets-failed.log", 5);
+        let first = Offsets::ack(&offsets, 10);
+        let second = Offsets::ack(&offsets, 20);
+        let third = Offsets::ack(&offsets, 30);
+
+        first.done();
+        drop(second);
+        third.done();
+        let it = offsets.lock().unwrap();
+        assert_eq!(it.committed, 10);
+        assert_eq!(it.failed_at(), Some(10));
+    }
+
+    #[test]
+    fn it_ignores_retired_acks() {
+        let offsets = Offsets::new("/tmp/harvest-offsets-retired.log", 0);
+        let first = Offsets::ack(&offsets, 10);
+        offsets.lock().unwrap().retire();
+        first.done();
+        assert_eq!(offsets.lock().unwrap().committed, 0);
+    }
+}
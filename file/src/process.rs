@@ -0,0 +1,323 @@
+use crate::rules::ProcessorRule;
+use chrono::format::{Item as FormatItem, StrftimeItems};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use common::Result;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+enum TimeFormat {
+    Rfc3339,
+    // seconds since the epoch
+    Unix,
+    // milliseconds since the epoch
+    UnixMs,
+    Strftime(String),
+}
+
+enum Processor {
+    Add(String, Value),
+    Rename(String, String),
+    Drop(Vec<String>),
+    ParseJson(String, String, bool),
+    Regex(String, Regex, String),
+    Timestamp(String, TimeFormat, String),
+    Mask(String, Regex, String),
+}
+
+// Pipeline applies the processors of a pod to the envelope of each of its records
+pub(crate) struct Pipeline {
+    processors: Vec<Processor>,
+}
+
+impl Pipeline {
+    pub(crate) fn new(rules: &[ProcessorRule]) -> Result<Self> {
+        let mut processors = vec![];
+        for rule in rules.iter() {
+            processors.push(Self::processor(rule)?);
+        }
+        Ok(Self { processors })
+    }
+
+    fn processor(rule: &ProcessorRule) -> Result<Processor> {
+        Ok(match rule {
+            ProcessorRule::Add { field, value } => Processor::Add(field.clone(), value.clone()),
+            ProcessorRule::Rename { from, to } => Processor::Rename(from.clone(), to.clone()),
+            ProcessorRule::Drop { fields } => Processor::Drop(fields.clone()),
+            ProcessorRule::ParseJson {
+                field,
+                target,
+                drop_source,
+            } => Processor::ParseJson(field.clone(), target.clone(), *drop_source),
+            ProcessorRule::Regex {
+                field,
+                pattern,
+                target,
+            } => {
+                let regex = Regex::new(pattern)?;
+                if regex.capture_names().flatten().next().is_none() {
+                    return Err(format!("regex processor `{}` has no named group", pattern).into());
+                }
+                Processor::Regex(field.clone(), regex, target.clone())
+            }
+            ProcessorRule::Timestamp {
+                field,
+                format,
+                target,
+            } => {
+                let format = match format.as_str() {
+                    "rfc3339" => TimeFormat::Rfc3339,
+                    "unix" => TimeFormat::Unix,
+                    "unix_ms" => TimeFormat::UnixMs,
+                    format => {
+                        if StrftimeItems::new(format).any(|item| matches!(item, FormatItem::Error))
+                        {
+                            return Err(
+                                format!("timestamp processor format `{}` is bad", format).into()
+                            );
+                        }
+                        TimeFormat::Strftime(format.to_string())
+                    }
+                };
+                Processor::Timestamp(field.clone(), format, target.clone())
+            }
+            ProcessorRule::Mask {
+                field,
+                pattern,
+                replacement,
+            } => Processor::Mask(field.clone(), Regex::new(pattern)?, replacement.clone()),
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    // a processor whose field is missing or does not parse leaves the envelope as it is
+    pub(crate) fn apply(&self, envelope: &mut Value) {
+        for processor in self.processors.iter() {
+            match processor {
+                Processor::Add(field, value) => set(envelope, field, value.clone()),
+                Processor::Rename(from, to) => {
+                    if let Some(value) = take(envelope, from) {
+                        set(envelope, to, value)
+                    }
+                }
+                Processor::Drop(fields) => {
+                    for field in fields.iter() {
+                        take(envelope, field);
+                    }
+                }
+                Processor::ParseJson(field, target, drop_source) => {
+                    let parsed = match get(envelope, field) {
+                        Some(Value::String(s)) => serde_json::from_str::<Value>(s.trim()).ok(),
+                        _ => None,
+                    };
+                    if let Some(parsed) = parsed {
+                        if *drop_source {
+                            take(envelope, field);
+                        }
+                        set(envelope, target, parsed);
+                    }
+                }
+                Processor::Regex(field, regex, target) => {
+                    let captured = match get(envelope, field) {
+                        Some(Value::String(s)) => regex.captures(s).map(|captures| {
+                            regex
+                                .capture_names()
+                                .flatten()
+                                .filter_map(|name| {
+                                    let value = captures.name(name)?.as_str();
+                                    Some((name.to_string(), Value::from(value)))
+                                })
+                                .collect::<Map<String, Value>>()
+                        }),
+                        _ => None,
+                    };
+                    if let Some(captured) = captured {
+                        set(envelope, target, Value::Object(captured));
+                    }
+                }
+                Processor::Timestamp(field, format, target) => {
+                    let time = get(envelope, field).and_then(|value| parse_time(value, format));
+                    if let Some(time) = time {
+                        let time = time.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+                        set(envelope, target, Value::from(time));
+                    }
+                }
+                Processor::Mask(field, regex, replacement) => {
+                    let masked = match get(envelope, field) {
+                        Some(Value::String(s)) if regex.is_match(s) => {
+                            Some(regex.replace_all(s, replacement.as_str()).to_string())
+                        }
+                        _ => None,
+                    };
+                    if let Some(masked) = masked {
+                        set(envelope, field, Value::from(masked));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn parse_time(value: &Value, format: &TimeFormat) -> Option<DateTime<Utc>> {
+    match (format, value) {
+        (TimeFormat::Unix, Value::Number(n)) => {
+            let secs = n.as_f64()?;
+            Utc.timestamp_opt(secs.trunc() as i64, (secs.fract() * 1e9) as u32)
+                .single()
+        }
+        (TimeFormat::UnixMs, Value::Number(n)) => {
+            Utc.timestamp_millis_opt(n.as_f64()? as i64).single()
+        }
+        (TimeFormat::Unix, Value::String(s)) | (TimeFormat::UnixMs, Value::String(s)) => {
+            parse_time(&Value::from(s.parse::<f64>().ok()?), format)
+        }
+        (TimeFormat::Rfc3339, Value::String(s)) => DateTime::parse_from_rfc3339(s)
+            .ok()
+            .map(|time| time.with_timezone(&Utc)),
+        // a format without offset is utc
+        (TimeFormat::Strftime(format), Value::String(s)) => DateTime::parse_from_str(s, format)
+            .map(|time| time.with_timezone(&Utc))
+            .or_else(|_| NaiveDateTime::parse_from_str(s, format).map(|time| time.and_utc()))
+            .ok(),
+        _ => None,
+    }
+}
+
+// the value at the dotted path, an empty path is the value itself
+fn get<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    if path.is_empty() {
+        return Some(value);
+    }
+    path.split('.')
+        .try_fold(value, |value, key| value.as_object()?.get(key))
+}
+
+// set the value at the dotted path creating the objects on the way,
+// an object set at the empty path is merged into the value
+fn set(value: &mut Value, path: &str, field: Value) {
+    if path.is_empty() {
+        if let (Value::Object(value), Value::Object(field)) = (value, field) {
+            value.extend(field);
+        }
+        return;
+    }
+    let mut value = value;
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        if !value.is_object() {
+            *value = Value::Object(Map::new());
+        }
+        let object = match value.as_object_mut() {
+            Some(it) => it,
+            None => return,
+        };
+        if keys.peek().is_none() {
+            object.insert(key.to_string(), field);
+            return;
+        }
+        value = object
+            .entry(key)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+}
+
+// remove the value at the dotted path
+fn take(value: &mut Value, path: &str) -> Option<Value> {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (parent, key),
+        None => ("", path),
+    };
+    let mut value = value;
+    if !parent.is_empty() {
+        for key in parent.split('.') {
+            value = value.as_object_mut()?.get_mut(key)?;
+        }
+    }
+    value.as_object_mut()?.remove(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pipeline;
+    use crate::rules::Rules;
+    use serde_json::{json, Value};
+
+    fn apply(processors: &str, envelope: Value) -> Value {
+        let rules = Rules::parse(&format!(r#"{{"processors":{}}}"#, processors)).unwrap();
+        let mut envelope = envelope;
+        Pipeline::new(&rules.processors)
+            .unwrap()
+            .apply(&mut envelope);
+        envelope
+    }
+
+    #[test]
+    fn it_reshapes_fields() {
+        let envelope = json!({"custom":{"nodeId":"pod-0","version":"v1.0.0"},"message":"m"});
+        assert_eq!(
+            apply(
+                r#"[
+                    {"rename":{"from":"custom.nodeId","to":"kubernetes.pod"}},
+                    {"drop":{"fields":["custom.version","missing.field"]}},
+                    {"add":{"field":"env","value":"prod"}}
+                ]"#,
+                envelope
+            ),
+            json!({"custom":{},"kubernetes":{"pod":"pod-0"},"message":"m","env":"prod"})
+        );
+    }
+
+    #[test]
+    fn it_parses_payloads() {
+        let envelope = json!({"message":r#"{"level":"info","ts":"2021-03-16 09:05:01.5"} "#});
+        assert_eq!(
+            apply(
+                r#"[
+                    {"parse_json":{"field":"message","target":"","drop_source":true}},
+                    {"timestamp":{"field":"ts","format":"%Y-%m-%d %H:%M:%S%.f"}},
+                    {"parse_json":{"field":"missing"}}
+                ]"#,
+                envelope
+            ),
+            json!({"level":"info","ts":"2021-03-16 09:05:01.5","time":"2021-03-16T09:05:01.500Z"})
+        );
+
+        let envelope =
+            json!({"message":"GET /api/order 200 card 4111111111111111","ts":1615885501});
+        assert_eq!(
+            apply(
+                r#"[
+                    {"regex":{"field":"message","pattern":"^(?P<method>[A-Z]+) (?P<path>\\S+) (?P<status>\\d+)","target":"http"}},
+                    {"mask":{"field":"message","pattern":"\\d{16}"}},
+                    {"timestamp":{"field":"ts","format":"unix","target":"time"}}
+                ]"#,
+                envelope
+            ),
+            json!({
+                "message":"GET /api/order 200 card ***",
+                "ts":1615885501,
+                "http":{"method":"GET","path":"/api/order","status":"200"},
+                "time":"2021-03-16T09:05:01Z"
+            })
+        );
+    }
+
+    #[test]
+    fn it_rejects_bad_processors() {
+        assert!(
+            Rules::parse(r#"{"processors":[{"regex":{"field":"message","pattern":"\\d+"}}]}"#)
+                .is_err()
+        );
+        assert!(
+            Rules::parse(r#"{"processors":[{"timestamp":{"field":"ts","format":"%Q"}}]}"#).is_err()
+        );
+        assert!(Rules::parse(r#"{"processors":[{"add":{"field":"env"}}]}"#).is_err());
+        assert!(
+            Rules::parse(r#"{"processors":[{"regex":{"field":"message","pattern":"("}}]}"#)
+                .is_err()
+        );
+        assert!(Rules::parse(r#"{"processors":[{"upper":{"field":"message"}}]}"#).is_err());
+    }
+}
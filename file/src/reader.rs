@@ -0,0 +1,371 @@
+use crate::decoder::{new_decoder, Decode, LogRecord};
+use crate::filter::Filter;
+use crate::handle::{FileChange, FileHandle};
+use crate::limit::Limiter;
+use crate::mask::Masker;
+use crate::multiline::Multiline;
+use crate::offsets::Offsets;
+use crate::process::Pipeline;
+use crate::rules::{EnvelopeRule, MaskingRule, RateLimitRule, Rules};
+use common::metrics::{self, Metric};
+use common::{Item, Runtime};
+use db::Pod;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// how often a reader without multiline rule wakes up when its file is idle
+pub(crate) const IDLE_TICK: Duration = Duration::from_secs(1);
+
+// Reader follows one log file and ships its decoded records
+pub(crate) struct Reader {
+    handle: FileHandle,
+    pod: Pod,
+    decoder: Box<dyn Decode>,
+    multiline: Option<Multiline>,
+    filter: Option<Filter>,
+    envelope: EnvelopeRule,
+    pipeline: Option<Pipeline>,
+    masker: Option<Masker>,
+    limiter: Option<Limiter>,
+    bf: String,
+    // bytes read but not shipped yet, e.g. docker partial lines
+    uncommitted: i64,
+    // end offset of the last shipped record
+    offset: i64,
+    offsets: Arc<Mutex<Offsets>>,
+    metrics: ReaderMetrics,
+}
+
+// the lines and bytes a reader read from its file and shipped to the outputs
+struct ReaderMetrics {
+    read_lines: Metric,
+    read_bytes: Metric,
+    shipped_lines: Metric,
+    shipped_bytes: Metric,
+}
+
+// the labels of the metrics of the container of pod
+pub(crate) fn labels(pod: &Pod) -> [(&str, &str); 3] {
+    [
+        ("namespace", pod.ns.as_str()),
+        ("pod", pod.pod_name.as_str()),
+        ("container", pod.container.as_str()),
+    ]
+}
+
+impl ReaderMetrics {
+    fn new(pod: &Pod) -> Self {
+        let labels = labels(pod);
+        Self {
+            read_lines: metrics::counter("harvest_read_lines_total", "records read", &labels),
+            read_bytes: metrics::counter("harvest_read_bytes_total", "bytes read", &labels),
+            shipped_lines: metrics::counter(
+                "harvest_shipped_lines_total",
+                "records shipped to the outputs",
+                &labels,
+            ),
+            shipped_bytes: metrics::counter(
+                "harvest_shipped_bytes_total",
+                "message bytes shipped to the outputs",
+                &labels,
+            ),
+        }
+    }
+}
+
+impl Reader {
+    pub(crate) fn new(
+        handle: FileHandle,
+        pod: Pod,
+        runtime: Runtime,
+        masking: &HashMap<String, MaskingRule>,
+        rate_limits: &[RateLimitRule],
+    ) -> Self {
+        let rules = Rules::parse(&pod.filter).unwrap_or_else(|e| {
+            eprintln!("frw parse rules of {:?} error: {:?}", pod.path, e);
+            Rules::default()
+        });
+        let multiline = rules
+            .multiline
+            .as_ref()
+            .and_then(|rule| match Multiline::new(rule) {
+                Ok(it) => Some(it),
+                Err(e) => {
+                    eprintln!("frw multiline rule of {:?} error: {:?}", pod.path, e);
+                    None
+                }
+            });
+        let filter = rules
+            .filter
+            .as_ref()
+            .and_then(|rule| match Filter::new(rule) {
+                Ok(it) => Some(it),
+                Err(e) => {
+                    eprintln!("frw filter rule of {:?} error: {:?}", pod.path, e);
+                    None
+                }
+            });
+        let pipeline = match Pipeline::new(&rules.processors) {
+            Ok(it) if !it.is_empty() => Some(it),
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("frw processors of {:?} error: {:?}", pod.path, e);
+                None
+            }
+        };
+        // the masking of the task adds to the one of its namespace
+        let namespace = masking.get(&pod.ns).or_else(|| masking.get("*"));
+        let masking = match (namespace, rules.masking.as_ref()) {
+            (Some(namespace), Some(task)) => Some(namespace.merge(task)),
+            (namespace, task) => namespace.or(task).cloned(),
+        };
+        let masker = masking.and_then(|rule| match Masker::new(&rule, &pod.ns) {
+            Ok(it) => Some(it),
+            Err(e) => {
+                eprintln!("frw masking of {:?} error: {:?}", pod.path, e);
+                None
+            }
+        });
+        // the rate limit of the task applies together with the one of the node
+        let node_limit = rate_limits.iter().find(|rule| rule.matches(&pod));
+        let limiter = match Limiter::new(rules.rate_limit.as_ref(), node_limit, &pod) {
+            Ok(it) => it,
+            Err(e) => {
+                eprintln!("frw rate limit of {:?} error: {:?}", pod.path, e);
+                None
+            }
+        };
+
+        let offset = handle.offset();
+        let offsets = Offsets::new(&pod.path, offset);
+        Self {
+            handle,
+            metrics: ReaderMetrics::new(&pod),
+            pod,
+            decoder: new_decoder(runtime),
+            multiline,
+            filter,
+            envelope: rules.envelope,
+            pipeline,
+            masker,
+            limiter,
+            bf: String::new(),
+            uncommitted: 0,
+            offset,
+            offsets,
+        }
+    }
+
+    // drop the reader, which releases its handles on the metrics of its container, then
+    // unregister them, metrics::unregister keeps those another reader of the container
+    // still holds a handle on, so only the metrics nobody updates any more are removed
+    pub(crate) fn close(self) {
+        let pod = self.pod.clone();
+        drop(self);
+        metrics::unregister(&labels(&pod));
+    }
+
+    // read the new lines of the file and reopen it when it was rotated or truncated
+    pub(crate) fn follow(&mut self) {
+        self.replay();
+        self.read_lines(false);
+
+        let change = match self.handle.check() {
+            Ok(it) => it,
+            Err(e) => {
+                eprintln!("frw stat file {:?} error: {:?}", self.pod.path, e);
+                return;
+            }
+        };
+        match change {
+            FileChange::Rotated => {
+                // drain what was left in the old file before switching to the new one
+                self.read_lines(true);
+                self.flush();
+                if let Err(e) = self.handle.reopen() {
+                    eprintln!("frw reopen rotated file {:?} error: {:?}", self.pod.path, e);
+                    return;
+                }
+            }
+            FileChange::Truncated => {
+                self.flush();
+                if let Err(e) = self.handle.rewind() {
+                    eprintln!(
+                        "frw rewind truncated file {:?} error: {:?}",
+                        self.pod.path, e
+                    );
+                    return;
+                }
+            }
+            FileChange::Unchanged => return,
+        }
+        self.decoder.reset();
+        // acks of the previous file must not move the offset of the new one
+        if let Ok(mut offsets) = self.offsets.lock() {
+            offsets.retire();
+        }
+        self.offsets = Offsets::new(&self.pod.path, 0);
+        self.uncommitted = 0;
+        self.offset = 0;
+        db::set_offset(&self.pod.path, 0);
+        self.read_lines(false);
+    }
+
+    // read the file again from the last committed offset once a record was not delivered,
+    // the records shipped after it are shipped again
+    pub(crate) fn replay(&mut self) {
+        let failed_at = match self.offsets.lock() {
+            Ok(offsets) => offsets.failed_at(),
+            Err(_) => None,
+        };
+        let offset = match failed_at {
+            Some(it) => it,
+            None => return,
+        };
+        if let Err(e) = self.handle.seek(offset) {
+            eprintln!("frw seek {:?} to {} error: {:?}", self.pod.path, offset, e);
+            return;
+        }
+        self.decoder.reset();
+        if let Some(multiline) = self.multiline.as_mut() {
+            multiline.flush();
+        }
+        self.bf.clear();
+        self.offsets = Offsets::new(&self.pod.path, offset);
+        self.uncommitted = 0;
+        self.offset = offset;
+        self.read_lines(false);
+    }
+
+    // ship every complete line until the end of the file
+    pub(crate) fn read_lines(&mut self, partial: bool) {
+        loop {
+            let size = match self.handle.read_line(&mut self.bf, partial) {
+                Ok(it) => it,
+                Err(e) => {
+                    eprintln!("frw read file {:?} error: {:?}", self.pod.path, e);
+                    return;
+                }
+            };
+            if size == 0 {
+                return;
+            }
+            self.uncommitted += size as i64;
+
+            if let Some(record) = self.decoder.decode(&self.bf) {
+                // the offset must not move past the partial line of another stream,
+                // the bytes of the record are counted once no partial line is left
+                let size = match self.decoder.pending() {
+                    true => 0,
+                    false => std::mem::take(&mut self.uncommitted),
+                };
+                match self.multiline.as_mut() {
+                    Some(multiline) => {
+                        if let Some((event, size)) = multiline.push(record, size) {
+                            self.ship(&event, size)
+                        }
+                    }
+                    None => self.ship(&record, size),
+                }
+            }
+            self.bf.clear();
+        }
+    }
+
+    // how long the reader may wait for file events before calling flush_expired
+    pub(crate) fn tick(&self) -> Duration {
+        match &self.multiline {
+            Some(multiline) => multiline.timeout(),
+            None => IDLE_TICK,
+        }
+    }
+
+    // ship the pending multiline event
+    pub(crate) fn flush(&mut self) {
+        if let Some((event, size)) = self.multiline.as_mut().and_then(|m| m.flush()) {
+            self.ship(&event, size)
+        }
+    }
+
+    // ship the pending multiline event once no line was appended within its timeout
+    pub(crate) fn flush_expired(&mut self) {
+        if let Some((event, size)) = self.multiline.as_mut().and_then(|m| m.flush_expired()) {
+            self.ship(&event, size)
+        }
+    }
+
+    // the offset only moves past records that were delivered by the output,
+    // records that are empty or rejected by the filter or the rate limit are skipped
+    // but still move the offset.
+    // shipping blocks while the output queue is full or the rate limit delays the record,
+    // which pauses the reads of this file only.
+    fn ship(&mut self, record: &LogRecord, size: i64) {
+        self.offset += size;
+        self.metrics.read_lines.inc();
+        self.metrics.read_bytes.add(size);
+        let matched = match &self.filter {
+            Some(filter) => filter.is_match(&record.message),
+            None => true,
+        };
+        let matched = match &mut self.limiter {
+            Some(limiter) if matched => limiter.admit(record.message.len()),
+            _ => matched,
+        };
+        let message = match matched {
+            true => encode_message(
+                &self.pod,
+                record,
+                &self.envelope,
+                self.pipeline.as_ref(),
+                self.masker.as_ref(),
+            ),
+            false => None,
+        };
+        let message = match message {
+            Some(it) => it,
+            None => {
+                Offsets::skip(&self.offsets, self.offset);
+                return;
+            }
+        };
+        self.metrics.shipped_lines.inc();
+        self.metrics.shipped_bytes.add(record.message.len() as i64);
+        let ack = Offsets::ack(&self.offsets, self.offset);
+        // the envelope goes to the outputs as it is, they encode it with the codec of their channel
+        if let Err(e) = output::ship(&self.pod.output, Item::from(message), ack) {
+            eprintln!("frw ship {:?} error: {:?}", self.pod.path, e);
+        }
+    }
+}
+
+// the envelope of the record, reshaped by the processors of the pod and masked
+fn encode_message(
+    pod: &Pod,
+    record: &LogRecord,
+    envelope: &EnvelopeRule,
+    pipeline: Option<&Pipeline>,
+    masker: Option<&Masker>,
+) -> Option<Value> {
+    if record.message.is_empty() {
+        return None;
+    }
+    let mut message = json!({
+        "custom": envelope.custom(pod),
+        "message": record.message,
+    });
+    if !record.stream.is_empty() {
+        message["stream"] = json!(record.stream);
+    }
+    if !record.time.is_empty() {
+        message["time"] = json!(record.time);
+    }
+    if let Some(pipeline) = pipeline {
+        pipeline.apply(&mut message);
+    }
+    if let Some(masker) = masker {
+        masker.apply(&mut message);
+    }
+    Some(message)
+}
@@ -0,0 +1,417 @@
+use crate::filter::Filter;
+use crate::limit::Limiter;
+use crate::mask::Masker;
+use crate::multiline::Multiline;
+use crate::process::Pipeline;
+use common::Result;
+use db::Pod;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+// Rules is the per task read path configuration carried in the `rules` field
+// of the api server request, an empty string means no rules.
+// {"multiline":{"start":"^\\d{4}-\\d{2}-\\d{2}","timeout_ms":1000,"max_lines":500},
+//  "filter":{"exclude":["healthz"],"levels":["ERROR","WARN"]},
+//  "processors":[{"parse_json":{"field":"message"}},{"drop":{"fields":["custom.version"]}}],
+//  "masking":{"builtin":["phone","bank_card"],"keys":["password"]},
+//  "rate_limit":{"lines_per_sec":500,"bytes_per_sec":1048576,"action":"sample","sample":10},
+//  "envelope":{"node_id":"pod_name","version":"v1.0.0"}}
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    pub multiline: Option<MultilineRule>,
+    pub filter: Option<FilterRule>,
+    // applied in order to the envelope of every shipped record
+    pub processors: Vec<ProcessorRule>,
+    // applied after the processors, adds to the masking of the namespace
+    pub masking: Option<MaskingRule>,
+    // applies on top of the rate limit of the node matching the pod
+    pub rate_limit: Option<RateLimitRule>,
+    // the custom block the processors start from
+    pub envelope: EnvelopeRule,
+}
+
+impl Rules {
+    // parse and validate the rules, patterns that do not compile are an error
+    pub fn parse(rules: &str) -> Result<Self> {
+        if rules.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let rules = serde_json::from_str::<Rules>(rules)?;
+        if let Some(rule) = &rules.multiline {
+            Multiline::new(rule)?;
+        }
+        if let Some(rule) = &rules.filter {
+            Filter::new(rule)?;
+        }
+        Pipeline::new(&rules.processors)?;
+        if let Some(rule) = &rules.masking {
+            Masker::check(rule)?;
+        }
+        if let Some(rule) = &rules.rate_limit {
+            Limiter::check(rule)?;
+        }
+        rules.envelope.check()?;
+        Ok(rules)
+    }
+}
+
+// EnvelopeRule shapes the custom block of the envelope of a record from its pod,
+// the defaults keep the block as it always was.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvelopeRule {
+    // the pod field copied to custom.nodeId: pod_name, node_name or container, left out when empty
+    pub node_id: String,
+    // custom.version, left out when empty
+    pub version: String,
+}
+
+impl Default for EnvelopeRule {
+    fn default() -> Self {
+        Self {
+            node_id: "pod_name".to_string(),
+            version: "v1.0.0".to_string(),
+        }
+    }
+}
+
+impl EnvelopeRule {
+    fn check(&self) -> Result<()> {
+        self.node_id(&Pod::default()).map(|_| ())
+    }
+
+    fn node_id<'a>(&self, pod: &'a Pod) -> Result<Option<&'a str>> {
+        match self.node_id.as_str() {
+            "" => Ok(None),
+            "pod_name" => Ok(Some(&pod.pod_name)),
+            "node_name" => Ok(Some(&pod.node_name)),
+            "container" => Ok(Some(&pod.container)),
+            field => Err(format!(
+                "envelope node_id `{}` is not supported, expect pod_name, node_name or container",
+                field
+            )
+            .into()),
+        }
+    }
+
+    // the custom block of the records of pod
+    pub(crate) fn custom(&self, pod: &Pod) -> Value {
+        let mut custom = json!({
+            "namespace": pod.ns,
+            "nodeName": pod.node_name,
+            "container": pod.container,
+            "serviceName": pod.service_name,
+            "ips": pod.ips,
+        });
+        if let Ok(Some(node_id)) = self.node_id(pod) {
+            custom["nodeId"] = json!(node_id);
+        }
+        if !self.version.is_empty() {
+            custom["version"] = json!(self.version);
+        }
+        custom
+    }
+}
+
+// MultilineRule joins the lines of one event, e.g. a stack trace.
+// with `start` a line matching it begins a new event and every other line is appended,
+// with `continuation` a line matching it is appended to the previous event.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MultilineRule {
+    pub start: Option<String>,
+    pub continuation: Option<String>,
+    // flush a pending event after no line was appended for this long, above 0
+    pub timeout_ms: u64,
+    pub max_lines: usize,
+}
+
+impl Default for MultilineRule {
+    fn default() -> Self {
+        Self {
+            start: None,
+            continuation: None,
+            timeout_ms: 1000,
+            max_lines: 500,
+        }
+    }
+}
+
+// FilterRule selects the records of a pod that are shipped, a record must match
+// one of `include` (when given), none of `exclude`, one of `levels` (when given)
+// and every predicate of `fields`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterRule {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // level keywords, matched against the level field of json lines or as words of the line
+    pub levels: Vec<String>,
+    // predicates on the fields of json lines, lines that are not json never match
+    pub fields: Vec<FieldPredicate>,
+}
+
+// {"field":"http.status","op":"ne","value":200}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldPredicate {
+    pub field: String,
+    pub op: FieldOp,
+    #[serde(default)]
+    pub value: Option<Value>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldOp {
+    Eq,
+    Ne,
+    Exists,
+    Missing,
+    Regex,
+}
+
+// ProcessorRule transforms the envelope of a record, fields are dotted paths into it,
+// e.g. `custom.nodeId` or `message`, an empty target is the envelope itself.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ProcessorRule {
+    // {"add":{"field":"env","value":"prod"}}
+    Add {
+        field: String,
+        value: Value,
+    },
+    // {"rename":{"from":"custom.nodeId","to":"pod"}}
+    Rename {
+        from: String,
+        to: String,
+    },
+    // {"drop":{"fields":["custom.version","custom.ips"]}}
+    Drop {
+        fields: Vec<String>,
+    },
+    // {"parse_json":{"field":"message","target":"","drop_source":false}}
+    ParseJson {
+        field: String,
+        #[serde(default)]
+        target: String,
+        #[serde(default)]
+        drop_source: bool,
+    },
+    // the named groups of the pattern become fields of the target
+    // {"regex":{"field":"message","pattern":"(?P<method>[A-Z]+) (?P<path>\\S+)","target":"http"}}
+    Regex {
+        field: String,
+        pattern: String,
+        #[serde(default)]
+        target: String,
+    },
+    // the field parsed by format, rfc3339, unix, unix_ms or a strftime format, written as rfc3339
+    // {"timestamp":{"field":"ts","format":"%Y-%m-%d %H:%M:%S%.f","target":"time"}}
+    Timestamp {
+        field: String,
+        format: String,
+        #[serde(default = "default_timestamp_target")]
+        target: String,
+    },
+    // {"mask":{"field":"message","pattern":"\\d{11}","replacement":"***"}}
+    Mask {
+        field: String,
+        pattern: String,
+        #[serde(default = "default_mask_replacement")]
+        replacement: String,
+    },
+}
+
+// MaskingRule redacts sensitive values before they leave the node.
+// `builtin` rules are phone (mainland mobile numbers), id_number (resident id numbers),
+// bank_card (luhn checked card numbers) and bearer_token, `keys` are the keys of json
+// objects whose values are replaced, matched case insensitive.
+// {"builtin":["phone","bank_card"],"patterns":[{"name":"email","pattern":"[\\w.]+@[\\w.]+"}],
+//  "keys":["password","authorization"],"replacement":"***"}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaskingRule {
+    pub builtin: Vec<String>,
+    pub patterns: Vec<MaskPattern>,
+    pub keys: Vec<String>,
+    // the value of a masked key
+    pub replacement: String,
+}
+
+impl Default for MaskingRule {
+    fn default() -> Self {
+        Self {
+            builtin: vec![],
+            patterns: vec![],
+            keys: vec![],
+            replacement: default_mask_replacement(),
+        }
+    }
+}
+
+// a pattern whose matches are replaced, `$name` expands its groups,
+// the masked values are counted under the name
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaskPattern {
+    #[serde(default = "default_mask_pattern_name")]
+    pub name: String,
+    pub pattern: String,
+    #[serde(default = "default_mask_replacement")]
+    pub replacement: String,
+}
+
+impl MaskingRule {
+    // rules = a json object of namespace to masking rule, `*` is any other namespace
+    pub fn parse_namespaces(rules: &str) -> Result<HashMap<String, MaskingRule>> {
+        let rules = serde_json::from_str::<HashMap<String, MaskingRule>>(rules)?;
+        for rule in rules.values() {
+            Masker::check(rule)?;
+        }
+        Ok(rules)
+    }
+
+    // the rule of a namespace together with the one of a task in it, the task adds builtins,
+    // patterns and keys but never drops the ones of the namespace nor changes its replacement
+    pub fn merge(&self, task: &MaskingRule) -> MaskingRule {
+        let mut merged = self.clone();
+        for name in task.builtin.iter() {
+            if !merged.builtin.contains(name) {
+                merged.builtin.push(name.clone());
+            }
+        }
+        for pattern in task.patterns.iter() {
+            if !merged
+                .patterns
+                .iter()
+                .any(|p| p.name == pattern.name && p.pattern == pattern.pattern)
+            {
+                merged.patterns.push(pattern.clone());
+            }
+        }
+        for key in task.keys.iter() {
+            if !merged.keys.iter().any(|k| k.eq_ignore_ascii_case(key)) {
+                merged.keys.push(key.clone());
+            }
+        }
+        merged
+    }
+}
+
+// RateLimitRule caps the lines and bytes per second a pod ships with token buckets holding
+// `burst` seconds of the rate, a rate of 0 is no limit. the lines over the limit are dropped,
+// sampled, one in `sample` is shipped, or delayed, which pauses the reads of the pod.
+// the rate limits of the node apply to the pods matching them, an empty match list matches
+// any pod, the first rate limit matching a pod is its limit:
+// [{"ns":["batch"],"service":["exporter"],"lines_per_sec":200,"action":"delay"}]
+// the pods of a node limit share its buckets per value of the fields it matches on, the one
+// above is the quota of each exporter service of the namespace. the limit of a task is shared
+// by the containers of its pod and applies on top of the node limit.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitRule {
+    pub ns: Vec<String>,
+    pub service: Vec<String>,
+    pub pod: Vec<String>,
+    pub lines_per_sec: f64,
+    pub bytes_per_sec: f64,
+    pub burst: f64,
+    pub action: RateLimitAction,
+    pub sample: u64,
+}
+
+impl Default for RateLimitRule {
+    fn default() -> Self {
+        Self {
+            ns: vec![],
+            service: vec![],
+            pod: vec![],
+            lines_per_sec: 0.0,
+            bytes_per_sec: 0.0,
+            burst: 1.0,
+            action: RateLimitAction::Drop,
+            sample: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    Drop,
+    Sample,
+    Delay,
+}
+
+impl RateLimitRule {
+    // rules = a json list of rate limits
+    pub fn parse(rules: &str) -> Result<Vec<RateLimitRule>> {
+        let rules = serde_json::from_str::<Vec<RateLimitRule>>(rules)?;
+        for rule in rules.iter() {
+            Limiter::check(rule)?;
+        }
+        Ok(rules)
+    }
+
+    pub(crate) fn matches(&self, pod: &Pod) -> bool {
+        let field =
+            |values: &[String], value: &str| values.is_empty() || values.iter().any(|v| v == value);
+        field(&self.ns, &pod.ns)
+            && field(&self.service, &pod.service_name)
+            && field(&self.pod, &pod.pod_name)
+    }
+}
+
+fn default_mask_pattern_name() -> String {
+    "pattern".to_string()
+}
+
+fn default_timestamp_target() -> String {
+    "time".to_string()
+}
+
+fn default_mask_replacement() -> String {
+    "***".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rules;
+    use db::Pod;
+    use serde_json::json;
+
+    #[test]
+    fn it_parses_rules() {
+        let rules = Rules::parse("").unwrap();
+        assert!(rules.multiline.is_none() && rules.processors.is_empty());
+        assert!(Rules::parse(r#"{"multi_line":{}}"#).is_err());
+        assert!(Rules::parse("not json").is_err());
+    }
+
+    #[test]
+    fn it_shapes_the_envelope() {
+        let pod = Pod {
+            ns: "web".to_string(),
+            pod_name: "web-0".to_string(),
+            node_name: "node1".to_string(),
+            container: "app".to_string(),
+            ..Default::default()
+        };
+        let custom = Rules::parse("").unwrap().envelope.custom(&pod);
+        assert_eq!(custom["nodeId"], json!("web-0"));
+        assert_eq!(custom["version"], json!("v1.0.0"));
+        assert_eq!(custom["namespace"], json!("web"));
+
+        let rules = Rules::parse(r#"{"envelope":{"node_id":"node_name","version":""}}"#).unwrap();
+        let custom = rules.envelope.custom(&pod);
+        assert_eq!(custom["nodeId"], json!("node1"));
+        assert!(custom.get("version").is_none());
+        let rules = Rules::parse(r#"{"envelope":{"node_id":""}}"#).unwrap();
+        assert!(rules.envelope.custom(&pod).get("nodeId").is_none());
+
+        assert!(Rules::parse(r#"{"envelope":{"node_id":"uid"}}"#).is_err());
+    }
+}
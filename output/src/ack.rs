@@ -0,0 +1,124 @@
+use common::Item;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Ack settles one shipped record: it is done once the output delivered the record,
+// and counts as failed when it is dropped before, e.g. a batch dropped after its retries.
+pub struct Ack {
+    commit: Option<Box<dyn FnOnce(bool) + Send + Sync>>,
+}
+
+impl Ack {
+    pub fn new<F>(commit: F) -> Self
+    where
+        F: FnOnce(bool) + Send + Sync + 'static,
+    {
+        Self {
+            commit: Some(Box::new(commit)),
+        }
+    }
+
+    // an ack nobody waits on
+    pub fn none() -> Self {
+        Self { commit: None }
+    }
+
+    // split the ack for n destinations, it is done once every part is done
+    // and fails as soon as one part fails, i.e. one output failed the record after its retries
+    pub fn split(self, n: usize) -> Vec<Ack> {
+        if n == 0 {
+            self.done();
+            return vec![];
+        }
+        if self.commit.is_none() || n == 1 {
+            let mut parts = vec![self];
+            parts.extend((1..n).map(|_| Ack::none()));
+            return parts;
+        }
+        let whole = Arc::new(Mutex::new(Some(self)));
+        let remaining = Arc::new(AtomicUsize::new(n));
+        (0..n)
+            .map(|_| {
+                let whole = whole.clone();
+                let remaining = remaining.clone();
+                Ack::new(move |ok| {
+                    if ok && remaining.fetch_sub(1, Ordering::SeqCst) > 1 {
+                        return;
+                    }
+                    let whole = whole.lock().ok().and_then(|mut whole| whole.take());
+                    // a failed part drops the whole ack, which fails it
+                    if let (Some(whole), true) = (whole, ok) {
+                        whole.done()
+                    }
+                })
+            })
+            .collect()
+    }
+
+    pub fn done(mut self) {
+        if let Some(commit) = self.commit.take() {
+            commit(true)
+        }
+    }
+}
+
+impl Drop for Ack {
+    fn drop(&mut self) {
+        if let Some(commit) = self.commit.take() {
+            commit(false)
+        }
+    }
+}
+
+// Record is an item queued for an output together with its ack
+pub struct Record {
+    pub item: Item,
+    pub ack: Ack,
+}
+
+impl Record {
+    pub fn new(item: Item, ack: Ack) -> Self {
+        Self { item, ack }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ack;
+    use std::sync::{Arc, Mutex};
+
+    fn ack(settled: &Arc<Mutex<Vec<bool>>>) -> Ack {
+        let settled = settled.clone();
+        Ack::new(move |ok| settled.lock().unwrap().push(ok))
+    }
+
+    #[test]
+    fn it_settles_once() {
+        let settled = Arc::new(Mutex::new(vec![]));
+        ack(&settled).done();
+        drop(ack(&settled));
+        Ack::none().done();
+        drop(Ack::none());
+        assert_eq!(*settled.lock().unwrap(), vec![true, false]);
+    }
+
+    #[test]
+    fn it_splits() {
+        let settled = Arc::new(Mutex::new(vec![]));
+        let mut parts = ack(&settled).split(3);
+        parts.pop().unwrap().done();
+        parts.pop().unwrap().done();
+        assert!(settled.lock().unwrap().is_empty());
+        parts.pop().unwrap().done();
+        assert_eq!(*settled.lock().unwrap(), vec![true]);
+
+        let mut parts = ack(&settled).split(2);
+        drop(parts.pop());
+        parts.pop().unwrap().done();
+        assert_eq!(*settled.lock().unwrap(), vec![true, false]);
+
+        ack(&settled).split(0);
+        assert_eq!(*settled.lock().unwrap(), vec![true, false, true]);
+        assert_eq!(Ack::none().split(2).len(), 2);
+    }
+}
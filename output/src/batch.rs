@@ -0,0 +1,95 @@
+use crate::Ack;
+use std::time::{Duration, Instant};
+
+// Batch buffers the records of an output with their acks,
+// it is sent once it holds size records or its first record waited for linger.
+pub(crate) struct Batch<T> {
+    items: Vec<T>,
+    acks: Vec<Ack>,
+    size: usize,
+    linger: Duration,
+    deadline: Instant,
+}
+
+impl<T> Batch<T> {
+    pub(crate) fn new(size: usize, linger: Duration) -> Self {
+        Self {
+            items: Vec::with_capacity(size),
+            acks: Vec::with_capacity(size),
+            size,
+            linger,
+            deadline: Instant::now(),
+        }
+    }
+
+    // buffer the record, true once the batch is full
+    pub(crate) fn push(&mut self, item: T, ack: Ack) -> bool {
+        if self.items.is_empty() {
+            self.deadline = Instant::now() + self.linger;
+        }
+        self.items.push(item);
+        self.acks.push(ack);
+        self.items.len() >= self.size
+    }
+
+    pub(crate) fn is_due(&self) -> bool {
+        !self.items.is_empty() && Instant::now() >= self.deadline
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub(crate) fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    // empty the batch, the acks of the records at the sorted failed indexes fail, the others are done
+    pub(crate) fn settle(&mut self, failed: &[usize]) {
+        let mut failed = failed.iter().peekable();
+        for (i, ack) in self.acks.drain(..).enumerate() {
+            if failed.peek() == Some(&&i) {
+                failed.next();
+                continue;
+            }
+            ack.done();
+        }
+        self.items.clear();
+    }
+
+    // empty the batch and fail all its acks
+    pub(crate) fn fail(&mut self) {
+        self.acks.clear();
+        self.items.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Batch;
+    use crate::Ack;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn it_settles_acks() {
+        let settled = Arc::new(Mutex::new(vec![]));
+        let mut batch = Batch::new(3, Duration::from_secs(60));
+        for i in 0..3 {
+            let settled = settled.clone();
+            let full = batch.push(i, Ack::new(move |ok| settled.lock().unwrap().push((i, ok))));
+            assert_eq!(full, i == 2);
+        }
+        assert!(!batch.is_due());
+        batch.settle(&[1]);
+        assert!(batch.is_empty());
+        assert_eq!(
+            *settled.lock().unwrap(),
+            vec![(0, true), (1, false), (2, true)]
+        );
+    }
+}
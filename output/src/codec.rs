@@ -0,0 +1,322 @@
+use super::{Item, Result};
+use prost::Message;
+use serde_json::{json, Map, Value};
+
+use std::collections::HashMap;
+
+// the avro schema of the records of codec=avro, to be registered in the schema registry
+// whose id is given by the schema_id option of the channel
+pub const AVRO_SCHEMA: &str = r#"{"type":"record","name":"LogRecord","namespace":"io.harvest","fields":[{"name":"namespace","type":"string"},{"name":"pod","type":"string"},{"name":"container","type":"string"},{"name":"service","type":"string"},{"name":"node","type":"string"},{"name":"stream","type":"string"},{"name":"time","type":"string"},{"name":"message","type":"string"},{"name":"fields","type":{"type":"map","values":"string"}}]}"#;
+
+// the record of codec=protobuf, the same fields as the avro schema:
+// message LogRecord {
+//   string namespace = 1; string pod = 2; string container = 3; string service = 4;
+//   string node = 5; string stream = 6; string time = 7; string message = 8;
+//   map<string, string> fields = 9;
+// }
+#[derive(Clone, PartialEq, Message)]
+struct LogRecord {
+    #[prost(string, tag = "1")]
+    namespace: String,
+    #[prost(string, tag = "2")]
+    pod: String,
+    #[prost(string, tag = "3")]
+    container: String,
+    #[prost(string, tag = "4")]
+    service: String,
+    #[prost(string, tag = "5")]
+    node: String,
+    #[prost(string, tag = "6")]
+    stream: String,
+    #[prost(string, tag = "7")]
+    time: String,
+    #[prost(string, tag = "8")]
+    message: String,
+    // the other top level fields of the envelope, json encoded unless they are strings
+    #[prost(map = "string, string", tag = "9")]
+    fields: HashMap<String, String>,
+}
+
+impl LogRecord {
+    fn of(item: &Item) -> Self {
+        let field = |path: &str| match item.field(path) {
+            Some(value) => text(value),
+            None => "".to_string(),
+        };
+        let mut record = LogRecord {
+            namespace: field("custom.namespace"),
+            pod: field("custom.nodeId"),
+            container: field("custom.container"),
+            service: field("custom.serviceName"),
+            node: field("custom.nodeName"),
+            stream: field("stream"),
+            time: field("time"),
+            message: field("message"),
+            fields: HashMap::new(),
+        };
+        match item {
+            Item::JSON(Value::Object(object)) => {
+                record.fields = object
+                    .iter()
+                    .filter(|(key, _)| {
+                        !matches!(key.as_str(), "custom" | "stream" | "time" | "message")
+                    })
+                    .map(|(key, value)| (key.clone(), text(value)))
+                    .collect()
+            }
+            other => record.message = other.string(),
+        }
+        record
+    }
+
+    // the avro binary encoding of the record in the field order of AVRO_SCHEMA
+    fn avro(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for value in [
+            &self.namespace,
+            &self.pod,
+            &self.container,
+            &self.service,
+            &self.node,
+            &self.stream,
+            &self.time,
+            &self.message,
+        ] {
+            avro_string(&mut buf, value);
+        }
+        // a map is a block of its entries followed by an empty block
+        if !self.fields.is_empty() {
+            avro_long(&mut buf, self.fields.len() as i64);
+            let mut fields = self.fields.iter().collect::<Vec<_>>();
+            fields.sort();
+            for (key, value) in fields {
+                avro_string(&mut buf, key);
+                avro_string(&mut buf, value);
+            }
+        }
+        avro_long(&mut buf, 0);
+        buf
+    }
+}
+
+fn avro_long(buf: &mut Vec<u8>, n: i64) {
+    let mut n = ((n << 1) ^ (n >> 63)) as u64;
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn avro_string(buf: &mut Vec<u8>, s: &str) {
+    avro_long(buf, s.len() as i64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+// the string of a value, json encoded unless it is a string
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "".to_string(),
+        other => other.to_string(),
+    }
+}
+
+// Codec encodes the records of an output channel, selected by its codec option
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Codec {
+    // the message of the record only
+    Raw,
+    // the envelope as it is, {"custom":{...},"message":...}
+    Json,
+    // the envelope with the fields of nested objects at the top level
+    Flat,
+    // key=value pairs of the flat envelope
+    Logfmt,
+    // AVRO_SCHEMA, in the confluent wire format with the id of the schema when it has one
+    Avro(Option<u32>),
+    // LogRecord
+    Protobuf,
+}
+
+impl Codec {
+    pub(crate) fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "raw" => Codec::Raw,
+            "json" => Codec::Json,
+            "flat" => Codec::Flat,
+            "logfmt" => Codec::Logfmt,
+            "avro" => Codec::Avro(None),
+            "protobuf" => Codec::Protobuf,
+            _ => {
+                return Err(format!(
+                    "codec `{}` is not supported, expect raw, json, flat, logfmt, avro or protobuf",
+                    name
+                )
+                .into())
+            }
+        })
+    }
+
+    // the codec with the schema id of the schema registry, only avro has one
+    pub(crate) fn with_schema_id(self, id: u32) -> Result<Self> {
+        match self {
+            Codec::Avro(_) => Ok(Codec::Avro(Some(id))),
+            codec => Err(format!("codec {:?} takes no schema_id, expect avro", codec).into()),
+        }
+    }
+
+    // the records of a text codec are utf-8 lines
+    pub(crate) fn is_text(&self) -> bool {
+        !matches!(self, Codec::Avro(_) | Codec::Protobuf)
+    }
+
+    // the records of a json codec are json objects
+    pub(crate) fn is_json(&self) -> bool {
+        matches!(self, Codec::Json | Codec::Flat)
+    }
+
+    // the record as text, the lossy utf-8 of a binary record
+    pub(crate) fn text(&self, item: &Item) -> String {
+        match self {
+            Codec::Raw => match item.field("message") {
+                Some(message) => text(message),
+                None => item.string(),
+            },
+            Codec::Json => match item {
+                Item::JSON(value @ Value::Object(_)) => value.to_string(),
+                other => json!({ "message": text_of(other) }).to_string(),
+            },
+            Codec::Flat => Value::Object(flatten(item)).to_string(),
+            Codec::Logfmt => logfmt(&flatten(item)),
+            Codec::Avro(_) | Codec::Protobuf => {
+                String::from_utf8_lossy(&self.encode(item)).into_owned()
+            }
+        }
+    }
+
+    pub(crate) fn encode(&self, item: &Item) -> Vec<u8> {
+        match self {
+            Codec::Avro(id) => {
+                let mut buf = vec![];
+                if let Some(id) = id {
+                    buf.push(0);
+                    buf.extend_from_slice(&id.to_be_bytes());
+                }
+                buf.extend(LogRecord::of(item).avro());
+                buf
+            }
+            Codec::Protobuf => LogRecord::of(item).encode_to_vec(),
+            codec => codec.text(item).into_bytes(),
+        }
+    }
+}
+
+fn text_of(item: &Item) -> Value {
+    match item {
+        Item::JSON(value) => value.clone(),
+        Item::Default(line) => Value::from(line.as_str()),
+    }
+}
+
+// the fields of nested objects joined by `.`, the fields of the pod in `custom` without prefix
+fn flatten(item: &Item) -> Map<String, Value> {
+    fn walk(flat: &mut Map<String, Value>, prefix: &str, value: &Value) {
+        match value {
+            Value::Object(object) if !object.is_empty() => {
+                for (key, value) in object.iter() {
+                    let key = match (prefix, key.as_str()) {
+                        ("", "custom") => "".to_string(),
+                        ("", key) => key.to_string(),
+                        (prefix, key) => format!("{}.{}", prefix, key),
+                    };
+                    walk(flat, &key, value);
+                }
+            }
+            value if !prefix.is_empty() => {
+                flat.insert(prefix.to_string(), value.clone());
+            }
+            _ => {}
+        }
+    }
+    let mut flat = Map::new();
+    match item {
+        Item::JSON(value @ Value::Object(_)) => walk(&mut flat, "", value),
+        other => {
+            flat.insert("message".to_string(), text_of(other));
+        }
+    }
+    flat
+}
+
+fn logfmt(flat: &Map<String, Value>) -> String {
+    flat.iter()
+        .map(|(key, value)| {
+            let value = text(value);
+            let quote = value.is_empty()
+                || value
+                    .chars()
+                    .any(|c| c == ' ' || c == '=' || c == '"' || c.is_control());
+            if !quote {
+                return format!("{}={}", key, value);
+            }
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r")
+                .replace('\t', "\\t");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Codec, LogRecord};
+    use common::Item;
+    use prost::Message;
+
+    fn item() -> Item {
+        Item::from(
+            r#"{"custom":{"nodeId":"pod-0","namespace":"ns","container":"web","ips":["10.0.0.1"]},"message":"GET / 200","stream":"stdout","http":{"status":200}}"#,
+        )
+    }
+
+    #[test]
+    fn it_encodes_text() {
+        assert_eq!(Codec::Raw.text(&item()), "GET / 200");
+        assert_eq!(Codec::Raw.text(&Item::from("raw line")), "raw line");
+        assert_eq!(
+            Codec::Json.text(&Item::from("raw line")),
+            r#"{"message":"raw line"}"#
+        );
+        assert_eq!(
+            Codec::Flat.text(&item()),
+            r#"{"container":"web","http.status":200,"ips":["10.0.0.1"],"message":"GET / 200","namespace":"ns","nodeId":"pod-0","stream":"stdout"}"#
+        );
+        assert_eq!(
+            Codec::Logfmt.text(&item()),
+            r#"container=web http.status=200 ips="[\"10.0.0.1\"]" message="GET / 200" namespace=ns nodeId=pod-0 stream=stdout"#
+        );
+        assert!(Codec::parse("xml").is_err());
+        assert!(Codec::parse("json").unwrap().with_schema_id(1).is_err());
+    }
+
+    #[test]
+    fn it_encodes_binary() {
+        let record = LogRecord::decode(Codec::Protobuf.encode(&item()).as_slice()).unwrap();
+        assert_eq!(record.pod, "pod-0");
+        assert_eq!(record.message, "GET / 200");
+        assert_eq!(record.fields["http"], r#"{"status":200}"#);
+
+        let avro = Codec::parse("avro")
+            .unwrap()
+            .with_schema_id(7)
+            .unwrap()
+            .encode(&Item::from("m"));
+        // magic byte and schema id, eight strings of which the last is the message, the empty map
+        assert_eq!(avro, vec![0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 2, b'm', 0]);
+    }
+}
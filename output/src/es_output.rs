@@ -0,0 +1,411 @@
+use super::codec::Codec;
+use super::{fields, Ack, Batch, IOutput, Item, Record, Result};
+use chrono::format::{Item as FormatItem, StrftimeItems};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use std::time::Duration;
+use std::{cmp, thread};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// IndexPattern names the index of a record, e.g. harvest-{ns}-%Y.%m.%d:
+// {field} is a field of the record or one of ns, pod, container, service, node,
+// the strftime specifiers are taken from the time of the record or from now.
+#[derive(Clone, Debug, PartialEq)]
+struct IndexPattern(String);
+
+impl IndexPattern {
+    fn parse(pattern: &str) -> Result<Self> {
+        if pattern.is_empty() {
+            return Err("elasticsearch index pattern is empty".into());
+        }
+        if StrftimeItems::new(pattern).any(|item| matches!(item, FormatItem::Error)) {
+            return Err(format!(
+                "elasticsearch index pattern `{}` has a bad date format",
+                pattern
+            )
+            .into());
+        }
+        if !fields::check_template(pattern) {
+            return Err(format!("elasticsearch index pattern `{}` misses a `}}`", pattern).into());
+        }
+        Ok(Self(pattern.to_string()))
+    }
+
+    fn render(&self, item: &Item) -> String {
+        let time = fields::lookup(item, "time")
+            .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+            .map(|time| time.with_timezone(&Utc))
+            .unwrap_or_else(Utc::now);
+        // dates first, the fields may contain a `%`
+        let dated = time.format(&self.0).to_string();
+
+        let index = fields::render(&dated, item);
+        index.to_lowercase()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ElasticsearchConfig {
+    hosts: Vec<String>,
+    index: IndexPattern,
+    // the document of a record, json or flat
+    codec: Codec,
+    // send a bulk request once it holds batch_size records
+    batch_size: usize,
+    // or once its first record waited for flush_interval
+    flush_interval: Duration,
+    // a rejected bulk request or record is retried this many times
+    retries: u32,
+    // first retry delay, doubled on every further retry
+    backoff: Duration,
+    timeout: Duration,
+    // basic authorization header
+    authorization: Option<String>,
+}
+
+impl Default for ElasticsearchConfig {
+    fn default() -> Self {
+        Self {
+            hosts: vec![],
+            index: IndexPattern("harvest-%Y.%m.%d".to_string()),
+            codec: Codec::Json,
+            batch_size: 500,
+            flush_interval: Duration::from_millis(1000),
+            retries: 5,
+            backoff: Duration::from_millis(100),
+            timeout: Duration::from_secs(10),
+            authorization: None,
+        }
+    }
+}
+
+// ElasticsearchOutput writes the records of one channel with the _bulk api
+pub(crate) struct ElasticsearchOutput {
+    cfg: ElasticsearchConfig,
+    agent: ureq::Agent,
+    // index and document of the buffered records
+    batch: Batch<(String, String)>,
+    // the host of the next request, moved on after a connection error
+    host: usize,
+}
+
+impl ElasticsearchOutput {
+    pub fn new(channel: &str) -> Result<Self> {
+        let cfg = Self::parse_uri(channel)?;
+        Ok(Self {
+            agent: ureq::AgentBuilder::new().timeout(cfg.timeout).build(),
+            batch: Batch::new(cfg.batch_size, cfg.flush_interval),
+            host: 0,
+            cfg,
+        })
+    }
+
+    // channel = es:http://10.200.100.200:9200,http://10.200.100.201:9200
+    // options = ?index=harvest-{ns}-%Y.%m.%d&codec=flat&batch_size=500&flush_ms=1000&retries=5&backoff_ms=100&timeout_ms=10000&user=elastic&password=changeme
+    fn parse_uri(channel: &str) -> Result<ElasticsearchConfig> {
+        let uri = match channel.split_once(':') {
+            Some(("es", uri)) | Some(("elasticsearch", uri)) => uri,
+            _ => {
+                return Err(format!("channel `{}` is not an elasticsearch channel", channel).into())
+            }
+        };
+        let (hosts, options) = uri.split_once('?').unwrap_or((uri, ""));
+        let hosts = hosts
+            .split(',')
+            .filter(|host| !host.is_empty())
+            .map(|host| host.trim_end_matches('/').to_string())
+            .collect::<Vec<String>>();
+        if hosts.is_empty() {
+            return Err(format!("elasticsearch channel `{}` has no host", channel).into());
+        }
+        if let Some(host) = hosts
+            .iter()
+            .find(|host| !host.starts_with("http://") && !host.starts_with("https://"))
+        {
+            return Err(format!(
+                "elasticsearch host `{}` must start with http:// or https://",
+                host
+            )
+            .into());
+        }
+
+        let mut cfg = ElasticsearchConfig {
+            hosts,
+            ..Default::default()
+        };
+        let (mut user, mut password) = (None, "");
+        for option in options.split('&').filter(|option| !option.is_empty()) {
+            let (name, value) = option.split_once('=').unwrap_or((option, ""));
+            match name {
+                "index" => cfg.index = IndexPattern::parse(value)?,
+                "codec" => {
+                    cfg.codec = match Codec::parse(value)? {
+                        codec if codec.is_json() => codec,
+                        _ => {
+                            return Err(format!(
+                                "elasticsearch codec `{}` is not json, expect json or flat",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "batch_size" => cfg.batch_size = cmp::max(value.parse::<usize>()?, 1),
+                "flush_ms" => cfg.flush_interval = Duration::from_millis(value.parse::<u64>()?),
+                "retries" => cfg.retries = value.parse::<u32>()?,
+                "backoff_ms" => cfg.backoff = Duration::from_millis(value.parse::<u64>()?),
+                "timeout_ms" => cfg.timeout = Duration::from_millis(value.parse::<u64>()?),
+                "user" => user = Some(value),
+                "password" => password = value,
+                _ => return Err(format!("unknown elasticsearch channel option `{}`", name).into()),
+            }
+        }
+        if let Some(user) = user {
+            cfg.authorization = Some(format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", user, password))
+            ));
+        }
+
+        Ok(cfg)
+    }
+
+    // send the buffered records, the ones still rejected after the retries are failed
+    fn send_buffer(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let mut pending = (0..self.batch.len()).collect::<Vec<usize>>();
+        let mut backoff = self.cfg.backoff;
+        for attempt in 0..=self.cfg.retries {
+            match self.bulk(&pending) {
+                Ok(retry) => pending = retry,
+                Err(e) => eprintln!(
+                    "elasticsearch output bulk of {} records error: {}, attempt {}",
+                    pending.len(),
+                    e,
+                    attempt
+                ),
+            }
+            if pending.is_empty() {
+                break;
+            }
+            if attempt < self.cfg.retries {
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        }
+
+        let failed = pending.len();
+        self.batch.settle(&pending);
+        if failed > 0 {
+            return Err(format!(
+                "elasticsearch output dropped {} records after {} retries",
+                failed, self.cfg.retries
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // send the records of the buffer at indexes, returns the ones to retry
+    fn bulk(&mut self, indexes: &[usize]) -> Result<Vec<usize>> {
+        let mut body = String::new();
+        for i in indexes {
+            let (index, document) = &self.batch.items()[*i];
+            body.push_str(&json!({ "index": { "_index": index } }).to_string());
+            body.push('\n');
+            body.push_str(document);
+            body.push('\n');
+        }
+
+        let url = format!("{}/_bulk", self.cfg.hosts[self.host % self.cfg.hosts.len()]);
+        let mut request = self
+            .agent
+            .post(&url)
+            .set("Content-Type", "application/x-ndjson");
+        if let Some(authorization) = &self.cfg.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        let response = match request.send_string(&body) {
+            Ok(it) => it.into_string()?,
+            // too many requests or an unavailable cluster, the whole request is retried
+            Err(ureq::Error::Status(status, _)) if status == 429 || status >= 500 => {
+                return Err(format!("{} responded {}", url, status).into())
+            }
+            // any other status rejects the request for good
+            Err(ureq::Error::Status(status, response)) => {
+                eprintln!(
+                    "elasticsearch output {} rejected the bulk request with {}: {}",
+                    url,
+                    status,
+                    response.into_string().unwrap_or_default()
+                );
+                return Ok(vec![]);
+            }
+            Err(e) => {
+                self.host += 1;
+                return Err(e.into());
+            }
+        };
+
+        let response = serde_json::from_str::<Value>(&response)?;
+        if response["errors"] != Value::Bool(true) {
+            return Ok(vec![]);
+        }
+        let items = match response["items"].as_array() {
+            Some(it) => it,
+            None => return Err("bulk response has errors but no items".into()),
+        };
+        let mut retry = vec![];
+        for (i, item) in indexes.iter().zip(items.iter()) {
+            let result = match item.as_object().and_then(|item| item.values().next()) {
+                Some(it) => it,
+                None => continue,
+            };
+            let status = result["status"].as_u64().unwrap_or(0);
+            if status == 429 || status >= 500 {
+                retry.push(*i);
+            } else if status >= 300 {
+                // a document the cluster will never take, e.g. a mapping conflict
+                eprintln!(
+                    "elasticsearch output index {} rejected a record with {}: {}",
+                    self.batch.items()[*i].0,
+                    status,
+                    result["error"]
+                );
+            }
+        }
+        Ok(retry)
+    }
+}
+
+impl IOutput for ElasticsearchOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.deliver(channel, Record::new(item, Ack::none()))
+    }
+
+    fn deliver(&mut self, _: &str, record: Record) -> Result<()> {
+        let Record { item, ack } = record;
+        let index = self.cfg.index.render(&item);
+        let document = self.cfg.codec.text(&item);
+        if !self.batch.push((index, document), ack) {
+            return Ok(());
+        }
+        self.send_buffer()
+    }
+
+    // the batches are retried here, records failed after cfg.retries are not written again
+    fn retries(&self) -> u32 {
+        0
+    }
+
+    fn flush(&mut self, _: &str) -> Result<()> {
+        if !self.batch.is_due() {
+            return Ok(());
+        }
+        self.send_buffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ElasticsearchOutput, IndexPattern};
+    use crate::testing::serve;
+    use crate::{Ack, IOutput, Record};
+    use common::Item;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn it_parses_channel() {
+        let cfg = ElasticsearchOutput::parse_uri(
+            "es:http://10.200.100.200:9200,https://10.200.100.201:9200/?index=logs-{ns}-%Y.%m&batch_size=10&flush_ms=50&user=elastic&password=changeme",
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.hosts,
+            vec!["http://10.200.100.200:9200", "https://10.200.100.201:9200"]
+        );
+        assert_eq!(cfg.index, IndexPattern("logs-{ns}-%Y.%m".to_string()));
+        assert_eq!(cfg.batch_size, 10);
+        assert_eq!(cfg.flush_interval, Duration::from_millis(50));
+        assert_eq!(
+            cfg.authorization,
+            Some("Basic ZWxhc3RpYzpjaGFuZ2VtZQ==".to_string())
+        );
+
+        assert!(ElasticsearchOutput::parse_uri("elasticsearch:http://127.0.0.1:9200").is_ok());
+        assert!(ElasticsearchOutput::parse_uri("es:").is_err());
+        assert!(ElasticsearchOutput::parse_uri("es:127.0.0.1:9200").is_err());
+        assert!(ElasticsearchOutput::parse_uri("es:http://127.0.0.1:9200?index=a-{ns").is_err());
+        assert!(ElasticsearchOutput::parse_uri("es:http://127.0.0.1:9200?index=a-%Q").is_err());
+        assert!(ElasticsearchOutput::parse_uri("es:http://127.0.0.1:9200?size=1").is_err());
+        assert!(ElasticsearchOutput::parse_uri("es:http://127.0.0.1:9200?codec=avro").is_err());
+    }
+
+    #[test]
+    fn it_renders_index() {
+        let pattern = IndexPattern::parse("harvest-{ns}-{service}-%Y.%m.%d").unwrap();
+        let item = Item::from(
+            r#"{"custom":{"namespace":"Finance-Dev"},"time":"2021-03-16T23:05:01.461813069-02:00","message":"m"}"#,
+        );
+        assert_eq!(
+            pattern.render(&item),
+            "harvest-finance-dev-unknown-2021.03.17"
+        );
+    }
+
+    #[test]
+    fn it_retries_rejected_records() {
+        let (addr, requests) = serve(vec![
+            (429, "{}".to_string()),
+            (
+                200,
+                r#"{"errors":true,"items":[{"index":{"status":201}},{"index":{"status":429}},{"index":{"status":400,"error":{"type":"mapper_parsing_exception"}}}]}"#.to_string(),
+            ),
+            (200, r#"{"errors":false,"items":[{"index":{"status":201}}]}"#.to_string()),
+        ]);
+        let mut es = ElasticsearchOutput::new(&format!(
+            "es:http://{}?index=logs-{{pod}}&batch_size=3&backoff_ms=1",
+            addr
+        ))
+        .unwrap();
+
+        let acked = Arc::new(Mutex::new(vec![]));
+        for i in 0..3 {
+            let acked = acked.clone();
+            let line = format!(r#"{{"custom":{{"nodeId":"pod-{}"}},"message":"{}"}}"#, i, i);
+            let ack = Ack::new(move |ok| acked.lock().unwrap().push((i, ok)));
+            es.deliver("es", Record::new(Item::from(line.as_str()), ack))
+                .unwrap();
+        }
+
+        let first = requests.recv().unwrap();
+        assert!(first.head.starts_with("POST /_bulk "));
+        assert_eq!(
+            first.header("content-type"),
+            Some("application/x-ndjson".to_string())
+        );
+        assert_eq!(first.body().lines().count(), 6);
+        assert_eq!(
+            first.body().lines().next(),
+            Some(r#"{"index":{"_index":"logs-pod-0"}}"#)
+        );
+        assert_eq!(requests.recv().unwrap().body().lines().count(), 6);
+        // only the record answered with 429 is sent again
+        let third = requests.recv().unwrap();
+        assert_eq!(
+            third.body().lines().next(),
+            Some(r#"{"index":{"_index":"logs-pod-1"}}"#)
+        );
+        assert_eq!(third.body().lines().count(), 2);
+
+        assert_eq!(
+            *acked.lock().unwrap(),
+            vec![(0, true), (1, true), (2, true)]
+        );
+    }
+}
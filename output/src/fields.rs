@@ -0,0 +1,103 @@
+use common::Item;
+use serde_json::Value;
+
+// the dotted path of the envelope field a short name stands for
+pub(crate) fn alias(name: &str) -> &str {
+    match name {
+        "ns" | "namespace" => "custom.namespace",
+        "pod" => "custom.nodeId",
+        "container" => "custom.container",
+        "service" => "custom.serviceName",
+        "node" => "custom.nodeName",
+        path => path,
+    }
+}
+
+// the value of the dotted path, or of its alias, in a json item
+pub(crate) fn lookup(item: &Item, path: &str) -> Option<String> {
+    match item.field(alias(path))? {
+        Value::String(s) => Some(s.clone()),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
+// the lowercased level of a record, its level field or the level of its json message
+pub(crate) fn level(item: &Item) -> Option<String> {
+    let level = lookup(item, "level").or_else(|| {
+        let message = lookup(item, "message")?;
+        let message = serde_json::from_str::<Value>(message.trim()).ok()?;
+        ["level", "severity", "lvl"]
+            .iter()
+            .find_map(|key| message.get(key)?.as_str().map(str::to_string))
+    })?;
+    Some(level.to_lowercase())
+}
+
+// substitute every {field} of template with the value of the field, unknown when missing,
+// a `/` in a value is replaced so that it never adds a path component
+pub(crate) fn render(template: &str, item: &Item) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        rendered.push_str(&rest[..start]);
+        match lookup(item, &rest[start + 1..end]) {
+            Some(value) if !value.is_empty() && value != ".." => {
+                rendered.push_str(&value.replace('/', "_"))
+            }
+            _ => rendered.push_str("unknown"),
+        }
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+// a template is valid when each of its `{` is closed
+pub(crate) fn check_template(template: &str) -> bool {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        match rest[start..].find('}') {
+            Some(end) => rest = &rest[start + end + 1..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_template, lookup, render};
+    use common::Item;
+
+    #[test]
+    fn it_looks_fields_up() {
+        let item = Item::from(
+            r#"{"custom":{"nodeId":"pod-0","namespace":"ns","ips":["127.0.0.1"]},"level":3}"#,
+        );
+        assert_eq!(lookup(&item, "pod"), Some("pod-0".to_string()));
+        assert_eq!(lookup(&item, "ns"), Some("ns".to_string()));
+        assert_eq!(lookup(&item, "level"), Some("3".to_string()));
+        assert_eq!(
+            lookup(&item, "custom.ips"),
+            Some(r#"["127.0.0.1"]"#.to_string())
+        );
+        assert_eq!(lookup(&item, "service"), None);
+        assert_eq!(lookup(&Item::from("raw"), "pod"), None);
+    }
+
+    #[test]
+    fn it_renders_templates() {
+        let item = Item::from(r#"{"custom":{"nodeId":"../pod/0","namespace":"ns"}}"#);
+        assert_eq!(
+            render("/data/{ns}/{pod}-{container}.log", &item),
+            "/data/ns/.._pod_0-unknown.log"
+        );
+        assert!(check_template("{ns}-{pod}"));
+        assert!(!check_template("{ns}-{pod"));
+    }
+}
@@ -0,0 +1,498 @@
+use super::codec::Codec;
+use super::{fields, Ack, IOutput, Item, Record, Result};
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// a file nothing was written to for this long is closed, it is opened again on the next line
+const IDLE_CLOSE: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug, PartialEq)]
+enum Rotate {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotate {
+    // lines of the same period go to the same file
+    fn period(&self, time: DateTime<Utc>) -> String {
+        match self {
+            Rotate::Never => "".to_string(),
+            Rotate::Hourly => time.format("%Y%m%d%H").to_string(),
+            Rotate::Daily => time.format("%Y%m%d").to_string(),
+        }
+    }
+}
+
+// Fsync decides when the written lines are synced to disk, their acks are done then
+#[derive(Clone, Debug, PartialEq)]
+enum Fsync {
+    // after every line
+    Always,
+    // at most once per interval
+    Interval(Duration),
+    // lines are handed to the os on every flush and never synced
+    Never,
+}
+
+#[derive(Clone, Debug)]
+struct FileOutputConfig {
+    // path template, {field} is replaced by the field of the record
+    path: String,
+    // a file is rotated before it grows past max_bytes, 0 disables it
+    max_bytes: u64,
+    rotate: Rotate,
+    // number of rotated files kept per path, 0 keeps all of them
+    retention: usize,
+    gzip: bool,
+    fsync: Fsync,
+    // the line of a record, the record as it is without codec
+    codec: Option<Codec>,
+}
+
+impl Default for FileOutputConfig {
+    fn default() -> Self {
+        Self {
+            path: "".to_string(),
+            max_bytes: 100 * 1024 * 1024,
+            rotate: Rotate::Daily,
+            retention: 7,
+            gzip: false,
+            fsync: Fsync::Interval(Duration::from_secs(1)),
+            codec: None,
+        }
+    }
+}
+
+// path.gz, unlike with_extension it keeps the current extension
+fn gz(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(".gz");
+    PathBuf::from(path)
+}
+
+struct OpenFile {
+    writer: BufWriter<File>,
+    size: u64,
+    period: String,
+    written: Instant,
+}
+
+// FileOutput appends the records of one channel as lines to local files
+pub(crate) struct FileOutput {
+    cfg: FileOutputConfig,
+    files: HashMap<PathBuf, OpenFile>,
+    // acks of the lines written since the last commit
+    pending: Vec<Ack>,
+    synced: Instant,
+}
+
+impl FileOutput {
+    pub fn new(channel: &str) -> Result<Self> {
+        Ok(Self {
+            cfg: Self::parse_uri(channel)?,
+            files: HashMap::new(),
+            pending: vec![],
+            synced: Instant::now(),
+        })
+    }
+
+    // channel = file:/data/harvest/{ns}/{pod}.log?max_bytes=104857600&rotate=daily&retention=7&gzip=true&fsync=interval&fsync_ms=1000&codec=logfmt
+    fn parse_uri(channel: &str) -> Result<FileOutputConfig> {
+        let rest = match channel.strip_prefix("file:") {
+            Some(rest) => rest,
+            None => return Err(format!("channel `{}` is not a file channel", channel).into()),
+        };
+        let (path, options) = rest.split_once('?').unwrap_or((rest, ""));
+        if path.is_empty() || path.ends_with('/') {
+            return Err(format!("file channel `{}` has no file path", channel).into());
+        }
+        if !fields::check_template(path) {
+            return Err(format!("file path template `{}` misses a `}}`", path).into());
+        }
+        let mut cfg = FileOutputConfig {
+            path: path.to_string(),
+            ..Default::default()
+        };
+        let mut fsync_interval = Duration::from_secs(1);
+        let mut fsync = "interval";
+        for option in options.split('&').filter(|option| !option.is_empty()) {
+            let (name, value) = option.split_once('=').unwrap_or((option, ""));
+            match name {
+                "max_bytes" => cfg.max_bytes = value.parse::<u64>()?,
+                "rotate" => {
+                    cfg.rotate = match value {
+                        "never" => Rotate::Never,
+                        "hourly" => Rotate::Hourly,
+                        "daily" => Rotate::Daily,
+                        _ => {
+                            return Err(format!(
+                                "file rotate `{}` is not supported, expect never, hourly or daily",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "retention" => cfg.retention = value.parse::<usize>()?,
+                "gzip" => cfg.gzip = value.parse::<bool>()?,
+                "fsync" => fsync = value,
+                "fsync_ms" => fsync_interval = Duration::from_millis(value.parse::<u64>()?),
+                "codec" => match Codec::parse(value)? {
+                    codec if codec.is_text() => cfg.codec = Some(codec),
+                    _ => {
+                        return Err(format!(
+                            "file codec `{}` is not a line, expect raw, json, flat or logfmt",
+                            value
+                        )
+                        .into())
+                    }
+                },
+                _ => return Err(format!("unknown file channel option `{}`", name).into()),
+            }
+        }
+        cfg.fsync = match fsync {
+            "always" => Fsync::Always,
+            "interval" => Fsync::Interval(fsync_interval),
+            "never" => Fsync::Never,
+            _ => {
+                return Err(format!(
+                    "file fsync `{}` is not supported, expect always, interval or never",
+                    fsync
+                )
+                .into())
+            }
+        };
+        Ok(cfg)
+    }
+
+    fn must_rotate(&self, size: u64, period: &str, line: u64) -> bool {
+        size > 0
+            && ((self.cfg.max_bytes > 0 && size + line > self.cfg.max_bytes)
+                || self.cfg.rotate.period(Utc::now()) != period)
+    }
+
+    // open path for appending, a file left over from an earlier run is rotated first
+    // when it is too large or of an earlier period
+    fn open(&mut self, path: &Path, line: u64) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if let Ok(meta) = fs::metadata(path) {
+            let period = self.cfg.rotate.period(meta.modified()?.into());
+            if self.must_rotate(meta.len(), &period, line) {
+                self.archive(path)?;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        self.files.insert(
+            path.to_path_buf(),
+            OpenFile {
+                writer: BufWriter::new(file),
+                size,
+                period: self.cfg.rotate.period(Utc::now()),
+                written: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn close(&mut self, path: &Path) -> Result<()> {
+        if let Some(mut file) = self.files.remove(path) {
+            file.writer.flush()?;
+            if self.cfg.fsync != Fsync::Never {
+                file.writer.get_ref().sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    // the rotated files of path by their stamp, the same stamp with and without the .gz
+    fn rotated(path: &Path) -> Result<Vec<(String, PathBuf)>> {
+        let (dir, name) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir, format!("{}.", name.to_string_lossy())),
+            _ => return Ok(vec![]),
+        };
+        Ok(fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                let stamp = file_name.strip_prefix(&name)?;
+                if !stamp.starts_with(|c: char| c.is_ascii_digit()) {
+                    return None;
+                }
+                Some((stamp.trim_end_matches(".gz").to_string(), entry.path()))
+            })
+            .collect())
+    }
+
+    // move path aside as path.<utc time>, compress it when gzip is set
+    // and remove the rotated files beyond the retention.
+    // this runs on the worker of the channel, the channel waits while a file is compressed.
+    fn archive(&self, path: &Path) -> Result<()> {
+        let rotated = Self::rotated(path)?;
+        let stamp = Utc::now().format("%Y%m%d-%H%M%S").to_string();
+        // files rotated within the same second are numbered after the last one still kept
+        let seq = rotated
+            .iter()
+            .filter_map(|(key, _)| match key.strip_prefix(&stamp)? {
+                "" => Some(0),
+                seq => seq.strip_prefix('-')?.parse::<u32>().ok(),
+            })
+            .max();
+        let rotated = PathBuf::from(match seq {
+            Some(seq) => format!("{}.{}-{:03}", path.display(), stamp, seq + 1),
+            None => format!("{}.{}", path.display(), stamp),
+        });
+        fs::rename(path, &rotated)?;
+
+        if self.cfg.gzip {
+            let compressed = gz(&rotated);
+            let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+            io::copy(&mut File::open(&rotated)?, &mut encoder)?;
+            encoder.finish()?.sync_all()?;
+            fs::remove_file(&rotated)?;
+        }
+        self.prune(path)
+    }
+
+    fn prune(&self, path: &Path) -> Result<()> {
+        if self.cfg.retention == 0 {
+            return Ok(());
+        }
+        let mut rotated = Self::rotated(path)?;
+        if rotated.len() <= self.cfg.retention {
+            return Ok(());
+        }
+        rotated.sort();
+        let expired = rotated.len() - self.cfg.retention;
+        for (_, path) in rotated.into_iter().take(expired) {
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!("file output remove {} error: {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    // hand the written lines to the os, sync them when sync is set,
+    // and done the acks of the lines written so far
+    fn commit(&mut self, sync: bool) -> Result<()> {
+        for file in self.files.values_mut() {
+            let synced = file.writer.flush().and_then(|_| match sync {
+                true => file.writer.get_ref().sync_data(),
+                false => Ok(()),
+            });
+            if let Err(e) = synced {
+                self.pending.clear();
+                return Err(e.into());
+            }
+        }
+        if sync {
+            self.synced = Instant::now();
+        }
+        for ack in self.pending.drain(..) {
+            ack.done();
+        }
+        Ok(())
+    }
+
+    fn close_idle(&mut self) {
+        if !self.pending.is_empty() {
+            return;
+        }
+        let idle = self
+            .files
+            .iter()
+            .filter(|(_, file)| file.written.elapsed() >= IDLE_CLOSE)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        for path in idle {
+            if let Err(e) = self.close(&path) {
+                eprintln!("file output close {} error: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn append(&mut self, item: &Item) -> Result<()> {
+        let path = PathBuf::from(fields::render(&self.cfg.path, item));
+        let mut line = match &self.cfg.codec {
+            Some(codec) => codec.text(item),
+            None => item.string(),
+        };
+        line.push('\n');
+        let len = line.len() as u64;
+
+        let rotate = match self.files.get(&path) {
+            Some(file) => self.must_rotate(file.size, &file.period, len),
+            None => false,
+        };
+        if rotate {
+            self.close(&path)?;
+            self.archive(&path)?;
+        }
+        if !self.files.contains_key(&path) {
+            self.open(&path, len)?;
+        }
+        if let Some(file) = self.files.get_mut(&path) {
+            file.writer.write_all(line.as_bytes())?;
+            file.size += len;
+            file.written = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+impl IOutput for FileOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.deliver(channel, Record::new(item, Ack::none()))
+    }
+
+    fn deliver(&mut self, _: &str, record: Record) -> Result<()> {
+        let Record { item, ack } = record;
+        // a failed line drops its ack, the line is delivered again
+        self.append(&item)?;
+        self.pending.push(ack);
+        match self.cfg.fsync {
+            Fsync::Always => self.commit(true),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self, _: &str) -> Result<()> {
+        let committed = match self.cfg.fsync {
+            Fsync::Never => self.commit(false),
+            Fsync::Interval(interval) if self.synced.elapsed() >= interval => self.commit(true),
+            _ => Ok(()),
+        };
+        self.close_idle();
+        committed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Codec, FileOutput, Fsync, Rotate};
+    use crate::{Ack, IOutput, Record};
+    use common::Item;
+    use flate2::read::GzDecoder;
+    use std::fs;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("harvest-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn line(pod: &str, message: &str) -> Item {
+        Item::from(
+            format!(
+                r#"{{"custom":{{"namespace":"ns","nodeId":"{}"}},"message":"{}"}}"#,
+                pod, message
+            )
+            .as_str(),
+        )
+    }
+
+    #[test]
+    fn it_parses_channel() {
+        let cfg = FileOutput::parse_uri(
+            "file:/data/harvest/{ns}/{pod}.log?max_bytes=1024&rotate=hourly&retention=3&gzip=true&fsync=interval&fsync_ms=200",
+        )
+        .unwrap();
+        assert_eq!(cfg.path, "/data/harvest/{ns}/{pod}.log");
+        assert_eq!(cfg.max_bytes, 1024);
+        assert_eq!(cfg.rotate, Rotate::Hourly);
+        assert_eq!(cfg.retention, 3);
+        assert!(cfg.gzip);
+        assert_eq!(cfg.fsync, Fsync::Interval(Duration::from_millis(200)));
+        assert_eq!(cfg.codec, None);
+        let cfg = FileOutput::parse_uri("file:/data/a.log?codec=raw").unwrap();
+        assert_eq!(cfg.codec, Some(Codec::Raw));
+
+        assert!(FileOutput::parse_uri("file:/data/a.log?codec=protobuf").is_err());
+        assert!(FileOutput::parse_uri("file:/data/harvest/").is_err());
+        assert!(FileOutput::parse_uri("file:/data/{pod.log").is_err());
+        assert!(FileOutput::parse_uri("file:/data/a.log?rotate=weekly").is_err());
+        assert!(FileOutput::parse_uri("file:/data/a.log?fsync=sometimes").is_err());
+        assert!(FileOutput::parse_uri("file:/data/a.log?size=1").is_err());
+    }
+
+    #[test]
+    fn it_acks_written_lines_on_flush() {
+        let dir = temp_dir("file-output");
+        let mut output = FileOutput::new(&format!(
+            "file:{}/{{ns}}/{{pod}}.log?fsync=never",
+            dir.display()
+        ))
+        .unwrap();
+
+        let acked = Arc::new(Mutex::new(vec![]));
+        for (pod, message) in &[("a", "1"), ("b", "2"), ("a", "3")] {
+            let acked = acked.clone();
+            let ack = Ack::new(move |ok| acked.lock().unwrap().push(ok));
+            output
+                .deliver("file", Record::new(line(pod, message), ack))
+                .unwrap();
+        }
+        assert!(acked.lock().unwrap().is_empty());
+        output.flush("file").unwrap();
+        assert_eq!(*acked.lock().unwrap(), vec![true, true, true]);
+
+        let a = fs::read_to_string(dir.join("ns/a.log")).unwrap();
+        assert_eq!(a.lines().count(), 2);
+        assert!(a.lines().last().unwrap().contains(r#""message":"3""#));
+        let b = fs::read_to_string(dir.join("ns/b.log")).unwrap();
+        assert_eq!(b.lines().count(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn it_rotates_by_size_and_keeps_retention() {
+        let dir = temp_dir("file-rotate");
+        let mut output = FileOutput::new(&format!(
+            "file:{}/{{pod}}.log?max_bytes=100&retention=2&gzip=true&fsync=always",
+            dir.display()
+        ))
+        .unwrap();
+        // every line is about 60 bytes, each one goes to a file of its own
+        for i in 0..5 {
+            output.write("file", line("a", &i.to_string())).unwrap();
+        }
+
+        let mut names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "a.log");
+        assert!(names[1..].iter().all(|name| name.ends_with(".gz")));
+
+        let current = fs::read_to_string(dir.join("a.log")).unwrap();
+        assert!(current.contains(r#""message":"4""#));
+        // the newest rotated file holds the line before
+        let mut previous = String::new();
+        let newest = names
+            .iter()
+            .skip(1)
+            .max_by_key(|name| name.trim_end_matches(".gz").to_string())
+            .unwrap();
+        GzDecoder::new(fs::File::open(dir.join(newest)).unwrap())
+            .read_to_string(&mut previous)
+            .unwrap();
+        assert!(previous.contains(r#""message":"3""#));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,340 @@
+use super::codec::Codec;
+use super::{Ack, Batch, IOutput, Item, Record, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use std::io::Write;
+use std::time::Duration;
+use std::{cmp, thread};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq)]
+enum BodyFormat {
+    // [{...},{...}]
+    Json,
+    // one record per line
+    Ndjson,
+}
+
+// Outcome is how a request is handled by its response status
+#[derive(Debug, PartialEq)]
+pub(crate) enum Outcome {
+    Sent,
+    // the endpoint is overloaded or down, the batch is sent again
+    Retry,
+    // the endpoint will never take the batch
+    Rejected,
+}
+
+impl Outcome {
+    pub(crate) fn of(status: u16) -> Self {
+        match status {
+            200..=299 => Outcome::Sent,
+            408 | 429 | 500..=599 => Outcome::Retry,
+            _ => Outcome::Rejected,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct HttpOutputConfig {
+    url: String,
+    format: BodyFormat,
+    // the json document of a record, json or flat
+    codec: Codec,
+    headers: Vec<(String, String)>,
+    gzip: bool,
+    // post a batch once it holds batch_size records
+    batch_size: usize,
+    // or once its first record waited for flush_interval
+    flush_interval: Duration,
+    timeout: Duration,
+    // a batch is retried this many times before it is dropped
+    retries: u32,
+    // first retry delay, doubled on every further retry
+    backoff: Duration,
+}
+
+impl Default for HttpOutputConfig {
+    fn default() -> Self {
+        Self {
+            url: "".to_string(),
+            format: BodyFormat::Json,
+            codec: Codec::Json,
+            headers: vec![],
+            gzip: false,
+            batch_size: 100,
+            flush_interval: Duration::from_millis(1000),
+            timeout: Duration::from_secs(10),
+            retries: 5,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+// HttpOutput posts the records of one channel in batches to an http endpoint
+pub(crate) struct HttpOutput {
+    cfg: HttpOutputConfig,
+    agent: ureq::Agent,
+    // json encoded records
+    batch: Batch<String>,
+}
+
+impl HttpOutput {
+    pub fn new(channel: &str) -> Result<Self> {
+        let cfg = Self::parse_uri(channel)?;
+        Ok(Self {
+            agent: ureq::AgentBuilder::new().timeout(cfg.timeout).build(),
+            batch: Batch::new(cfg.batch_size, cfg.flush_interval),
+            cfg,
+        })
+    }
+
+    // channel = https://collector:8080/ingest?tenant=a, the options follow the `#`
+    // so that the query of the endpoint is kept as it is
+    // options = #format=ndjson&codec=flat&gzip=true&header=Authorization:Bearer xyz&batch_size=100&flush_ms=1000&timeout_ms=10000&retries=5&backoff_ms=100
+    fn parse_uri(channel: &str) -> Result<HttpOutputConfig> {
+        if !channel.starts_with("http://") && !channel.starts_with("https://") {
+            return Err(format!("channel `{}` is not an http channel", channel).into());
+        }
+        let (url, options) = channel.split_once('#').unwrap_or((channel, ""));
+        let mut cfg = HttpOutputConfig {
+            url: url.to_string(),
+            ..Default::default()
+        };
+        for option in options.split('&').filter(|option| !option.is_empty()) {
+            let (name, value) = option.split_once('=').unwrap_or((option, ""));
+            match name {
+                "format" => {
+                    cfg.format = match value {
+                        "json" => BodyFormat::Json,
+                        "ndjson" => BodyFormat::Ndjson,
+                        _ => {
+                            return Err(format!(
+                                "http format `{}` is not supported, expect json or ndjson",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "codec" => {
+                    cfg.codec = match Codec::parse(value)? {
+                        codec if codec.is_json() => codec,
+                        _ => {
+                            return Err(format!(
+                                "http codec `{}` is not json, expect json or flat",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "header" => match value.split_once(':') {
+                    Some((key, value)) if !key.trim().is_empty() => cfg
+                        .headers
+                        .push((key.trim().to_string(), value.trim().to_string())),
+                    _ => return Err(format!("http header `{}` expects name:value", value).into()),
+                },
+                "gzip" => cfg.gzip = value.parse::<bool>()?,
+                "batch_size" => cfg.batch_size = cmp::max(value.parse::<usize>()?, 1),
+                "flush_ms" => cfg.flush_interval = Duration::from_millis(value.parse::<u64>()?),
+                "timeout_ms" => cfg.timeout = Duration::from_millis(value.parse::<u64>()?),
+                "retries" => cfg.retries = value.parse::<u32>()?,
+                "backoff_ms" => cfg.backoff = Duration::from_millis(value.parse::<u64>()?),
+                _ => return Err(format!("unknown http channel option `{}`", name).into()),
+            }
+        }
+        Ok(cfg)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let records = self.batch.items();
+        let body = match self.cfg.format {
+            BodyFormat::Json => format!("[{}]", records.join(",")),
+            BodyFormat::Ndjson => records.iter().map(|r| format!("{}\n", r)).collect(),
+        };
+        if !self.cfg.gzip {
+            return Ok(body.into_bytes());
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body.as_bytes())?;
+        Ok(encoder.finish()?)
+    }
+
+    fn post(&self, body: &[u8]) -> Result<Outcome> {
+        let content_type = match self.cfg.format {
+            BodyFormat::Json => "application/json",
+            BodyFormat::Ndjson => "application/x-ndjson",
+        };
+        let mut request = self
+            .agent
+            .post(&self.cfg.url)
+            .set("Content-Type", content_type);
+        if self.cfg.gzip {
+            request = request.set("Content-Encoding", "gzip");
+        }
+        for (key, value) in self.cfg.headers.iter() {
+            request = request.set(key, value);
+        }
+        match request.send_bytes(body) {
+            Ok(response) => Ok(Outcome::of(response.status())),
+            Err(ureq::Error::Status(status, response)) => {
+                let outcome = Outcome::of(status);
+                if outcome == Outcome::Rejected {
+                    eprintln!(
+                        "http output {} rejected a batch with {}: {}",
+                        self.cfg.url,
+                        status,
+                        response.into_string().unwrap_or_default()
+                    );
+                }
+                Ok(outcome)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // post the batch, retrying with exponential backoff until the retry budget is spent,
+    // a rejected batch is logged and skipped as retrying it can not succeed
+    fn send_buffer(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let body = match self.encode() {
+            Ok(it) => it,
+            Err(e) => {
+                self.batch.fail();
+                return Err(e);
+            }
+        };
+        let mut backoff = self.cfg.backoff;
+        for attempt in 0..=self.cfg.retries {
+            match self.post(&body) {
+                Ok(Outcome::Sent) | Ok(Outcome::Rejected) => {
+                    self.batch.settle(&[]);
+                    return Ok(());
+                }
+                Ok(Outcome::Retry) => eprintln!(
+                    "http output {} asked to retry a batch, attempt {}",
+                    self.cfg.url, attempt
+                ),
+                Err(e) => eprintln!(
+                    "http output {} post error: {}, attempt {}",
+                    self.cfg.url, e, attempt
+                ),
+            }
+            if attempt < self.cfg.retries {
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        }
+
+        let dropped = self.batch.len();
+        self.batch.fail();
+        Err(format!(
+            "http output {} dropped {} records after {} retries",
+            self.cfg.url, dropped, self.cfg.retries
+        )
+        .into())
+    }
+}
+
+impl IOutput for HttpOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.deliver(channel, Record::new(item, Ack::none()))
+    }
+
+    fn deliver(&mut self, _: &str, record: Record) -> Result<()> {
+        let Record { item, ack } = record;
+        if !self.batch.push(self.cfg.codec.text(&item), ack) {
+            return Ok(());
+        }
+        self.send_buffer()
+    }
+
+    // the batches are retried here, records failed after cfg.retries are not written again
+    fn retries(&self) -> u32 {
+        0
+    }
+
+    fn flush(&mut self, _: &str) -> Result<()> {
+        if !self.batch.is_due() {
+            return Ok(());
+        }
+        self.send_buffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BodyFormat, HttpOutput, Outcome};
+    use crate::testing::serve;
+    use crate::{Ack, IOutput, Record};
+    use common::Item;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn it_parses_channel() {
+        let cfg = HttpOutput::parse_uri(
+            "https://collector:8080/ingest?tenant=a#format=ndjson&gzip=true&header=Authorization: Bearer xyz&batch_size=10",
+        )
+        .unwrap();
+        assert_eq!(cfg.url, "https://collector:8080/ingest?tenant=a");
+        assert_eq!(cfg.format, BodyFormat::Ndjson);
+        assert!(cfg.gzip);
+        assert_eq!(
+            cfg.headers,
+            vec![("Authorization".to_string(), "Bearer xyz".to_string())]
+        );
+        assert_eq!(cfg.batch_size, 10);
+
+        assert!(HttpOutput::parse_uri("collector:8080").is_err());
+        assert!(HttpOutput::parse_uri("http://collector#format=xml").is_err());
+        assert!(HttpOutput::parse_uri("http://collector#header=token").is_err());
+        assert!(HttpOutput::parse_uri("http://collector#size=1").is_err());
+        assert!(HttpOutput::parse_uri("http://collector#codec=logfmt").is_err());
+    }
+
+    #[test]
+    fn it_classifies_status() {
+        assert_eq!(Outcome::of(204), Outcome::Sent);
+        assert_eq!(Outcome::of(429), Outcome::Retry);
+        assert_eq!(Outcome::of(503), Outcome::Retry);
+        assert_eq!(Outcome::of(400), Outcome::Rejected);
+    }
+
+    #[test]
+    fn it_posts_gzipped_batches() {
+        let (addr, requests) = serve(vec![(503, "".to_string()), (200, "".to_string())]);
+        let mut output = HttpOutput::new(&format!(
+            "http://{}/ingest#gzip=true&header=X-Token:abc&batch_size=2&backoff_ms=1",
+            addr
+        ))
+        .unwrap();
+
+        let acked = Arc::new(Mutex::new(vec![]));
+        for line in &[r#"{"message":"a"}"#, "b"] {
+            let acked = acked.clone();
+            let ack = Ack::new(move |ok| acked.lock().unwrap().push(ok));
+            output
+                .deliver("http", Record::new(Item::from(*line), ack))
+                .unwrap();
+        }
+
+        requests.recv().unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.head.starts_with("POST /ingest "));
+        assert_eq!(request.header("x-token"), Some("abc".to_string()));
+        assert_eq!(request.header("content-encoding"), Some("gzip".to_string()));
+        let mut body = String::new();
+        GzDecoder::new(request.body.as_slice())
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, r#"[{"message":"a"},{"message":"b"}]"#);
+        assert_eq!(*acked.lock().unwrap(), vec![true, true]);
+    }
+}
@@ -1,145 +1,365 @@
-use super::{IOutput, Item, Result};
-use async_std::task;
-use kafka::producer::{Producer, Record, RequiredAcks};
-use ringbuf::{Consumer as RConsumer, Producer as RProducer, RingBuffer};
+use super::codec::Codec;
+use super::{fields, Ack, Batch, IOutput, Item, Record, Result};
+use common::metrics::{self, Metric};
+use kafka::producer::{Compression, Producer, Record as KafkaRecord, RequiredAcks};
 
-use std::time::Instant;
-use std::{collections::HashMap, thread, time::Duration};
+use std::{cmp, thread, time::Duration};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// KafkaKey selects the partition key of a record from its json fields
+#[derive(Clone, Debug, PartialEq)]
+enum KafkaKey {
+    // no key, records are spread over the partitions
+    None,
+    // dotted path of the field holding the key
+    Field(String),
+}
+
+impl KafkaKey {
+    fn parse(key: &str) -> Self {
+        match key {
+            "" | "none" => KafkaKey::None,
+            field => KafkaKey::Field(fields::alias(field).to_string()),
+        }
+    }
+
+    // an empty key is sent without key by the kafka producer
+    fn of(&self, item: &Item) -> String {
+        match self {
+            KafkaKey::None => "".to_string(),
+            KafkaKey::Field(field) => fields::lookup(item, field).unwrap_or_default(),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 struct KafkaOutputConfig {
     broker: Vec<String>,
     topic: String,
+    // send a batch once it holds batch_size records
+    batch_size: usize,
+    // or once its first record waited for linger
+    linger: Duration,
+    compression: Compression,
+    // a failed batch is retried this many times before it is dropped
+    retries: u32,
+    // first retry delay, doubled on every further retry
+    backoff: Duration,
+    key: KafkaKey,
+    // the value of a record, the record as it is without codec
+    codec: Option<Codec>,
+}
+
+impl Default for KafkaOutputConfig {
+    fn default() -> Self {
+        Self {
+            broker: vec![],
+            topic: "".to_string(),
+            batch_size: 100,
+            linger: Duration::from_millis(1000),
+            compression: Compression::NONE,
+            retries: 5,
+            backoff: Duration::from_millis(100),
+            key: KafkaKey::parse("pod"),
+            codec: None,
+        }
+    }
 }
 
+// KafkaOuput buffers the records of one channel and sends them in batches,
+// a batch is sent by write once full or by flush once it lingered long enough.
 pub(crate) struct KafkaOuput {
-    channels: HashMap<String, RProducer<Item>>,
+    cfg: KafkaOutputConfig,
+    // created on the first batch so unreachable brokers do not fail the registration
+    producer: Option<Producer>,
+    // key and value of the buffered records
+    batch: Batch<(String, Vec<u8>)>,
+    sent: Metric,
+    failed: Metric,
 }
 
 impl KafkaOuput {
-    pub fn new() -> KafkaOuput {
-        Self {
-            channels: HashMap::new(),
-        }
+    pub fn new(channel: &str) -> Result<KafkaOuput> {
+        let cfg = Self::parse_uri_to_producer(channel)?;
+        let id = crate::channel_id(channel);
+        let labels = [("output", id.as_str())];
+        Ok(Self {
+            batch: Batch::new(cfg.batch_size, cfg.linger),
+            cfg,
+            producer: None,
+            sent: metrics::counter(
+                "harvest_kafka_sent_records_total",
+                "records acknowledged by the kafka brokers",
+                &labels,
+            ),
+            failed: metrics::counter(
+                "harvest_kafka_failed_batches_total",
+                "kafka batches dropped after their retries",
+                &labels,
+            ),
+        })
     }
 
-    // channel =  kafka:topic@10.200.100.200:9092,10.200.100.201:9092
-    fn parse_uri_to_producer(&self, channel: &str) -> KafkaOutputConfig {
-        let type_topic_ips = channel.split("@").collect::<Vec<&str>>();
-        let broker = type_topic_ips[1]
-            .split(",")
-            .map(|k| k.to_string())
-            .collect::<Vec<String>>();
-        let topic = type_topic_ips[0].split(":").collect::<Vec<&str>>()[1];
-        KafkaOutputConfig {
-            broker: broker,
+    // channel = kafka:topic@10.200.100.200:9092,10.200.100.201:9092
+    // options = ?batch_size=100&linger_ms=1000&compression=gzip&retries=5&backoff_ms=100&key=pod&codec=avro&schema_id=1
+    fn parse_uri_to_producer(channel: &str) -> Result<KafkaOutputConfig> {
+        let (uri, options) = channel.split_once('?').unwrap_or((channel, ""));
+        let (type_topic, ips) = match uri.split_once('@') {
+            Some((type_topic, ips)) if !ips.is_empty() => (type_topic, ips),
+            _ => return Err(format!("kafka channel `{}` has no broker", channel).into()),
+        };
+        let topic = match type_topic.split_once(':') {
+            Some((_, topic)) if !topic.is_empty() => topic,
+            _ => return Err(format!("kafka channel `{}` has no topic", channel).into()),
+        };
+
+        let mut cfg = KafkaOutputConfig {
+            broker: ips
+                .split(',')
+                .map(|k| k.to_string())
+                .collect::<Vec<String>>(),
             topic: topic.to_string(),
+            ..Default::default()
+        };
+
+        let mut schema_id = None;
+        for option in options.split('&').filter(|option| !option.is_empty()) {
+            let (name, value) = option.split_once('=').unwrap_or((option, ""));
+            match name {
+                "batch_size" => cfg.batch_size = cmp::max(value.parse::<usize>()?, 1),
+                "linger_ms" => cfg.linger = Duration::from_millis(value.parse::<u64>()?),
+                "retries" => cfg.retries = value.parse::<u32>()?,
+                "backoff_ms" => cfg.backoff = Duration::from_millis(value.parse::<u64>()?),
+                "key" => cfg.key = KafkaKey::parse(value),
+                "codec" => cfg.codec = Some(Codec::parse(value)?),
+                "schema_id" => schema_id = Some(value.parse::<u32>()?),
+                "compression" => {
+                    cfg.compression = match value {
+                        "none" => Compression::NONE,
+                        "gzip" => Compression::GZIP,
+                        "snappy" => Compression::SNAPPY,
+                        // the kafka client compresses the batches itself and has no lz4 codec
+                        "lz4" => {
+                            return Err(
+                                "kafka compression `lz4` is not supported by the kafka client, expect none, gzip or snappy"
+                                    .into(),
+                            )
+                        }
+                        _ => {
+                            return Err(format!(
+                            "kafka compression `{}` is not supported, expect none, gzip or snappy",
+                            value
+                        )
+                            .into())
+                        }
+                    }
+                }
+                _ => return Err(format!("unknown kafka channel option `{}`", name).into()),
+            }
+        }
+        if let Some(id) = schema_id {
+            cfg.codec = match cfg.codec.take() {
+                Some(codec) => Some(codec.with_schema_id(id)?),
+                None => return Err("kafka schema_id expects codec=avro".into()),
+            };
         }
+
+        Ok(cfg)
     }
 
-    fn write_in(&mut self, channel: &str, item: &Item) {
-        let prod = self.channels.get_mut(channel).unwrap();
-        loop {
-            if prod.is_full() {
-                thread::sleep(Duration::from_millis(1));
-                continue;
+    // send the buffered records, they are dropped and their acks failed when the batch can not be sent
+    fn send_buffer(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        if self.producer.is_none() {
+            match Self::new_producer(&self.cfg) {
+                Ok(kp) => self.producer = Some(kp),
+                Err(e) => {
+                    self.failed.inc();
+                    self.batch.fail();
+                    return Err(e);
+                }
             }
-            prod.push(item.clone()).unwrap();
-            return;
         }
+        let sent = match self.producer.as_mut() {
+            Some(kp) => Self::send_batch(&self.cfg, kp, self.batch.items()),
+            None => false,
+        };
+        if !sent {
+            self.failed.inc();
+            self.batch.fail();
+            return Err(format!("kafka output topic {} batch dropped", self.cfg.topic).into());
+        }
+        self.sent.add(self.batch.len() as i64);
+        self.batch.settle(&[]);
+        Ok(())
     }
 
-    async fn write_out(topic: &str, cons: &mut RConsumer<Item>, kp: &mut Producer) {
-        let count = 5;
-        let mut index = 0;
-        let mut now = Instant::now();
-        let mut write_buffer = Vec::with_capacity(count);
-        loop {
-            if cons.is_empty() {
-                thread::sleep(Duration::from_millis(1));
-                continue;
-            }
-            let content = cons.pop().unwrap().string();
-            write_buffer.push(Record::from_key_value(
-                topic,
-                format!("{:?}", index),
-                content,
-            ));
-            index += 1;
-
-            if index >= write_buffer.capacity() || now.elapsed().as_secs() > 1 {
-                match kp.send_all(&write_buffer) {
-                    Ok(_) => {
-                        index = 0;
-                        now = Instant::now();
-                        write_buffer.clear();
-                    }
-                    Err(e) => {
-                        eprintln!("{:?}", e);
-                        continue;
+    // send the batch, retrying with exponential backoff until the retry budget is spent
+    fn send_batch(cfg: &KafkaOutputConfig, kp: &mut Producer, batch: &[(String, Vec<u8>)]) -> bool {
+        if batch.is_empty() {
+            return true;
+        }
+        let records = batch
+            .iter()
+            .map(|(key, value)| {
+                KafkaRecord::from_key_value(&cfg.topic, key.as_str(), value.as_slice())
+            })
+            .collect::<Vec<KafkaRecord<&str, &[u8]>>>();
+
+        let mut backoff = cfg.backoff;
+        for attempt in 0..=cfg.retries {
+            match kp.send_all(&records) {
+                Ok(confirms) => {
+                    let failed = confirms
+                        .iter()
+                        .flat_map(|confirm| confirm.partition_confirms.iter())
+                        .filter(|partition| partition.offset.is_err())
+                        .count();
+                    if failed == 0 {
+                        return true;
                     }
+                    eprintln!(
+                        "kafka output topic {} batch failed on {} partitions, attempt {}",
+                        cfg.topic, failed, attempt
+                    );
                 }
+                Err(e) => eprintln!(
+                    "kafka output topic {} batch error: {:?}, attempt {}",
+                    cfg.topic, e, attempt
+                ),
+            }
+            if attempt < cfg.retries {
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
             }
         }
+
+        eprintln!(
+            "kafka output topic {} dropped {} records after {} retries",
+            cfg.topic,
+            batch.len(),
+            cfg.retries
+        );
+        false
     }
 
-    fn not_exist_create(&mut self, channel: &str) -> Result<()> {
-        let cfg = self.parse_uri_to_producer(channel);
-        let mut kp = match Producer::from_hosts(cfg.broker)
+    fn new_producer(cfg: &KafkaOutputConfig) -> Result<Producer> {
+        match Producer::from_hosts(cfg.broker.clone())
             .with_ack_timeout(Duration::from_secs(1))
             .with_required_acks(RequiredAcks::One)
+            .with_compression(cfg.compression)
             .create()
         {
-            Ok(it) => it,
-            Err(e) => return Err(Box::new(e)),
+            Ok(it) => Ok(it),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+}
+
+impl IOutput for KafkaOuput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.deliver(channel, Record::new(item, Ack::none()))
+    }
+
+    fn deliver(&mut self, _: &str, record: Record) -> Result<()> {
+        let Record { item, ack } = record;
+        let value = match &self.cfg.codec {
+            Some(codec) => codec.encode(&item),
+            None => item.string().into_bytes(),
         };
+        if !self.batch.push((self.cfg.key.of(&item), value), ack) {
+            return Ok(());
+        }
+        self.send_buffer()
+    }
+
+    // the batches are retried here, records failed after cfg.retries are not written again
+    fn retries(&self) -> u32 {
+        0
+    }
 
-        let ring_buff = RingBuffer::new(10240);
-        let (p, mut c) = ring_buff.split();
+    fn flush(&mut self, _: &str) -> Result<()> {
+        if !self.batch.is_due() {
+            return Ok(());
+        }
+        self.send_buffer()
+    }
+}
 
-        let topic = cfg.topic.clone();
+#[cfg(test)]
+mod tests {
+    use super::{Codec, KafkaKey, KafkaOuput};
+    use crate::IOutput;
+    use common::Item;
+    use kafka::producer::Compression;
+    use std::time::Duration;
 
-        task::spawn(async move {
-            Self::write_out(&topic, &mut c, &mut kp).await;
-        });
+    #[test]
+    fn it_parses_channel() {
+        let cfg =
+            KafkaOuput::parse_uri_to_producer("kafka:test@10.200.100.200:9092,10.200.100.201:9092")
+                .unwrap();
+        assert_eq!(cfg.topic, "test");
+        assert_eq!(
+            cfg.broker,
+            vec!["10.200.100.200:9092", "10.200.100.201:9092"]
+        );
+        assert_eq!(cfg.batch_size, 100);
 
-        self.channels.insert(channel.to_string(), p);
+        let cfg = KafkaOuput::parse_uri_to_producer(
+                "kafka:test@10.200.100.200:9092?batch_size=500&linger_ms=50&compression=snappy&retries=3&backoff_ms=10&key=container",
+            )
+            .unwrap();
+        assert_eq!(cfg.broker, vec!["10.200.100.200:9092"]);
+        assert_eq!(cfg.batch_size, 500);
+        assert_eq!(cfg.linger, Duration::from_millis(50));
+        assert!(matches!(cfg.compression, Compression::SNAPPY));
+        assert_eq!(cfg.retries, 3);
+        assert_eq!(cfg.backoff, Duration::from_millis(10));
+        assert_eq!(cfg.key, KafkaKey::Field("custom.container".to_string()));
+        assert_eq!(cfg.codec, None);
 
-        Ok(())
+        let cfg = KafkaOuput::parse_uri_to_producer(
+            "kafka:test@10.200.100.200:9092?codec=avro&schema_id=3",
+        )
+        .unwrap();
+        assert_eq!(cfg.codec, Some(Codec::Avro(Some(3))));
     }
 
-    fn write_to_channel_queue(&mut self, channel: &str, item: Item) -> Result<()> {
-        self.write_in(channel, &item);
-        Ok(())
+    #[test]
+    fn it_rejects_bad_channel() {
+        assert!(KafkaOuput::new("kafka:test").is_err());
+        assert!(KafkaOuput::new("kafka@10.200.100.200:9092").is_err());
+        assert!(KafkaOuput::new("kafka:test@10.200.100.200:9092?compression=lz4").is_err());
+        assert!(KafkaOuput::new("kafka:test@10.200.100.200:9092?compression=zstd").is_err());
+        assert!(KafkaOuput::new("kafka:test@10.200.100.200:9092?batch=1").is_err());
+        assert!(KafkaOuput::new("kafka:test@10.200.100.200:9092?codec=json&schema_id=3").is_err());
     }
-}
 
-impl IOutput for KafkaOuput {
-    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
-        if !self.channels.contains_key(channel) {
-            self.not_exist_create(channel)?;
+    #[test]
+    fn it_keys_records() {
+        let item = Item::from(r#"{"custom":{"nodeId":"pod-0","container":"web"},"message":"m"}"#);
+        assert_eq!(KafkaKey::parse("pod").of(&item), "pod-0");
+        assert_eq!(KafkaKey::parse("container").of(&item), "web");
+        assert_eq!(KafkaKey::parse("service").of(&item), "");
+        assert_eq!(KafkaKey::parse("none").of(&item), "");
+        assert_eq!(KafkaKey::parse("pod").of(&Item::from("raw")), "");
+    }
+
+    #[test]
+    fn it_buffers_until_batch_is_due() {
+        let mut ko =
+            KafkaOuput::new("kafka:test@127.0.0.1:9092?batch_size=10&linger_ms=60000").unwrap();
+        for i in 0..3 {
+            ko.write("kafka", Item::from(format!("{}", i).as_str()))
+                .unwrap();
         }
-        self.write_to_channel_queue(channel, item)
+        // the linger of the batch has not elapsed, nothing is sent
+        ko.flush("kafka").unwrap();
+        assert_eq!(ko.batch.len(), 3);
+        assert_eq!(ko.batch.items()[0], ("".to_string(), b"0".to_vec()));
     }
 }
-
-// #[cfg(test)]
-// mod tests {
-//     use super::KafkaOuput;
-//     use crate::IOutput;
-//     use common::Item;
-//     use std::{thread, time::Duration};
-
-//     #[test]
-//     fn kafka_working() {
-//         //first docker run a kafka
-//         let mut ko = KafkaOuput::new();
-//         for index in 0..1000 {
-//             let item = Item::from(format!("{:?} xx", index).as_str());
-//             if let Err(e) = ko.write(&"kafka:test@10.200.100.200:9092", item) {
-//                 panic!("{:?}", e)
-//             }
-//         }
-//         thread::sleep(Duration::from_secs(5));
-//     }
-// }
@@ -1,15 +1,56 @@
-use common::{Item, Result};
+use batch::Batch;
+use common::{health, metrics, Item, Result};
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use es_output::ElasticsearchOutput;
+use file_output::FileOutput;
+use http_output::HttpOutput;
 use kafka_output::KafkaOuput;
+use loki_output::LokiOutput;
 use once_cell::sync::Lazy;
+use route::route;
+use serde_json::{json, Value};
+use spool::Spool;
+use syslog_output::SyslogOutput;
 
+use std::cmp;
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+mod ack;
+mod batch;
+mod codec;
+mod es_output;
+mod fields;
+mod file_output;
+mod http_output;
 mod kafka_output;
+mod loki_output;
+mod route;
+mod spool;
+mod syslog_output;
+#[cfg(test)]
+mod testing;
 
+pub use ack::{Ack, Record};
+pub use codec::AVRO_SCHEMA;
+pub use route::{channel_id, channels, join_channels, Route};
+pub use spool::SpoolConfig;
 pub use OUTPUTS as OTS;
 
+// records queued per output, a full queue blocks the readers shipping to it
+const QUEUE_SIZE: usize = 10240;
+// an output worker calls IOutput.flush at least this often
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+// how often a worker writes a record its output failed again before it fails the ack
+const RETRIES: u32 = 3;
+// how long a worker waits before it writes the records its output failed again,
+// doubled up to MAX_RETRY_BACKOFF while they keep failing
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
 pub static OUTPUTS: Lazy<Arc<Mutex<Outputs>>> = Lazy::new(|| {
     let outputs = Arc::new(Mutex::new(Outputs::new()));
     if let Ok(mut ots) = outputs.lock() {
@@ -19,26 +60,196 @@ pub static OUTPUTS: Lazy<Arc<Mutex<Outputs>>> = Lazy::new(|| {
     outputs
 });
 
-pub fn registry_output(channel: &str) {
-    if !channel.starts_with("kafka") {
-        return;
+// registry the outputs of every channel of output, see channels
+pub fn registry_output(output: &str) {
+    for channel in channels(output) {
+        registry_channel(&channel);
     }
+}
+
+// registry the output of channel by its scheme, e.g. kafka:topic@broker, es:http://host:9200 or https://host/path
+fn registry_channel(channel: &str) {
     if let Ok(mut ots) = OUTPUTS.lock() {
         if ots.contains_output(channel) {
             return;
         }
-        ots.registry_output(channel, Output::new(KafkaOuput::new()));
+        let registered = match channel.split_once(':').map(|(scheme, _)| scheme) {
+            Some("kafka") => {
+                KafkaOuput::new(channel).map(|ko| ots.registry_output(channel, Output::new(ko)))
+            }
+            Some("es") | Some("elasticsearch") => ElasticsearchOutput::new(channel)
+                .map(|es| ots.registry_output(channel, Output::new(es))),
+            Some("http") | Some("https") => {
+                HttpOutput::new(channel).map(|ho| ots.registry_output(channel, Output::new(ho)))
+            }
+            Some("file") => {
+                FileOutput::new(channel).map(|fo| ots.registry_output(channel, Output::new(fo)))
+            }
+            Some("syslog") => {
+                SyslogOutput::new(channel).map(|so| ots.registry_output(channel, Output::new(so)))
+            }
+            Some("loki") => {
+                LokiOutput::new(channel).map(|lo| ots.registry_output(channel, Output::new(lo)))
+            }
+            _ => return,
+        };
+        if let Err(e) = registered {
+            eprintln!("registry output `{}` error: {:?}", channel, e);
+        }
+    }
+}
+
+// ship the line to every output of the pod output and of the routes matching it,
+// the line is queued on the outputs with room first and only then it waits on the full queues.
+// the global OUTPUTS lock is only held to look the queues up, never while waiting on them.
+// the ack is done once every output delivered the line, each output keeps its own queue,
+// spool and retries and one failing output does not keep the line from the others.
+pub fn output(output: &str, line: &str, ack: Ack) -> Result<()> {
+    if line.is_empty() {
+        ack.done();
+        return Ok(());
+    }
+    ship(output, Item::from(line), ack)
+}
+
+// ship the item like output ships a line, a json item reaches the outputs as it is
+pub fn ship(output: &str, item: Item, ack: Ack) -> Result<()> {
+    let senders = match OUTPUTS.lock() {
+        Ok(ots) => ots.destinations(output, &item),
+        Err(e) => return Err(e.to_string().into()),
+    };
+    let senders = match senders {
+        Some(senders) if !senders.is_empty() => senders,
+        // a route dropped the line
+        None => {
+            ack.done();
+            return Ok(());
+        }
+        Some(_) => {
+            eprintln!("output not found `{:?}`", output);
+            eprintln!("use stdout {:?}", item.string());
+            ack.done();
+            return Ok(());
+        }
+    };
+    let acks = ack.split(senders.len());
+    let mut errors = vec![];
+    let mut full = vec![];
+    for (sender, ack) in senders.iter().zip(acks) {
+        match sender.try_send_item(item.clone(), ack) {
+            Ok(Some(record)) => full.push((sender, record)),
+            Ok(None) => {}
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    for (sender, record) in full {
+        if sender.tx.send(record).is_err() {
+            errors.push(format!("output `{}` worker is gone", sender.channel));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join(", ").into());
+    }
+    Ok(())
+}
+
+// route the lines of every pod by routes, the outputs of the routes are registered
+pub fn set_routes(routes: Vec<Route>) {
+    for route in routes.iter() {
+        for channel in route.outputs.iter() {
+            registry_channel(channel);
+        }
+    }
+    if let Ok(mut ots) = OUTPUTS.lock() {
+        ots.routes = routes;
+    }
+}
+
+// spool the outputs registered from now on below cfg.dir
+pub fn enable_spool(cfg: SpoolConfig) {
+    if let Ok(mut ots) = OUTPUTS.lock() {
+        ots.spool = Some(cfg);
+    }
+}
+
+// queue and spool depth of every output
+pub fn stats() -> Value {
+    let mut stats = json!({});
+    if let Ok(ots) = OUTPUTS.lock() {
+        for (channel, sender) in ots.output_listener.iter() {
+            let channel = &channel_id(channel);
+            stats[channel] = json!({ "queue": sender.tx.len() });
+            if let Some(spool) = &sender.spool {
+                let (records, bytes, segments) = spool.depth();
+                stats[channel]["spool"] = json!({
+                    "records": records,
+                    "bytes": bytes,
+                    "segments": segments,
+                });
+            }
+        }
+    }
+    stats
+}
+
+// OutputSender is the sending side of the bounded queue of one output
+#[derive(Clone)]
+pub struct OutputSender {
+    channel: String,
+    tx: Sender<Record>,
+    spool: Option<Arc<Spool>>,
+}
+
+impl OutputSender {
+    // blocks while the queue is full, or spools the line when the output has a spool
+    pub fn send(&self, line: &str, ack: Ack) -> Result<()> {
+        if line.is_empty() {
+            ack.done();
+            return Ok(());
+        }
+        self.send_item(Item::from(line), ack)
+    }
+
+    // like send, the item is only written out when it is spooled
+    pub fn send_item(&self, item: Item, ack: Ack) -> Result<()> {
+        if let Some(spool) = &self.spool {
+            return spool.send(&self.tx, item, ack);
+        }
+        // the record and its ack are dropped, failed, when the worker is gone
+        if self.tx.send(Record::new(item, ack)).is_err() {
+            return Err(format!("output `{}` worker is gone", self.channel).into());
+        }
+        Ok(())
+    }
+
+    // like send_item without waiting, the record is handed back when the queue is full
+    fn try_send_item(&self, item: Item, ack: Ack) -> Result<Option<Record>> {
+        if let Some(spool) = &self.spool {
+            spool.send(&self.tx, item, ack)?;
+            return Ok(None);
+        }
+        match self.tx.try_send(Record::new(item, ack)) {
+            Ok(()) => Ok(None),
+            Err(TrySendError::Full(record)) => Ok(Some(record)),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(format!("output `{}` worker is gone", self.channel).into())
+            }
+        }
     }
 }
 
 pub struct Outputs {
-    output_listener: HashMap<String, Box<dyn IOutput>>,
+    output_listener: HashMap<String, OutputSender>,
+    spool: Option<SpoolConfig>,
+    routes: Vec<Route>,
 }
 
 impl Outputs {
     pub fn new() -> Self {
         Self {
             output_listener: HashMap::new(),
+            spool: None,
+            routes: vec![],
         }
     }
 
@@ -46,6 +257,7 @@ impl Outputs {
         self.output_listener.contains_key(channel)
     }
 
+    // every output gets a bounded queue drained by its own worker thread
     pub fn registry_output<T>(&mut self, channel: &str, t: T)
     where
         T: IOutput + Send + Sync + 'static,
@@ -53,33 +265,226 @@ impl Outputs {
         if self.output_listener.contains_key(channel) {
             return;
         }
-        self.output_listener
-            .insert(channel.to_string(), Box::new(t));
+        let spool = match &self.spool {
+            Some(cfg) => match Spool::open(channel, cfg) {
+                Ok(it) => Some(it),
+                Err(e) => {
+                    eprintln!("output `{}` open spool error: {:?}", channel, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let (tx, rx) = bounded::<Record>(QUEUE_SIZE);
+        let name = channel.to_string();
+        let worker_spool = spool.clone();
+        let worker = thread::Builder::new()
+            .name(format!("output-{}", channel))
+            .spawn(move || work(&name, t, rx, worker_spool));
+        if let Err(e) = worker {
+            eprintln!("output `{}` start worker error: {:?}", channel, e);
+            return;
+        }
+        self.output_listener.insert(
+            channel.to_string(),
+            OutputSender {
+                channel: channel.to_string(),
+                tx,
+                spool,
+            },
+        );
     }
 
-    pub fn output(&mut self, channel: &str, line: &str) {
-        if !self.output_listener.contains_key(channel) {
-            if line.len() == 0 {
-                return;
+    pub fn sender(&self, channel: &str) -> Option<OutputSender> {
+        self.output_listener.get(channel).cloned()
+    }
+
+    // the registered outputs of the line, none when the routes leave it without any channel
+    pub fn destinations(&self, output: &str, item: &Item) -> Option<Vec<OutputSender>> {
+        let channels = route(&self.routes, channels(output), item);
+        if channels.is_empty() {
+            return None;
+        }
+        Some(
+            channels
+                .iter()
+                .filter_map(|channel| self.sender(channel))
+                .collect(),
+        )
+    }
+
+    // blocks while the queue of an output is full,
+    // callers sharing Outputs behind a lock should wait on Outputs.destinations instead
+    pub fn output(&mut self, output: &str, line: &str) {
+        match self.destinations(output, &Item::from(line)) {
+            Some(senders) if !senders.is_empty() => {
+                for sender in senders {
+                    if let Err(e) = sender.send(line, Ack::none()) {
+                        eprintln!("{:?}", e);
+                    }
+                }
+            }
+            None => {}
+            Some(_) => {
+                if line.is_empty() {
+                    return;
+                }
+                eprintln!("output not found `{:?}`", output);
+                eprintln!("use stdout {:?}", line);
             }
-            eprintln!("output not found `{:?}`", channel);
-            eprintln!("use stdout {:?}", line);
-            return;
         }
+    }
+}
 
-        match self.output_listener.get_mut(channel) {
-            Some(o) => {
-                if let Err(e) = o.write(channel, Item::from(line)) {
-                    eprintln!("{:?}", e);
+// work writes the queued records into the output until every sender is dropped,
+// the spooled records are drained whenever the queue is empty.
+// the records the output failed are written again up to IOutput.retries times after a backoff,
+// the queue waits meanwhile so only the shippers of this output are held up.
+fn work<T: IOutput>(channel: &str, mut o: T, rx: Receiver<Record>, spool: Option<Arc<Spool>>) {
+    let id = channel_id(channel);
+    let labels = [("output", id.as_str())];
+    let written = metrics::counter(
+        "harvest_output_records_total",
+        "records written to an output",
+        &labels,
+    );
+    let errors = metrics::counter(
+        "harvest_output_errors_total",
+        "errors writing to or flushing an output",
+        &labels,
+    );
+    let depth = metrics::gauge(
+        "harvest_output_queue_depth",
+        "records waiting in the queue of an output",
+        &labels,
+    );
+    let probe = health::Probe::new(&format!("output:{}", id));
+    // the output is not ready while its last write or flush failed
+    let mut failing = false;
+    let mut flushed = Instant::now();
+    let retries = o.retries();
+    // the failed records with the retries they have left
+    let (retry_tx, retry_rx) = unbounded::<(Record, u32)>();
+    let mut retry_at: Option<Instant> = None;
+    let mut backoff = RETRY_BACKOFF;
+    loop {
+        let received = if !retry_rx.is_empty() {
+            let at = *retry_at.get_or_insert_with(|| {
+                let at = Instant::now() + backoff;
+                backoff = cmp::min(backoff * 2, MAX_RETRY_BACKOFF);
+                at
+            });
+            match at.checked_duration_since(Instant::now()) {
+                Some(wait) if !wait.is_zero() => {
+                    thread::sleep(cmp::min(wait, FLUSH_INTERVAL));
+                    Err(RecvTimeoutError::Timeout)
+                }
+                _ => retry_rx.try_recv().map_err(|_| RecvTimeoutError::Timeout),
+            }
+        } else {
+            retry_at = None;
+            // queued records were sent before the spooled ones
+            match rx.try_recv() {
+                Ok(record) => Ok(record),
+                Err(_) => match spool.as_ref().and_then(|spool| spool.next()) {
+                    Some(record) => Ok(record),
+                    None => rx.recv_timeout(FLUSH_INTERVAL),
+                },
+            }
+            .map(|record| (record, retries))
+        };
+        match received {
+            Ok((record, left)) => match o.deliver(channel, retrying(record, left, &retry_tx)) {
+                Ok(()) => {
+                    written.inc();
+                    if failing {
+                        failing = false;
+                        probe.ready();
+                    }
                 }
+                Err(e) => {
+                    errors.inc();
+                    eprintln!("output `{}` write error: {:?}", channel, e);
+                    failing = true;
+                    probe.unready(&format!("write error: {}", e));
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                if let Err(e) = o.flush(channel) {
+                    eprintln!("output `{}` flush error: {:?}", channel, e);
+                }
+                return;
+            }
+        }
+        if flushed.elapsed() >= FLUSH_INTERVAL {
+            match o.flush(channel) {
+                Ok(()) if failing => {
+                    failing = false;
+                    probe.ready();
+                }
+                Ok(()) => {}
+                Err(e) => {
+                    errors.inc();
+                    eprintln!("output `{}` flush error: {:?}", channel, e);
+                    failing = true;
+                    probe.unready(&format!("flush error: {}", e));
+                }
+            }
+            if let Some(spool) = &spool {
+                spool.expire();
             }
-            _ => {}
+            if !failing && retry_rx.is_empty() {
+                backoff = RETRY_BACKOFF;
+            }
+            depth.set(rx.len() as i64);
+            flushed = Instant::now();
         }
     }
 }
 
+// the record with an ack that queues it for another write when the output fails it
+// and it has retries left, otherwise the failure settles its ack
+fn retrying(record: Record, left: u32, retry: &Sender<(Record, u32)>) -> Record {
+    if left == 0 {
+        return record;
+    }
+    let Record { item, ack } = record;
+    let again = item.clone();
+    let retry = retry.clone();
+    Record::new(
+        item,
+        Ack::new(move |ok| match ok {
+            true => ack.done(),
+            // dropping the record handed back fails its ack
+            false => drop(retry.send((Record::new(again, ack), left - 1))),
+        }),
+    )
+}
+
 pub trait IOutput: Send + Sync + 'static {
     fn write(&mut self, channel: &str, item: Item) -> Result<()>;
+
+    // write the record and settle its ack once it is delivered,
+    // outputs that buffer records keep the ack until the buffer is sent
+    fn deliver(&mut self, channel: &str, record: Record) -> Result<()> {
+        let Record { item, ack } = record;
+        self.write(channel, item)?;
+        ack.done();
+        Ok(())
+    }
+
+    // how often the worker writes a record the output failed again, outputs that retry
+    // their batches themselves return 0 so their own budget bounds the attempts
+    fn retries(&self) -> u32 {
+        RETRIES
+    }
+
+    // called by the output worker at least every FLUSH_INTERVAL,
+    // outputs buffering records send the ones that are due here
+    fn flush(&mut self, _channel: &str) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -100,6 +505,18 @@ impl<T: IOutput> IOutput for Output<T> {
     fn write(&mut self, channel: &str, item: Item) -> Result<()> {
         self.o.write(channel, item)
     }
+
+    fn deliver(&mut self, channel: &str, record: Record) -> Result<()> {
+        self.o.deliver(channel, record)
+    }
+
+    fn retries(&self) -> u32 {
+        self.o.retries()
+    }
+
+    fn flush(&mut self, channel: &str) -> Result<()> {
+        self.o.flush(channel)
+    }
 }
 
 pub fn sync_via_output(line: &str, channel: &str, output: Arc<Mutex<dyn IOutput>>) -> Result<()> {
@@ -145,6 +562,23 @@ mod tests {
     use super::*;
     use std::thread;
 
+    struct Collect {
+        lines: Arc<Mutex<Vec<String>>>,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl IOutput for Collect {
+        fn write(&mut self, _: &str, item: Item) -> Result<()> {
+            self.lines.lock().unwrap().push(item.string());
+            Ok(())
+        }
+
+        fn flush(&mut self, _: &str) -> Result<()> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
     #[test]
     fn it_works() {
         let output = &mut Output::new(FakeOutput);
@@ -201,4 +635,151 @@ mod tests {
 
         let _ = j.into_iter().map(|_j| _j.join().unwrap());
     }
+
+    #[test]
+    fn it_drains_output_queue_in_order() {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let mut outputs = Outputs::new();
+        outputs.registry_output(
+            "collect",
+            Collect {
+                lines: lines.clone(),
+                flushes: flushes.clone(),
+            },
+        );
+
+        let acked = Arc::new(AtomicUsize::new(0));
+        let sender = outputs.sender("collect").unwrap();
+        for i in 0..100 {
+            let acked = acked.clone();
+            let ack = Ack::new(move |ok| {
+                if ok {
+                    acked.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            sender.send(&format!("{}", i), ack).unwrap();
+        }
+        assert!(outputs.sender("unknown").is_none());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while acked.load(Ordering::SeqCst) < 100 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let expected = (0..100).map(|i| format!("{}", i)).collect::<Vec<String>>();
+        assert_eq!(*lines.lock().unwrap(), expected);
+        assert_eq!(acked.load(Ordering::SeqCst), 100);
+
+        thread::sleep(FLUSH_INTERVAL * 3);
+        assert!(flushes.load(Ordering::SeqCst) > 0);
+    }
+
+    struct Flaky {
+        lines: Arc<Mutex<Vec<String>>>,
+        failures: usize,
+        retries: u32,
+    }
+
+    impl IOutput for Flaky {
+        fn write(&mut self, _: &str, item: Item) -> Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err("unavailable".into());
+            }
+            self.lines.lock().unwrap().push(item.string());
+            Ok(())
+        }
+
+        fn retries(&self) -> u32 {
+            self.retries
+        }
+    }
+
+    // ship a and b to a flaky output failing its first writes, the acks in the order they settle
+    fn ship_flaky(failures: usize, retries: u32) -> (Vec<bool>, Vec<String>) {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let mut outputs = Outputs::new();
+        outputs.registry_output(
+            "flaky",
+            Flaky {
+                lines: lines.clone(),
+                failures,
+                retries,
+            },
+        );
+
+        let acked = Arc::new(Mutex::new(vec![]));
+        let sender = outputs.sender("flaky").unwrap();
+        for line in &["a", "b"] {
+            let acked = acked.clone();
+            let ack = Ack::new(move |ok| acked.lock().unwrap().push(ok));
+            sender.send(line, ack).unwrap();
+        }
+
+        let deadline = Instant::now() + RETRY_BACKOFF * 5;
+        while acked.lock().unwrap().len() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let acked = acked.lock().unwrap().clone();
+        let mut lines = lines.lock().unwrap().clone();
+        lines.sort();
+        (acked, lines)
+    }
+
+    #[test]
+    fn it_retries_failed_records() {
+        let (acked, lines) = ship_flaky(1, 1);
+        assert_eq!(acked, vec![true, true]);
+        assert_eq!(lines, vec!["a", "b"]);
+
+        // a record still failed after its retries fails its ack, the next one is written
+        let (acked, lines) = ship_flaky(2, 1);
+        assert_eq!(acked, vec![false, true]);
+        assert_eq!(lines, vec!["b"]);
+
+        // outputs retrying their batches themselves fail the ack right away
+        let (acked, lines) = ship_flaky(1, 0);
+        assert_eq!(acked, vec![false, true]);
+        assert_eq!(lines, vec!["b"]);
+    }
+
+    #[test]
+    fn it_fans_out_to_routed_outputs() {
+        let mut outputs = Outputs::new();
+        let mut collected = vec![];
+        for channel in &["kafka", "audit"] {
+            let lines = Arc::new(Mutex::new(vec![]));
+            let collect = Collect {
+                lines: lines.clone(),
+                flushes: Arc::new(AtomicUsize::new(0)),
+            };
+            outputs.registry_output(channel, collect);
+            collected.push(lines);
+        }
+        outputs.routes = Route::parse(r#"[{"level":["error"],"outputs":["audit"]}]"#).unwrap();
+
+        let error = r#"{"level":"error","message":"e"}"#;
+        let senders = outputs.destinations("kafka", &Item::from(error)).unwrap();
+        assert_eq!(senders.len(), 2);
+        let acked = Arc::new(Mutex::new(vec![]));
+        let ack = {
+            let acked = acked.clone();
+            Ack::new(move |ok| acked.lock().unwrap().push(ok))
+        };
+        for (sender, ack) in senders.iter().zip(ack.split(senders.len())) {
+            sender.send(error, ack).unwrap();
+        }
+        outputs.output(&join_channels(&["kafka", "unknown"]), "info");
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while acked.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*acked.lock().unwrap(), vec![true]);
+        while collected[0].lock().unwrap().len() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(collected[0].lock().unwrap().len(), 2);
+        assert_eq!(collected[1].lock().unwrap().len(), 1);
+    }
 }
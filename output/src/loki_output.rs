@@ -0,0 +1,582 @@
+use super::http_output::Outcome;
+use super::{fields, Ack, Batch, IOutput, Item, Record, Result};
+use chrono::{DateTime, Utc};
+use prost::Message;
+use serde_json::{json, Map, Value};
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::{cmp, thread};
+
+const PUSH_PATH: &str = "/loki/api/v1/push";
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// the last timestamp of a stream nothing was pushed to for this long is forgotten
+const STREAM_IDLE: Duration = Duration::from_secs(3600);
+
+// the messages of logproto.PushRequest
+#[derive(Clone, PartialEq, Message)]
+struct PushRequest {
+    #[prost(message, repeated, tag = "1")]
+    streams: Vec<StreamAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct StreamAdapter {
+    #[prost(string, tag = "1")]
+    labels: String,
+    #[prost(message, repeated, tag = "2")]
+    entries: Vec<EntryAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct EntryAdapter {
+    #[prost(message, optional, tag = "1")]
+    timestamp: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    line: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Timestamp {
+    #[prost(int64, tag = "1")]
+    seconds: i64,
+    #[prost(int32, tag = "2")]
+    nanos: i32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PushFormat {
+    // snappy compressed protobuf, what promtail sends
+    Protobuf,
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum LineFormat {
+    // the message field of the record
+    Message,
+    // the whole record
+    Json,
+}
+
+// Label is a stream label and the record field it is taken from
+#[derive(Clone, Debug, PartialEq)]
+struct Label {
+    name: String,
+    field: String,
+}
+
+#[derive(Clone, Debug)]
+struct LokiOutputConfig {
+    url: String,
+    format: PushFormat,
+    line: LineFormat,
+    labels: Vec<Label>,
+    // X-Scope-OrgID of a multi tenant loki
+    tenant: Option<String>,
+    // push once the batch holds batch_size entries
+    batch_size: usize,
+    // or once its first entry waited for flush_interval
+    flush_interval: Duration,
+    timeout: Duration,
+    // a push is retried this many times before it is dropped
+    retries: u32,
+    // first retry delay, doubled on every further retry
+    backoff: Duration,
+    // basic authorization header
+    authorization: Option<String>,
+}
+
+impl Default for LokiOutputConfig {
+    fn default() -> Self {
+        Self {
+            url: "".to_string(),
+            format: PushFormat::Protobuf,
+            line: LineFormat::Message,
+            labels: parse_labels(
+                "namespace,pod_name:pod,container,service_name:service,node_name:node",
+            )
+            .unwrap_or_default(),
+            tenant: None,
+            batch_size: 1000,
+            flush_interval: Duration::from_millis(1000),
+            timeout: Duration::from_secs(10),
+            retries: 5,
+            backoff: Duration::from_millis(100),
+            authorization: None,
+        }
+    }
+}
+
+// labels = namespace,pod,app:service, a label is named after its field unless name:field is given
+fn parse_labels(value: &str) -> Result<Vec<Label>> {
+    let mut labels = vec![];
+    for spec in value.split(',').filter(|spec| !spec.is_empty()) {
+        let (name, field) = spec.split_once(':').unwrap_or((spec, spec));
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid || field.is_empty() {
+            return Err(format!("loki label `{}` is not a valid label name", spec).into());
+        }
+        labels.push(Label {
+            name: name.to_string(),
+            field: field.to_string(),
+        });
+    }
+    Ok(labels)
+}
+
+// Stream is the label set entries are pushed to
+struct Stream {
+    labels: Vec<(String, String)>,
+    // timestamp of the last entry, loki rejects older ones
+    last: i64,
+    seen: Instant,
+}
+
+struct Entry {
+    // the label string that keys the stream
+    stream: String,
+    // nanoseconds since the epoch
+    timestamp: i64,
+    line: String,
+}
+
+// LokiOutput pushes the records of one channel to the loki push api
+pub(crate) struct LokiOutput {
+    cfg: LokiOutputConfig,
+    agent: ureq::Agent,
+    batch: Batch<Entry>,
+    streams: HashMap<String, Stream>,
+}
+
+impl LokiOutput {
+    pub fn new(channel: &str) -> Result<Self> {
+        let cfg = Self::parse_uri(channel)?;
+        Ok(Self {
+            agent: ureq::AgentBuilder::new().timeout(cfg.timeout).build(),
+            batch: Batch::new(cfg.batch_size, cfg.flush_interval),
+            streams: HashMap::new(),
+            cfg,
+        })
+    }
+
+    // channel = loki:http://loki:3100?format=protobuf&line=message&labels=namespace,pod,app:service&tenant=team-a&batch_size=1000&flush_ms=1000&timeout_ms=10000&retries=5&backoff_ms=100&user=&password=
+    fn parse_uri(channel: &str) -> Result<LokiOutputConfig> {
+        let rest = match channel.strip_prefix("loki:") {
+            Some(rest) if rest.starts_with("http://") || rest.starts_with("https://") => rest,
+            _ => return Err(format!("channel `{}` is not a loki channel", channel).into()),
+        };
+        let (url, options) = rest.split_once('?').unwrap_or((rest, ""));
+        let url = url.trim_end_matches('/');
+        let mut cfg = LokiOutputConfig {
+            url: match url.ends_with(PUSH_PATH) {
+                true => url.to_string(),
+                false => format!("{}{}", url, PUSH_PATH),
+            },
+            ..Default::default()
+        };
+        let (mut user, mut password) = (None, "");
+        for option in options.split('&').filter(|option| !option.is_empty()) {
+            let (name, value) = option.split_once('=').unwrap_or((option, ""));
+            match name {
+                "format" => {
+                    cfg.format = match value {
+                        "protobuf" => PushFormat::Protobuf,
+                        "json" => PushFormat::Json,
+                        _ => {
+                            return Err(format!(
+                                "loki format `{}` is not supported, expect protobuf or json",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "line" => {
+                    cfg.line = match value {
+                        "message" => LineFormat::Message,
+                        "json" => LineFormat::Json,
+                        _ => {
+                            return Err(format!(
+                                "loki line `{}` is not supported, expect message or json",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "labels" => cfg.labels = parse_labels(value)?,
+                "tenant" => cfg.tenant = Some(value.to_string()),
+                "batch_size" => cfg.batch_size = cmp::max(value.parse::<usize>()?, 1),
+                "flush_ms" => cfg.flush_interval = Duration::from_millis(value.parse::<u64>()?),
+                "timeout_ms" => cfg.timeout = Duration::from_millis(value.parse::<u64>()?),
+                "retries" => cfg.retries = value.parse::<u32>()?,
+                "backoff_ms" => cfg.backoff = Duration::from_millis(value.parse::<u64>()?),
+                "user" => user = Some(value),
+                "password" => password = value,
+                _ => return Err(format!("unknown loki channel option `{}`", name).into()),
+            }
+        }
+        if let Some(user) = user {
+            cfg.authorization = Some(format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", user, password))
+            ));
+        }
+        Ok(cfg)
+    }
+
+    // the labels of the record sorted by name, {job="harvest"} when it has none of them
+    fn labels(&self, item: &Item) -> Vec<(String, String)> {
+        let mut labels = self
+            .cfg
+            .labels
+            .iter()
+            .filter_map(|label| {
+                let value = fields::lookup(item, &label.field)?;
+                Some((label.name.clone(), value))
+            })
+            .filter(|(_, value)| !value.is_empty())
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            labels.push(("job".to_string(), "harvest".to_string()));
+        }
+        labels.sort();
+        labels.dedup_by(|a, b| a.0 == b.0);
+        labels
+    }
+
+    // push the entry to the stream of its labels, an entry older than the last one
+    // of its stream gets the timestamp of that one so that loki keeps it
+    fn entry(&mut self, item: &Item) -> Entry {
+        let labels = self.labels(item);
+        let key = format!(
+            "{{{}}}",
+            labels
+                .iter()
+                .map(|(name, value)| format!(
+                    "{}=\"{}\"",
+                    name,
+                    value.replace('\\', "\\\\").replace('"', "\\\"")
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let timestamp = fields::lookup(item, "time")
+            .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+            .and_then(|time| time.timestamp_nanos_opt())
+            .or_else(|| Utc::now().timestamp_nanos_opt())
+            .unwrap_or_default();
+        let line = match (&self.cfg.line, item) {
+            (LineFormat::Message, Item::JSON(_)) => {
+                fields::lookup(item, "message").unwrap_or_else(|| item.string())
+            }
+            _ => item.string(),
+        };
+
+        let stream = self.streams.entry(key.clone()).or_insert_with(|| Stream {
+            labels,
+            last: i64::MIN,
+            seen: Instant::now(),
+        });
+        stream.last = cmp::max(stream.last, timestamp);
+        stream.seen = Instant::now();
+        Entry {
+            stream: key,
+            timestamp: stream.last,
+            line: line.trim_end_matches(['\n', '\r']).to_string(),
+        }
+    }
+
+    // the entries of the batch by stream, in the order the streams were first seen
+    fn group(&self) -> Vec<(&str, Vec<&Entry>)> {
+        let mut groups: Vec<(&str, Vec<&Entry>)> = vec![];
+        let mut index = HashMap::new();
+        for entry in self.batch.items() {
+            let i = *index.entry(entry.stream.as_str()).or_insert_with(|| {
+                groups.push((entry.stream.as_str(), vec![]));
+                groups.len() - 1
+            });
+            groups[i].1.push(entry);
+        }
+        groups
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let groups = self.group();
+        match self.cfg.format {
+            PushFormat::Protobuf => {
+                let request = PushRequest {
+                    streams: groups
+                        .into_iter()
+                        .map(|(labels, entries)| StreamAdapter {
+                            labels: labels.to_string(),
+                            entries: entries
+                                .into_iter()
+                                .map(|entry| EntryAdapter {
+                                    timestamp: Some(Timestamp {
+                                        seconds: entry.timestamp.div_euclid(1_000_000_000),
+                                        nanos: entry.timestamp.rem_euclid(1_000_000_000) as i32,
+                                    }),
+                                    line: entry.line.clone(),
+                                })
+                                .collect(),
+                        })
+                        .collect(),
+                };
+                Ok(snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?)
+            }
+            PushFormat::Json => {
+                let streams = groups
+                    .into_iter()
+                    .map(|(key, entries)| {
+                        let labels = self
+                            .streams
+                            .get(key)
+                            .map(|stream| {
+                                stream
+                                    .labels
+                                    .iter()
+                                    .map(|(name, value)| (name.clone(), json!(value)))
+                                    .collect::<Map<String, Value>>()
+                            })
+                            .unwrap_or_default();
+                        let values = entries
+                            .into_iter()
+                            .map(|entry| json!([entry.timestamp.to_string(), entry.line]))
+                            .collect::<Vec<_>>();
+                        json!({ "stream": labels, "values": values })
+                    })
+                    .collect::<Vec<_>>();
+                Ok(json!({ "streams": streams }).to_string().into_bytes())
+            }
+        }
+    }
+
+    fn push(&self, body: &[u8]) -> Result<Outcome> {
+        let content_type = match self.cfg.format {
+            PushFormat::Protobuf => "application/x-protobuf",
+            PushFormat::Json => "application/json",
+        };
+        let mut request = self
+            .agent
+            .post(&self.cfg.url)
+            .set("Content-Type", content_type);
+        if let Some(tenant) = &self.cfg.tenant {
+            request = request.set("X-Scope-OrgID", tenant);
+        }
+        if let Some(authorization) = &self.cfg.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        match request.send_bytes(body) {
+            Ok(response) => Ok(Outcome::of(response.status())),
+            Err(ureq::Error::Status(status, response)) => {
+                let outcome = Outcome::of(status);
+                if outcome == Outcome::Rejected {
+                    eprintln!(
+                        "loki output {} rejected a push with {}: {}",
+                        self.cfg.url,
+                        status,
+                        response.into_string().unwrap_or_default()
+                    );
+                }
+                Ok(outcome)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // push the batch, retrying with exponential backoff until the retry budget is spent,
+    // a rejected push is logged and skipped as retrying it can not succeed
+    fn send_buffer(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let body = match self.encode() {
+            Ok(it) => it,
+            Err(e) => {
+                self.batch.fail();
+                return Err(e);
+            }
+        };
+        let mut backoff = self.cfg.backoff;
+        for attempt in 0..=self.cfg.retries {
+            match self.push(&body) {
+                Ok(Outcome::Sent) | Ok(Outcome::Rejected) => {
+                    self.batch.settle(&[]);
+                    return Ok(());
+                }
+                Ok(Outcome::Retry) => eprintln!(
+                    "loki output {} asked to retry a push, attempt {}",
+                    self.cfg.url, attempt
+                ),
+                Err(e) => eprintln!(
+                    "loki output {} push error: {}, attempt {}",
+                    self.cfg.url, e, attempt
+                ),
+            }
+            if attempt < self.cfg.retries {
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        }
+
+        let dropped = self.batch.len();
+        self.batch.fail();
+        Err(format!(
+            "loki output {} dropped {} entries after {} retries",
+            self.cfg.url, dropped, self.cfg.retries
+        )
+        .into())
+    }
+}
+
+impl IOutput for LokiOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.deliver(channel, Record::new(item, Ack::none()))
+    }
+
+    fn deliver(&mut self, _: &str, record: Record) -> Result<()> {
+        let Record { item, ack } = record;
+        let entry = self.entry(&item);
+        if !self.batch.push(entry, ack) {
+            return Ok(());
+        }
+        self.send_buffer()
+    }
+
+    // the batches are retried here, records failed after cfg.retries are not written again
+    fn retries(&self) -> u32 {
+        0
+    }
+
+    fn flush(&mut self, _: &str) -> Result<()> {
+        if self.batch.is_empty() {
+            self.streams
+                .retain(|_, stream| stream.seen.elapsed() < STREAM_IDLE);
+        }
+        if !self.batch.is_due() {
+            return Ok(());
+        }
+        self.send_buffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LineFormat, LokiOutput, PushFormat, PushRequest};
+    use crate::testing::serve;
+    use crate::{Ack, IOutput, Record};
+    use common::Item;
+    use prost::Message;
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    fn line(pod: &str, time: &str, message: &str) -> Item {
+        Item::from(
+            format!(
+                r#"{{"custom":{{"namespace":"ns","nodeId":"{}","serviceName":"pay"}},"time":"{}","message":"{}\n"}}"#,
+                pod, time, message
+            )
+            .as_str(),
+        )
+    }
+
+    #[test]
+    fn it_parses_channel() {
+        let cfg = LokiOutput::parse_uri(
+            "loki:https://loki:3100/?format=json&line=json&labels=ns:namespace,app:service&tenant=a&user=u&password=p",
+        )
+        .unwrap();
+        assert_eq!(cfg.url, "https://loki:3100/loki/api/v1/push");
+        assert_eq!(cfg.format, PushFormat::Json);
+        assert_eq!(cfg.line, LineFormat::Json);
+        assert_eq!(
+            cfg.labels
+                .iter()
+                .map(|label| (label.name.as_str(), label.field.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("ns", "namespace"), ("app", "service")]
+        );
+        assert_eq!(cfg.tenant, Some("a".to_string()));
+        assert_eq!(cfg.authorization, Some("Basic dTpw".to_string()));
+        assert_eq!(
+            LokiOutput::parse_uri("loki:http://loki")
+                .unwrap()
+                .labels
+                .len(),
+            5
+        );
+
+        assert!(LokiOutput::parse_uri("loki:loki:3100").is_err());
+        assert!(LokiOutput::parse_uri("loki:http://loki?format=xml").is_err());
+        assert!(LokiOutput::parse_uri("loki:http://loki?labels=pod.name").is_err());
+    }
+
+    #[test]
+    fn it_groups_streams_with_ordered_timestamps() {
+        let mut output = LokiOutput::new("loki:http://loki?format=json").unwrap();
+        for (pod, time, message) in &[
+            ("a", "2021-03-16T23:05:01Z", "1"),
+            ("b", "2021-03-16T23:05:02Z", "2"),
+            ("a", "2021-03-16T23:05:00Z", "3"),
+        ] {
+            let entry = output.entry(&line(pod, time, message));
+            output.batch.push(entry, Ack::none());
+        }
+        let body = serde_json::from_slice::<Value>(&output.encode().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({"streams": [
+                {
+                    "stream": {"namespace": "ns", "pod_name": "a", "service_name": "pay"},
+                    "values": [["1615935901000000000", "1"], ["1615935901000000000", "3"]]
+                },
+                {
+                    "stream": {"namespace": "ns", "pod_name": "b", "service_name": "pay"},
+                    "values": [["1615935902000000000", "2"]]
+                }
+            ]})
+        );
+    }
+
+    #[test]
+    fn it_pushes_snappy_protobuf() {
+        let (addr, requests) = serve(vec![(204, "".to_string())]);
+        let mut output = LokiOutput::new(&format!(
+            "loki:http://{}?tenant=team-a&labels=pod&batch_size=2",
+            addr
+        ))
+        .unwrap();
+
+        let acked = Arc::new(Mutex::new(vec![]));
+        for message in &["1", "2"] {
+            let acked = acked.clone();
+            let ack = Ack::new(move |ok| acked.lock().unwrap().push(ok));
+            let item = line("a", "2021-03-16T23:05:01.5Z", message);
+            output.deliver("loki", Record::new(item, ack)).unwrap();
+        }
+
+        let request = requests.recv().unwrap();
+        assert!(request.head.starts_with("POST /loki/api/v1/push "));
+        assert_eq!(request.header("x-scope-orgid"), Some("team-a".to_string()));
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&request.body)
+            .unwrap();
+        let push = PushRequest::decode(body.as_slice()).unwrap();
+        assert_eq!(push.streams.len(), 1);
+        assert_eq!(push.streams[0].labels, r#"{pod="a"}"#);
+        let entries = &push.streams[0].entries;
+        assert_eq!(
+            entries.iter().map(|e| e.line.as_str()).collect::<Vec<_>>(),
+            vec!["1", "2"]
+        );
+        let timestamp = entries[0].timestamp.as_ref().unwrap();
+        assert_eq!(
+            (timestamp.seconds, timestamp.nanos),
+            (1615935901, 500_000_000)
+        );
+        assert_eq!(*acked.lock().unwrap(), vec![true, true]);
+    }
+}
@@ -0,0 +1,164 @@
+use super::{fields, Item, Result};
+use serde::Deserialize;
+
+// the channels of a pod output, a single channel or a json list of channels
+pub fn channels(output: &str) -> Vec<String> {
+    let output = output.trim();
+    if output.starts_with('[') {
+        if let Ok(channels) = serde_json::from_str::<Vec<String>>(output) {
+            return channels;
+        }
+    }
+    match output {
+        "" => vec![],
+        channel => vec![channel.to_string()],
+    }
+}
+
+// the pod output of channels, the inverse of channels
+pub fn join_channels(channels: &[&str]) -> String {
+    match channels {
+        [channel] => channel.to_string(),
+        channels => serde_json::to_string(channels).unwrap_or_default(),
+    }
+}
+
+// the id of channel shown on /metrics, /healthz and /outputs, its options and the user
+// info of its url are cut off as they may hold credentials, e.g. es:https://user:pass@es:9200,
+// a hash of the whole channel keeps apart the channels differing only in what was cut off
+pub fn channel_id(channel: &str) -> String {
+    let mut id = match channel.find(&['?', '#'][..]) {
+        Some(i) => &channel[..i],
+        None => channel,
+    }
+    .to_string();
+    if let Some(start) = id.find("://").map(|i| i + 3) {
+        let end = id[start..].find('/').map_or(id.len(), |i| start + i);
+        if let Some(at) = id[start..end].rfind('@') {
+            id.replace_range(start..start + at + 1, "");
+        }
+    }
+    if id == channel {
+        return id;
+    }
+    // fnv-1a
+    let hash = channel.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{}~{:08x}", id, hash as u32)
+}
+
+// Route sends the records it matches to more outputs, e.g. the errors of a namespace to syslog:
+// {"ns":["payments"],"level":["error","fatal"],"outputs":["syslog://siem:6514?transport=tls"]}
+// an empty match list matches any record.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Route {
+    pub ns: Vec<String>,
+    pub service: Vec<String>,
+    pub level: Vec<String>,
+    pub outputs: Vec<String>,
+    // the record goes to the outputs of the route instead of the outputs of its pod
+    pub replace: bool,
+}
+
+impl Route {
+    // routes = a json list of routes
+    pub fn parse(routes: &str) -> Result<Vec<Route>> {
+        let routes = serde_json::from_str::<Vec<Route>>(routes)?;
+        for route in routes.iter() {
+            if route.outputs.is_empty() && !route.replace {
+                return Err(format!("route {:?} has no outputs", route).into());
+            }
+        }
+        Ok(routes)
+    }
+
+    fn matches(&self, item: &Item) -> bool {
+        let field = |values: &[String], value: Option<String>| {
+            values.is_empty()
+                || value.is_some_and(|value| values.iter().any(|v| v.eq_ignore_ascii_case(&value)))
+        };
+        field(&self.ns, fields::lookup(item, "ns"))
+            && field(&self.service, fields::lookup(item, "service"))
+            && field(&self.level, fields::level(item))
+    }
+}
+
+// the channels of the record, its pod channels with the outputs of every matching route
+pub(crate) fn route(routes: &[Route], mut channels: Vec<String>, item: &Item) -> Vec<String> {
+    for route in routes.iter().filter(|route| route.matches(item)) {
+        if route.replace {
+            channels.clear();
+        }
+        for output in route.outputs.iter() {
+            if !channels.contains(output) {
+                channels.push(output.clone());
+            }
+        }
+    }
+    channels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel_id, channels, join_channels, Route};
+    use common::Item;
+
+    fn route(routes: &[Route], channels: Vec<String>, line: &str) -> Vec<String> {
+        super::route(routes, channels, &Item::from(line))
+    }
+
+    #[test]
+    fn it_parses_channels() {
+        assert_eq!(channels("kafka:a@b"), vec!["kafka:a@b"]);
+        assert_eq!(channels(""), Vec::<String>::new());
+        let output = join_channels(&["kafka:a@b", "file:/tmp/{pod}.log"]);
+        assert_eq!(output, r#"["kafka:a@b","file:/tmp/{pod}.log"]"#);
+        assert_eq!(channels(&output), vec!["kafka:a@b", "file:/tmp/{pod}.log"]);
+        assert_eq!(join_channels(&["kafka:a@b"]), "kafka:a@b");
+    }
+
+    #[test]
+    fn it_redacts_channel_ids() {
+        assert_eq!(channel_id("kafka:a@b"), "kafka:a@b");
+        assert_eq!(channel_id("file:/tmp/{pod}.log"), "file:/tmp/{pod}.log");
+
+        let es = channel_id("es:https://user:secret@es:9200/logs");
+        assert!(es.starts_with("es:https://es:9200/logs~"));
+        let kafka = channel_id("kafka:a@b?compression=gzip&sasl_password=secret");
+        assert!(kafka.starts_with("kafka:a@b~"));
+        assert!(!es.contains("secret") && !kafka.contains("secret"));
+        // stable, and apart for channels differing in the cut off part
+        assert_eq!(
+            kafka,
+            channel_id("kafka:a@b?compression=gzip&sasl_password=secret")
+        );
+        assert_ne!(kafka, channel_id("kafka:a@b?compression=lz4"));
+    }
+
+    #[test]
+    fn it_routes_records() {
+        let routes = Route::parse(
+            r#"[
+                {"ns":["prod"],"level":["error"],"outputs":["syslog://siem"]},
+                {"service":["audit"],"outputs":["file:/audit.log"],"replace":true}
+            ]"#,
+        )
+        .unwrap();
+        let pod = vec!["kafka:a@b".to_string()];
+
+        let error = r#"{"custom":{"namespace":"prod"},"message":"{\"level\":\"ERROR\"}"}"#;
+        assert_eq!(
+            route(&routes, pod.clone(), error),
+            vec!["kafka:a@b", "syslog://siem"]
+        );
+        let info = r#"{"custom":{"namespace":"prod"},"level":"info","message":"m"}"#;
+        assert_eq!(route(&routes, pod.clone(), info), vec!["kafka:a@b"]);
+        let audit = r#"{"custom":{"namespace":"dev","serviceName":"audit"},"message":"m"}"#;
+        assert_eq!(route(&routes, pod.clone(), audit), vec!["file:/audit.log"]);
+        assert_eq!(route(&routes, pod.clone(), "raw"), vec!["kafka:a@b"]);
+
+        assert!(Route::parse(r#"[{"ns":["prod"]}]"#).is_err());
+    }
+}
@@ -0,0 +1,562 @@
+use crate::{Ack, Record};
+use common::metrics::{self, Metric};
+use common::{Item, Result};
+use crossbeam_channel::{Sender, TrySendError};
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+const SEGMENT_EXT: &str = "seg";
+// how long the drain pauses after the output failed a spooled record, doubled up to MAX_BACKOFF
+const BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// SpoolConfig enables an on-disk spool per output channel below dir
+#[derive(Debug, Clone)]
+pub struct SpoolConfig {
+    pub dir: String,
+    // a segment file is sealed once it holds segment_bytes
+    pub segment_bytes: u64,
+    // writers wait while the spool of a channel holds max_bytes
+    pub max_bytes: u64,
+    // segments not written for max_age are dropped, delivered or not, the undelivered
+    // records are logged and counted by harvest_output_spool_dropped_records_total
+    pub max_age: Duration,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            dir: "".to_string(),
+            segment_bytes: 16 * 1024 * 1024,
+            max_bytes: 1024 * 1024 * 1024,
+            max_age: Duration::from_secs(24 * 3600),
+        }
+    }
+}
+
+// Segment is one spool file of length prefixed records
+struct Segment {
+    seq: u64,
+    path: PathBuf,
+    bytes: u64,
+    records: u64,
+    read: u64,
+    acked: u64,
+    modified: SystemTime,
+}
+
+struct State {
+    segments: VecDeque<Segment>,
+    next_seq: u64,
+    // appends to the last segment
+    writer: Option<File>,
+    // reads the segment of seq
+    reader: Option<(u64, BufReader<File>)>,
+    // acks of records read before the last rewind are ignored
+    generation: u64,
+    paused_until: Option<Instant>,
+    backoff: Duration,
+}
+
+impl State {
+    fn unread(&self) -> u64 {
+        self.segments.iter().map(|s| s.records - s.read).sum()
+    }
+
+    fn bytes(&self) -> u64 {
+        self.segments.iter().map(|s| s.bytes).sum()
+    }
+
+    fn segment(&mut self, seq: u64) -> Option<&mut Segment> {
+        self.segments.iter_mut().find(|s| s.seq == seq)
+    }
+}
+
+// Spool keeps the records of an output on disk while its queue is full,
+// they are drained in order and a segment is removed once all its records were acked.
+pub(crate) struct Spool {
+    channel: String,
+    dir: PathBuf,
+    cfg: SpoolConfig,
+    state: Mutex<State>,
+    // signalled when segments are removed
+    room: Condvar,
+    records: Metric,
+    bytes: Metric,
+    dropped: Metric,
+}
+
+impl Spool {
+    // open the spool of channel, segments left by a previous run are drained first
+    pub(crate) fn open(channel: &str, cfg: &SpoolConfig) -> Result<Arc<Self>> {
+        let name = channel
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+                _ => '_',
+            })
+            .collect::<String>();
+        let dir = Path::new(&cfg.dir).join(name);
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXT) {
+                continue;
+            }
+            let seq = match path.file_stem().and_then(|s| s.to_str()).map(str::parse) {
+                Some(Ok(seq)) => seq,
+                _ => continue,
+            };
+            segments.push(load_segment(seq, path)?);
+        }
+        segments.sort_by_key(|s| s.seq);
+        let next_seq = segments.last().map(|s| s.seq + 1).unwrap_or(0);
+
+        let id = crate::channel_id(channel);
+        let labels = [("output", id.as_str())];
+        let spool = Arc::new(Self {
+            channel: channel.to_string(),
+            dir,
+            cfg: cfg.clone(),
+            state: Mutex::new(State {
+                segments: segments.into_iter().collect(),
+                next_seq,
+                writer: None,
+                reader: None,
+                generation: 0,
+                paused_until: None,
+                backoff: BACKOFF,
+            }),
+            room: Condvar::new(),
+            records: metrics::gauge(
+                "harvest_output_spool_records",
+                "records kept in the spool of an output until they are acked",
+                &labels,
+            ),
+            bytes: metrics::gauge(
+                "harvest_output_spool_bytes",
+                "bytes of the segment files of an output spool",
+                &labels,
+            ),
+            dropped: metrics::counter(
+                "harvest_output_spool_dropped_records_total",
+                "records dropped from an output spool because they were too old or corrupted",
+                &labels,
+            ),
+        });
+        let state = spool.lock();
+        spool.observe(&state);
+        drop(state);
+        Ok(spool)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(it) => it,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    fn observe(&self, state: &State) {
+        self.records.set(
+            state
+                .segments
+                .iter()
+                .map(|s| s.records - s.acked)
+                .sum::<u64>() as i64,
+        );
+        self.bytes.set(state.bytes() as i64);
+    }
+
+    // queue the item when the spool is empty and the queue has room, spool it otherwise,
+    // the ack is done once the line is queued or synced to the spool, the source may
+    // then commit past it as the spool survives a crash
+    pub(crate) fn send(&self, tx: &Sender<Record>, item: Item, ack: Ack) -> Result<()> {
+        let mut state = self.lock();
+        let (mut item, mut ack) = (item, ack);
+        // lines must not overtake the spooled ones
+        if state.unread() == 0 {
+            match tx.try_send(Record::new(item, ack)) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(record)) => {
+                    item = record.item;
+                    ack = record.ack
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err(format!("output `{}` worker is gone", self.channel).into())
+                }
+            }
+        }
+        while state.bytes() >= self.cfg.max_bytes {
+            state = match self.room.wait_timeout(state, BACKOFF) {
+                Ok((it, _)) => it,
+                Err(e) => e.into_inner().0,
+            };
+        }
+        self.append(&mut state, &item.string())?;
+        self.observe(&state);
+        drop(state);
+        ack.done();
+        Ok(())
+    }
+
+    fn append(&self, state: &mut State, line: &str) -> Result<()> {
+        let sealed = match state.segments.back() {
+            Some(last) => last.bytes >= self.cfg.segment_bytes,
+            None => true,
+        };
+        if state.writer.is_none() || sealed {
+            let seq = state.next_seq;
+            let path = self.dir.join(format!("{:020}.{}", seq, SEGMENT_EXT));
+            state.writer = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+            // the entry of the new file must survive a crash as much as its records
+            File::open(&self.dir)?.sync_all()?;
+            state.segments.push_back(Segment {
+                seq,
+                path,
+                bytes: 0,
+                records: 0,
+                read: 0,
+                acked: 0,
+                modified: SystemTime::now(),
+            });
+            state.next_seq += 1;
+        }
+
+        let mut buf = Vec::with_capacity(4 + line.len());
+        buf.extend_from_slice(&(line.len() as u32).to_le_bytes());
+        buf.extend_from_slice(line.as_bytes());
+        if let Some(writer) = state.writer.as_mut() {
+            writer.write_all(&buf)?;
+            writer.sync_data()?;
+        }
+        if let Some(last) = state.segments.back_mut() {
+            last.bytes += buf.len() as u64;
+            last.records += 1;
+            last.modified = SystemTime::now();
+        }
+        Ok(())
+    }
+
+    // the oldest unread record, None while the spool is empty or paused after a failure
+    pub(crate) fn next(self: &Arc<Self>) -> Option<Record> {
+        let mut state = self.lock();
+        if let Some(until) = state.paused_until {
+            if Instant::now() < until {
+                return None;
+            }
+            state.paused_until = None;
+        }
+        loop {
+            let seq = state.segments.iter().find(|s| s.read < s.records)?.seq;
+            let line = match self.read(&mut state, seq) {
+                Ok(it) => it,
+                Err(e) => {
+                    // the rest of a corrupted segment can not be framed again
+                    eprintln!(
+                        "output `{}` spool segment {} error: {:?}",
+                        self.channel, seq, e
+                    );
+                    if let Some(segment) = state.segment(seq) {
+                        let lost = segment.records - segment.read;
+                        segment.read = segment.records;
+                        segment.acked += lost;
+                        self.dropped.add(lost as i64);
+                    }
+                    state.reader = None;
+                    self.remove_acked(&mut state);
+                    continue;
+                }
+            };
+            if let Some(segment) = state.segment(seq) {
+                segment.read += 1;
+            }
+
+            let generation = state.generation;
+            let spool = self.clone();
+            let ack = Ack::new(move |ok| spool.settle(generation, seq, ok));
+            return Some(Record::new(Item::from(line.as_str()), ack));
+        }
+    }
+
+    fn read(&self, state: &mut State, seq: u64) -> Result<String> {
+        let (read, path) = match state.segment(seq) {
+            Some(segment) => (segment.read, segment.path.clone()),
+            None => return Err("segment is gone".into()),
+        };
+        if !matches!(&state.reader, Some((reading, _)) if *reading == seq) {
+            let mut reader = BufReader::new(File::open(&path)?);
+            // skip the records read before a rewind or restart
+            for _ in 0..read {
+                read_record(&mut reader)?;
+            }
+            state.reader = Some((seq, reader));
+        }
+        match state.reader.as_mut() {
+            Some((_, reader)) => read_record(reader),
+            None => Err("segment is not open".into()),
+        }
+    }
+
+    fn settle(&self, generation: u64, seq: u64, ok: bool) {
+        let mut state = self.lock();
+        if generation != state.generation {
+            return;
+        }
+        if !ok {
+            // read everything not acked again once the pause is over
+            state.generation += 1;
+            state.reader = None;
+            for segment in state.segments.iter_mut() {
+                segment.read = segment.acked;
+            }
+            state.paused_until = Some(Instant::now() + state.backoff);
+            state.backoff = cmp::min(state.backoff * 2, MAX_BACKOFF);
+            return;
+        }
+        state.backoff = BACKOFF;
+        if let Some(segment) = state.segment(seq) {
+            segment.acked += 1;
+        }
+        self.remove_acked(&mut state);
+        self.observe(&state);
+    }
+
+    // remove the leading segments whose records were all acked
+    fn remove_acked(&self, state: &mut State) {
+        let mut removed = false;
+        while let Some(front) = state.segments.front() {
+            if front.acked < front.records {
+                break;
+            }
+            self.remove_front(state);
+            removed = true;
+        }
+        if removed {
+            self.room.notify_all();
+        }
+    }
+
+    fn remove_front(&self, state: &mut State) {
+        let segment = match state.segments.pop_front() {
+            Some(it) => it,
+            None => return,
+        };
+        if state.segments.is_empty() {
+            state.writer = None;
+        }
+        if matches!(&state.reader, Some((seq, _)) if *seq == segment.seq) {
+            state.reader = None;
+        }
+        if let Err(e) = fs::remove_file(&segment.path) {
+            eprintln!(
+                "output `{}` remove spool segment error: {:?}",
+                self.channel, e
+            );
+        }
+    }
+
+    // drop the segments that were not written for max_age
+    pub(crate) fn expire(&self) {
+        let mut state = self.lock();
+        let now = SystemTime::now();
+        let mut removed = false;
+        while let Some(front) = state.segments.front() {
+            match now.duration_since(front.modified) {
+                Ok(age) if age >= self.cfg.max_age => {}
+                _ => break,
+            }
+            let lost = front.records - front.acked;
+            if lost > 0 {
+                eprintln!(
+                    "output `{}` spool dropped {} undelivered records of segment {} older than {:?}",
+                    self.channel, lost, front.seq, self.cfg.max_age
+                );
+            }
+            self.dropped.add(lost as i64);
+            self.remove_front(&mut state);
+            removed = true;
+        }
+        if removed {
+            // acks of the dropped records must not count for the next segments
+            state.generation += 1;
+            for segment in state.segments.iter_mut() {
+                segment.read = segment.acked;
+            }
+            state.reader = None;
+            self.observe(&state);
+            self.room.notify_all();
+        }
+    }
+
+    pub(crate) fn depth(&self) -> (u64, u64, usize) {
+        let state = self.lock();
+        (
+            state.segments.iter().map(|s| s.records - s.acked).sum(),
+            state.bytes(),
+            state.segments.len(),
+        )
+    }
+}
+
+fn read_record<R: Read>(reader: &mut R) -> Result<String> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+// count the records of a segment left by a previous run, a torn last record is cut off
+fn load_segment(seq: u64, path: PathBuf) -> Result<Segment> {
+    let file = File::open(&path)?;
+    let metadata = file.metadata()?;
+    let mut reader = BufReader::new(file);
+    let (mut records, mut bytes) = (0, 0);
+    while let Ok(line) = read_record(&mut reader) {
+        records += 1;
+        bytes += 4 + line.len() as u64;
+    }
+    if bytes < metadata.len() {
+        OpenOptions::new().write(true).open(&path)?.set_len(bytes)?;
+    }
+    Ok(Segment {
+        seq,
+        path,
+        bytes,
+        records,
+        read: 0,
+        acked: 0,
+        modified: metadata.modified()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Spool, SpoolConfig};
+    use crate::{Ack, Record};
+    use common::{metrics, Item};
+    use crossbeam_channel::bounded;
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn config(name: &str) -> SpoolConfig {
+        let dir = std::env::temp_dir().join("harvest-spool").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        SpoolConfig {
+            dir: dir.to_str().unwrap().to_string(),
+            segment_bytes: 16,
+            ..Default::default()
+        }
+    }
+
+    fn drain(spool: &Arc<Spool>) -> Vec<Record> {
+        let mut records = vec![];
+        while let Some(record) = spool.next() {
+            records.push(record);
+        }
+        records
+    }
+
+    #[test]
+    fn it_spools_when_queue_is_full() {
+        let cfg = config("full");
+        let spool = Spool::open("kafka:test@127.0.0.1:9092", &cfg).unwrap();
+        let (tx, rx) = bounded::<Record>(1);
+
+        for line in &["a", "b", "c", "d"] {
+            spool.send(&tx, Item::from(*line), Ack::none()).unwrap();
+        }
+        assert_eq!(rx.try_recv().unwrap().item.string(), "a");
+        // the queue has room again but the spooled lines come first
+        spool.send(&tx, Item::from("e"), Ack::none()).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        let records = drain(&spool);
+        let lines = records
+            .iter()
+            .map(|r| r.item.string())
+            .collect::<Vec<String>>();
+        assert_eq!(lines, vec!["b", "c", "d", "e"]);
+        assert_eq!(spool.depth().0, 4);
+
+        records.into_iter().for_each(|r| r.ack.done());
+        assert_eq!(spool.depth(), (0, 0, 0));
+        spool.send(&tx, Item::from("f"), Ack::none()).unwrap();
+        assert_eq!(rx.try_recv().unwrap().item.string(), "f");
+    }
+
+    #[test]
+    fn it_rewinds_failed_records() {
+        let cfg = config("rewind");
+        let spool = Spool::open("rewind", &cfg).unwrap();
+        let (tx, _rx) = bounded::<Record>(0);
+        for line in &["a", "b", "c"] {
+            spool.send(&tx, Item::from(*line), Ack::none()).unwrap();
+        }
+
+        let mut records = drain(&spool).into_iter();
+        records.next().unwrap().ack.done();
+        drop(records);
+        assert!(spool.next().is_none());
+
+        spool.lock().paused_until = None;
+        let lines = drain(&spool)
+            .iter()
+            .map(|r| r.item.string())
+            .collect::<Vec<String>>();
+        assert_eq!(lines, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn it_counts_expired_records() {
+        let mut cfg = config("expire");
+        cfg.max_age = Duration::from_secs(0);
+        let spool = Spool::open("expire", &cfg).unwrap();
+        let (tx, _rx) = bounded::<Record>(0);
+        for line in &["a", "b"] {
+            spool.send(&tx, Item::from(*line), Ack::none()).unwrap();
+        }
+        // a delivered record is not counted
+        spool.next().unwrap().ack.done();
+
+        spool.expire();
+        assert_eq!(spool.depth(), (0, 0, 0));
+        let dropped = metrics::snapshot()
+            .into_iter()
+            .find(|s| {
+                s.name == "harvest_output_spool_dropped_records_total"
+                    && s.labels.iter().any(|(k, v)| k == "output" && v == "expire")
+            })
+            .map(|s| s.value);
+        assert_eq!(dropped, Some(1));
+    }
+
+    #[test]
+    fn it_replays_segments_after_restart() {
+        let cfg = config("restart");
+        let spool = Spool::open("restart", &cfg).unwrap();
+        let (tx, _rx) = bounded::<Record>(0);
+        for line in &["0123456789", "b", "c"] {
+            spool.send(&tx, Item::from(*line), Ack::none()).unwrap();
+        }
+        assert_eq!(spool.depth().2, 2);
+        drop(spool);
+
+        let spool = Spool::open("restart", &cfg).unwrap();
+        let lines = drain(&spool)
+            .iter()
+            .map(|r| r.item.string())
+            .collect::<Vec<String>>();
+        assert_eq!(lines, vec!["0123456789", "b", "c"]);
+    }
+}
@@ -0,0 +1,490 @@
+use super::{fields, Ack, IOutput, Item, Record, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use std::convert::TryFrom;
+use std::io::{BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+// the private enterprise number of the structured data, 32473 is reserved for documentation
+const SD_ID: &str = "harvest@32473";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Transport {
+    Udp,
+    // octet counting framing of rfc 6587
+    Tcp,
+    // octet counting framing over tls of rfc 5425
+    Tls,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Format {
+    Rfc5424,
+    // the legacy bsd format
+    Rfc3164,
+}
+
+#[derive(Clone, Debug)]
+struct SyslogOutputConfig {
+    host: String,
+    port: u16,
+    transport: Transport,
+    format: Format,
+    facility: u8,
+    // a udp message is cut to max_size bytes
+    max_size: usize,
+    timeout: Duration,
+    // pem file of the authorities trusted for tls, the bundled web roots when empty
+    ca: String,
+    // pem files of the client certificate and key when the collector asks for one
+    cert: String,
+    key: String,
+}
+
+fn facility(name: &str) -> Option<u8> {
+    let code = match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        _ => match name.strip_prefix("local")?.parse::<u8>().ok()? {
+            n @ 0..=7 => 16 + n,
+            _ => return None,
+        },
+    };
+    Some(code)
+}
+
+// printable ascii only, cut to max characters, - when nothing is left
+fn header_field(value: Option<String>, max: usize) -> String {
+    let value = value
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| ('!'..='~').contains(c))
+        .take(max)
+        .collect::<String>();
+    if value.is_empty() {
+        return "-".to_string();
+    }
+    value
+}
+
+fn escape_param(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(BufWriter<TcpStream>),
+    Tls(Box<BufWriter<StreamOwned<ClientConnection, TcpStream>>>),
+}
+
+// SyslogOutput forwards the records of one channel to a syslog collector
+pub(crate) struct SyslogOutput {
+    cfg: SyslogOutputConfig,
+    tls: Option<Arc<ClientConfig>>,
+    conn: Option<Connection>,
+    // acks of the messages buffered on the stream since the last flush
+    pending: Vec<Ack>,
+}
+
+impl SyslogOutput {
+    pub fn new(channel: &str) -> Result<Self> {
+        let cfg = Self::parse_uri(channel)?;
+        let tls = match cfg.transport {
+            Transport::Tls => Some(Arc::new(Self::tls_config(&cfg)?)),
+            _ => None,
+        };
+        Ok(Self {
+            cfg,
+            tls,
+            conn: None,
+            pending: vec![],
+        })
+    }
+
+    // channel = syslog://collector:6514?transport=tls&format=rfc5424&facility=local0&max_size=2048&timeout_ms=10000&ca=/etc/harvest/ca.pem&cert=&key=
+    fn parse_uri(channel: &str) -> Result<SyslogOutputConfig> {
+        let rest = match channel.strip_prefix("syslog://") {
+            Some(rest) => rest,
+            None => return Err(format!("channel `{}` is not a syslog channel", channel).into()),
+        };
+        let (addr, options) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut cfg = SyslogOutputConfig {
+            host: "".to_string(),
+            port: 0,
+            transport: Transport::Udp,
+            format: Format::Rfc5424,
+            facility: 1,
+            max_size: 2048,
+            timeout: Duration::from_secs(10),
+            ca: "".to_string(),
+            cert: "".to_string(),
+            key: "".to_string(),
+        };
+        for option in options.split('&').filter(|option| !option.is_empty()) {
+            let (name, value) = option.split_once('=').unwrap_or((option, ""));
+            match name {
+                "transport" => {
+                    cfg.transport = match value {
+                        "udp" => Transport::Udp,
+                        "tcp" => Transport::Tcp,
+                        "tls" => Transport::Tls,
+                        _ => {
+                            return Err(format!(
+                                "syslog transport `{}` is not supported, expect udp, tcp or tls",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "format" => {
+                    cfg.format = match value {
+                        "rfc5424" => Format::Rfc5424,
+                        "rfc3164" => Format::Rfc3164,
+                        _ => {
+                            return Err(format!(
+                                "syslog format `{}` is not supported, expect rfc5424 or rfc3164",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "facility" => {
+                    cfg.facility = match facility(value) {
+                        Some(code) => code,
+                        None => return Err(format!("unknown syslog facility `{}`", value).into()),
+                    }
+                }
+                "max_size" => cfg.max_size = value.parse::<usize>()?,
+                "timeout_ms" => cfg.timeout = Duration::from_millis(value.parse::<u64>()?),
+                "ca" => cfg.ca = value.to_string(),
+                "cert" => cfg.cert = value.to_string(),
+                "key" => cfg.key = value.to_string(),
+                _ => return Err(format!("unknown syslog channel option `{}`", name).into()),
+            }
+        }
+
+        let default_port = match cfg.transport {
+            Transport::Tls => 6514,
+            _ => 514,
+        };
+        let (host, port) = match addr.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse::<u16>()?),
+            _ => (addr, default_port),
+        };
+        if host.is_empty() {
+            return Err(format!("syslog channel `{}` has no host", channel).into());
+        }
+        cfg.host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        cfg.port = port;
+        if cfg.cert.is_empty() != cfg.key.is_empty() {
+            return Err("syslog client certificate expects both cert and key".into());
+        }
+        Ok(cfg)
+    }
+
+    fn tls_config(cfg: &SyslogOutputConfig) -> Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        if cfg.ca.is_empty() {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        } else {
+            for cert in CertificateDer::pem_file_iter(&cfg.ca)? {
+                roots.add(cert?)?;
+            }
+        }
+        let builder =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots);
+        if cfg.cert.is_empty() {
+            return Ok(builder.with_no_client_auth());
+        }
+        let certs = CertificateDer::pem_file_iter(&cfg.cert)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(&cfg.key)?;
+        Ok(builder.with_client_auth_cert(certs, key)?)
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        let addr = (self.cfg.host.as_str(), self.cfg.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("syslog host `{}` has no address", self.cfg.host))?;
+        if self.cfg.transport == Transport::Udp {
+            let bind = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = UdpSocket::bind(bind)?;
+            socket.connect(addr)?;
+            return Ok(Connection::Udp(socket));
+        }
+
+        let stream = TcpStream::connect_timeout(&addr, self.cfg.timeout)?;
+        stream.set_write_timeout(Some(self.cfg.timeout))?;
+        match &self.tls {
+            Some(tls) => {
+                let name = ServerName::try_from(self.cfg.host.clone())?;
+                let conn = ClientConnection::new(tls.clone(), name)?;
+                let stream = StreamOwned::new(conn, stream);
+                Ok(Connection::Tls(Box::new(BufWriter::new(stream))))
+            }
+            None => Ok(Connection::Tcp(BufWriter::new(stream))),
+        }
+    }
+
+    // stdout is informational, stderr is an error
+    fn severity(item: &Item) -> u8 {
+        match fields::lookup(item, "stream").as_deref() {
+            Some("stderr") => 3,
+            _ => 6,
+        }
+    }
+
+    fn format(&self, item: &Item) -> String {
+        let pri = self.cfg.facility * 8 + Self::severity(item);
+        let time = fields::lookup(item, "time")
+            .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+            .map(|time| time.with_timezone(&Utc))
+            .unwrap_or_else(Utc::now);
+        let message = match item {
+            Item::JSON(_) => fields::lookup(item, "message").unwrap_or_else(|| item.string()),
+            Item::Default(message) => message.clone(),
+        };
+        let message = message.trim_end_matches(['\n', '\r']);
+        let hostname = header_field(fields::lookup(item, "node"), 255);
+
+        match self.cfg.format {
+            Format::Rfc5424 => {
+                let params = ["namespace", "pod", "container"]
+                    .iter()
+                    .filter_map(|name| {
+                        let value = fields::lookup(item, name)?;
+                        Some(format!(" {}=\"{}\"", name, escape_param(&value)))
+                    })
+                    .collect::<String>();
+                let sd = match params.is_empty() {
+                    true => "-".to_string(),
+                    false => format!("[{}{}]", SD_ID, params),
+                };
+                format!(
+                    "<{}>1 {} {} {} - - {} {}",
+                    pri,
+                    time.to_rfc3339_opts(SecondsFormat::Micros, true),
+                    hostname,
+                    header_field(fields::lookup(item, "service"), 48),
+                    sd,
+                    message
+                )
+            }
+            Format::Rfc3164 => {
+                let tag = fields::lookup(item, "service")
+                    .unwrap_or_default()
+                    .chars()
+                    .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_' || *c == '.')
+                    .take(32)
+                    .collect::<String>();
+                let tag = match tag.is_empty() {
+                    true => "harvest".to_string(),
+                    false => tag,
+                };
+                format!(
+                    "<{}>{} {} {}: {}",
+                    pri,
+                    time.format("%b %e %H:%M:%S"),
+                    hostname,
+                    tag,
+                    message
+                )
+            }
+        }
+    }
+
+    fn send(&mut self, message: &str) -> Result<()> {
+        if self.conn.is_none() {
+            self.conn = Some(self.connect()?);
+        }
+        let max_size = self.cfg.max_size;
+        let sent = match self.conn.as_mut() {
+            Some(Connection::Udp(socket)) => {
+                let mut end = std::cmp::min(message.len(), max_size);
+                while !message.is_char_boundary(end) {
+                    end -= 1;
+                }
+                socket.send(&message.as_bytes()[..end]).map(|_| ())
+            }
+            Some(Connection::Tcp(writer)) => write!(writer, "{} {}", message.len(), message),
+            Some(Connection::Tls(writer)) => write!(writer, "{} {}", message.len(), message),
+            None => Ok(()),
+        };
+        if let Err(e) = sent {
+            self.reset();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    // drop the connection, the buffered messages are lost and their acks fail
+    fn reset(&mut self) {
+        self.conn = None;
+        self.pending.clear();
+    }
+}
+
+impl IOutput for SyslogOutput {
+    fn write(&mut self, channel: &str, item: Item) -> Result<()> {
+        self.deliver(channel, Record::new(item, Ack::none()))
+    }
+
+    fn deliver(&mut self, _: &str, record: Record) -> Result<()> {
+        let Record { item, ack } = record;
+        let message = self.format(&item);
+        self.send(&message)?;
+        match self.conn {
+            // a datagram is never confirmed, it is delivered once sent
+            Some(Connection::Udp(_)) => ack.done(),
+            _ => self.pending.push(ack),
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, _: &str) -> Result<()> {
+        let flushed = match self.conn.as_mut() {
+            Some(Connection::Tcp(writer)) => writer.flush(),
+            Some(Connection::Tls(writer)) => writer.flush(),
+            _ => Ok(()),
+        };
+        if let Err(e) = flushed {
+            self.reset();
+            return Err(e.into());
+        }
+        for ack in self.pending.drain(..) {
+            ack.done();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, SyslogOutput, Transport};
+    use crate::{Ack, IOutput, Record};
+    use common::Item;
+    use std::io::Read;
+    use std::net::{TcpListener, UdpSocket};
+    use std::sync::{Arc, Mutex};
+
+    const LINE: &str = r#"{"custom":{"namespace":"ns","nodeId":"pod-0","container":"app","serviceName":"pay","nodeName":"node-1"},"stream":"stderr","time":"2021-03-16T23:05:01.461813069-02:00","message":"say \"hi\"\n"}"#;
+
+    #[test]
+    fn it_parses_channel() {
+        let cfg = SyslogOutput::parse_uri(
+            "syslog://collector?transport=tls&format=rfc3164&facility=local3",
+        )
+        .unwrap();
+        assert_eq!(cfg.host, "collector");
+        assert_eq!(cfg.port, 6514);
+        assert_eq!(cfg.transport, Transport::Tls);
+        assert_eq!(cfg.format, Format::Rfc3164);
+        assert_eq!(cfg.facility, 19);
+        assert!(SyslogOutput::new("syslog://collector?transport=tls").is_ok());
+
+        let cfg = SyslogOutput::parse_uri("syslog://[::1]:1514").unwrap();
+        assert_eq!((cfg.host.as_str(), cfg.port), ("::1", 1514));
+        assert_eq!(cfg.transport, Transport::Udp);
+
+        assert!(SyslogOutput::parse_uri("syslog://:514").is_err());
+        assert!(SyslogOutput::parse_uri("syslog://collector?transport=quic").is_err());
+        assert!(SyslogOutput::parse_uri("syslog://collector?facility=local8").is_err());
+        assert!(SyslogOutput::parse_uri("syslog://collector?cert=/tmp/cert.pem").is_err());
+    }
+
+    #[test]
+    fn it_formats_messages() {
+        let output = SyslogOutput::new("syslog://collector?facility=local0").unwrap();
+        assert_eq!(
+            output.format(&Item::from(LINE)),
+            r#"<131>1 2021-03-17T01:05:01.461813Z node-1 pay - - [harvest@32473 namespace="ns" pod="pod-0" container="app"] say "hi""#
+        );
+        assert_eq!(
+            output.format(&Item::from("raw")).split(' ').nth(6),
+            Some("-")
+        );
+
+        let output = SyslogOutput::new("syslog://collector?format=rfc3164").unwrap();
+        assert_eq!(
+            output.format(&Item::from(LINE)),
+            r#"<11>Mar 17 01:05:01 node-1 pay: say "hi""#
+        );
+    }
+
+    #[test]
+    fn it_frames_tcp_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut output = SyslogOutput::new(&format!("syslog://{}?transport=tcp", addr)).unwrap();
+
+        let acked = Arc::new(Mutex::new(vec![]));
+        for line in &["a", "bc"] {
+            let acked = acked.clone();
+            let ack = Ack::new(move |ok| acked.lock().unwrap().push(ok));
+            output
+                .deliver("syslog", Record::new(Item::from(*line), ack))
+                .unwrap();
+        }
+        assert!(acked.lock().unwrap().is_empty());
+        output.flush("syslog").unwrap();
+        assert_eq!(*acked.lock().unwrap(), vec![true, true]);
+        drop(output);
+
+        let mut received = String::new();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.read_to_string(&mut received).unwrap();
+        let mut frames = vec![];
+        let mut rest = received.as_str();
+        while let Some((len, tail)) = rest.split_once(' ') {
+            let len = len.parse::<usize>().unwrap();
+            frames.push(&tail[..len]);
+            rest = &tail[len..];
+        }
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].starts_with("<14>1 ") && frames[0].ends_with(" - - - - - a"));
+        assert!(frames[1].ends_with(" - - - - - bc"));
+    }
+
+    #[test]
+    fn it_sends_udp_datagrams() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut output = SyslogOutput::new(&format!("syslog://{}?max_size=20", addr)).unwrap();
+        output.write("syslog", Item::from("a long line")).unwrap();
+
+        let mut datagram = [0; 64];
+        let len = socket.recv(&mut datagram).unwrap();
+        assert_eq!(len, 20);
+        assert!(datagram.starts_with(b"<14>1 "));
+    }
+}
@@ -0,0 +1,69 @@
+use crossbeam_channel::{unbounded, Receiver};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+// Request is what the stand-in received
+pub(crate) struct Request {
+    pub(crate) head: String,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<String> {
+        self.head.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.eq_ignore_ascii_case(name) {
+                return Some(value.trim().to_string());
+            }
+            None
+        })
+    }
+
+    pub(crate) fn body(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+// serve answers one connection per response with the given status and body,
+// it returns the address to send to and the received requests
+pub(crate) fn serve(responses: Vec<(u16, String)>) -> (String, Receiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = unbounded();
+    thread::spawn(move || {
+        for (status, body) in responses {
+            let (stream, _) = match listener.accept() {
+                Ok(it) => it,
+                Err(_) => return,
+            };
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let request = Request { head, body: vec![] };
+            let len = request
+                .header("content-length")
+                .and_then(|len| len.parse::<usize>().ok())
+                .unwrap_or(0);
+            let mut request = request;
+            request.body = vec![0; len];
+            let _ = reader.read_exact(&mut request.body);
+
+            let response = format!(
+                "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = reader.get_mut().write_all(response.as_bytes());
+            let _ = tx.send(request);
+        }
+    });
+    (addr, rx)
+}
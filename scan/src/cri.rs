@@ -0,0 +1,62 @@
+use std::path::Path;
+
+// CriLogPath is the pod identity encoded in a kubelet managed log path:
+// /var/log/pods/<namespace>_<pod_name>_<pod_uid>/<container_name>/<restart_count>.log
+#[derive(Debug, Clone, PartialEq)]
+pub struct CriLogPath {
+    pub ns: String,
+    pub pod_name: String,
+    pub pod_uid: String,
+    pub container_name: String,
+}
+
+impl CriLogPath {
+    pub fn parse(path: &str) -> Option<Self> {
+        let path = Path::new(path);
+        if path.extension()? != "log" {
+            return None;
+        }
+        let container_dir = path.parent()?;
+        let container_name = container_dir.file_name()?.to_str()?;
+        let pod_dir = container_dir.parent()?.file_name()?.to_str()?;
+
+        // namespace and pod names are DNS labels, they can not contain `_`
+        let parts = pod_dir.splitn(3, '_').collect::<Vec<&str>>();
+        if parts.len() != 3 || parts.iter().any(|part| part.is_empty()) {
+            return None;
+        }
+
+        Some(Self {
+            ns: parts[0].to_string(),
+            pod_name: parts[1].to_string(),
+            pod_uid: parts[2].to_string(),
+            container_name: container_name.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CriLogPath;
+
+    #[test]
+    fn it_works() {
+        let cri_path = CriLogPath::parse(
+            "/var/log/pods/finance-dev_sky-fcms-web-ui-0-b-0_c7621e69-de2b-4a5c-b439-6e3021dba432/web/0.log",
+        )
+        .unwrap();
+        assert_eq!(cri_path.ns, "finance-dev");
+        assert_eq!(cri_path.pod_name, "sky-fcms-web-ui-0-b-0");
+        assert_eq!(cri_path.pod_uid, "c7621e69-de2b-4a5c-b439-6e3021dba432");
+        assert_eq!(cri_path.container_name, "web");
+    }
+
+    #[test]
+    fn it_rejects_other_paths() {
+        assert_eq!(CriLogPath::parse("/var/log/pods/web/0.log"), None);
+        assert_eq!(
+            CriLogPath::parse("/var/log/pods/ns_pod_uid/web/0.log.20210316-090501"),
+            None
+        );
+    }
+}
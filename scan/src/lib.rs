@@ -1,4 +1,5 @@
-use common::Result;
+use common::metrics::{self, Metric};
+use common::{Result, Runtime};
 use db::Pod;
 use event::{Dispatch, Listener};
 use notify::{raw_watcher, RawEvent, RecursiveMode, Watcher};
@@ -9,7 +10,9 @@ use std::sync::{Arc, RwLock};
 use strum::AsRefStr;
 use walkdir::WalkDir;
 mod config_v2;
+mod cri;
 use config_v2::JSONConfig;
+pub use cri::CriLogPath;
 
 #[derive(Debug, AsRefStr, Clone)]
 pub enum PathEvent {
@@ -100,12 +103,24 @@ type Cache = Arc<Vec<RwLock<HashMap<String, Option<JSONConfig>>>>>;
 pub struct AutoScanner {
     namespace: String,
     docker_dir: String,
+    runtime: Runtime,
     event_dispatch: Dispatch<PathEventInfo>,
     cache: Cache,
+    // dispatched create, write and remove events
+    events: [Metric; 3],
+    discovered: Metric,
+}
+
+fn event_counter(event: PathEvent) -> Metric {
+    metrics::counter(
+        "harvest_scanner_events_total",
+        "log file events dispatched by the scanner",
+        &[("event", event.as_ref())],
+    )
 }
 
 impl AutoScanner {
-    pub fn new(namespace: String, docker_dir: String) -> Self {
+    pub fn new(namespace: String, docker_dir: String, runtime: Runtime) -> Self {
         let len = 2;
         let mut cache: Vec<RwLock<HashMap<String, Option<JSONConfig>>>> = Vec::with_capacity(len);
         for _i in 0..len {
@@ -114,8 +129,19 @@ impl AutoScanner {
         Self {
             namespace,
             docker_dir,
+            runtime,
             event_dispatch: Dispatch::<PathEventInfo>::new(),
             cache: Arc::new(cache),
+            events: [
+                event_counter(PathEvent::Create),
+                event_counter(PathEvent::Write),
+                event_counter(PathEvent::Remove),
+            ],
+            discovered: metrics::counter(
+                "harvest_scanner_discovered_files_total",
+                "log files found by the scan at start",
+                &[],
+            ),
         }
     }
 
@@ -204,15 +230,18 @@ impl AutoScanner {
     }
 
     fn dispatch_create_event(&mut self, pei: &PathEventInfo) {
+        self.events[0].inc();
         self.event_dispatch
             .dispatch(PathEvent::Create.as_ref(), pei)
     }
 
     fn dispatch_write_event(&mut self, pei: &PathEventInfo) {
+        self.events[1].inc();
         self.event_dispatch.dispatch(PathEvent::Write.as_ref(), pei)
     }
 
     fn dispatch_close_event(&mut self, pei: &PathEventInfo) {
+        self.events[2].inc();
         self.event_dispatch
             .dispatch(PathEvent::Remove.as_ref(), pei)
     }
@@ -234,12 +263,64 @@ impl AutoScanner {
         }
     }
 
+    // the pod of a log file, docker logs are looked up in the config.v2.json cache
+    // and CRI logs carry the pod in their path
+    fn log_pei(&self, path: &str) -> Option<PathEventInfo> {
+        match self.runtime {
+            Runtime::Docker => self.get(path).map(|cfg| {
+                Self::config_to_pei(
+                    &cfg.get_service_name(),
+                    &cfg.get_ns(),
+                    &cfg.get_pod_name(),
+                    &cfg.get_container_name(),
+                    &cfg.log_path,
+                )
+            }),
+            Runtime::Cri => {
+                let cri_path = CriLogPath::parse(path)?;
+                if cri_path.ns != self.namespace {
+                    return None;
+                }
+                Some(Self::config_to_pei(
+                    "",
+                    &cri_path.ns,
+                    &cri_path.pod_name,
+                    &cri_path.container_name,
+                    path,
+                ))
+            }
+        }
+    }
+
     fn insert_config_file(&self, path: &str) {
         let _j_s_o_n_config = JSONConfig::from(path);
         self.insert(&_j_s_o_n_config.log_path.clone(), _j_s_o_n_config);
     }
 
     pub fn prepare(&self) -> Result<Vec<PathEventInfo>> {
+        let prepared = match self.runtime {
+            Runtime::Docker => self.prepare_docker(),
+            Runtime::Cri => self.prepare_cri(),
+        }?;
+        self.discovered.add(prepared.len() as i64);
+        Ok(prepared)
+    }
+
+    fn prepare_cri(&self) -> Result<Vec<PathEventInfo>> {
+        let mut result = vec![];
+        for entry in WalkDir::new(self.docker_dir.clone()) {
+            let entry = entry?;
+            let path = entry.path().to_str().unwrap();
+            if let DockerConfigFileType::Log = docker_config_file_type(path) {
+                if let Some(pei) = self.log_pei(path) {
+                    result.push(pei);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn prepare_docker(&self) -> Result<Vec<PathEventInfo>> {
         let mut result = vec![];
         for entry in WalkDir::new(self.docker_dir.clone()) {
             let entry = entry?;
@@ -290,19 +371,11 @@ impl AutoScanner {
             match op {
                 notify::Op::CREATE => match docker_config_file_type(path) {
                     DockerConfigFileType::ConfigV2 => self.insert(path, JSONConfig::from(path)),
-                    DockerConfigFileType::Log => match self.get(path) {
-                        Some(cfg) => {
-                            let pei = Self::config_to_pei(
-                                &cfg.get_service_name(),
-                                &cfg.get_ns(),
-                                &cfg.get_pod_name(),
-                                &cfg.get_container_name(),
-                                &cfg.log_path,
-                            );
+                    DockerConfigFileType::Log => {
+                        if let Some(pei) = self.log_pei(path) {
                             self.dispatch_create_event(&pei)
                         }
-                        _ => {}
-                    },
+                    }
                     _ => continue,
                 },
                 notify::Op::WRITE => match docker_config_file_type(path) {
@@ -313,6 +386,14 @@ impl AutoScanner {
                     }),
                     _ => continue,
                 },
+                // docker rotates `*-json.log` by renaming it, let the reader drain it
+                notify::Op::RENAME => match docker_config_file_type(path) {
+                    DockerConfigFileType::Log => self.dispatch_write_event(&PathEventInfo {
+                        path: path.to_string(),
+                        ..Default::default()
+                    }),
+                    _ => continue,
+                },
                 notify::Op::REMOVE => match docker_config_file_type(path) {
                     DockerConfigFileType::ConfigV2 => self.remove(path),
                     DockerConfigFileType::Log => self.dispatch_write_event(&PathEventInfo {
@@ -327,20 +408,12 @@ impl AutoScanner {
                             DockerConfigFileType::ConfigV2 => {
                                 self.insert(path, JSONConfig::from(path))
                             }
-                            DockerConfigFileType::Log => match self.get(path) {
-                                Some(cfg) => {
-                                    let pei = Self::config_to_pei(
-                                        &cfg.get_service_name(),
-                                        &cfg.get_ns(),
-                                        &cfg.get_pod_name(),
-                                        &cfg.get_container_name(),
-                                        &cfg.log_path,
-                                    );
+                            DockerConfigFileType::Log => {
+                                if let Some(pei) = self.log_pei(path) {
                                     self.dispatch_create_event(&pei);
                                     self.dispatch_write_event(&pei)
                                 }
-                                _ => {}
-                            },
+                            }
                             _ => continue,
                         }
                         continue;
@@ -373,11 +446,12 @@ impl AutoScanner {
 #[cfg(test)]
 mod tests {
     use crate::{AutoScanner, GetDebug, PathEvent};
+    use common::Runtime;
     use event::Listener;
 
     #[test]
     fn it_works() {
-        let mut auto_scanner = AutoScanner::new("".into(), ".".into());
+        let mut auto_scanner = AutoScanner::new("".into(), ".".into(), Runtime::Docker);
 
         struct ListenerImpl;
         impl<T> Listener<T> for ListenerImpl
@@ -391,6 +465,27 @@ mod tests {
         auto_scanner.append_close_event_handle(ListenerImpl);
     }
 
+    #[test]
+    fn it_prepares_cri_logs() {
+        let dir = std::env::temp_dir().join("harvest-scan-cri");
+        let _ = std::fs::remove_dir_all(&dir);
+        for pod_dir in ["finance-dev_web-0_uid0", "default_web-1_uid1"].iter() {
+            let container_dir = dir.join(pod_dir).join("web");
+            std::fs::create_dir_all(&container_dir).unwrap();
+            std::fs::write(container_dir.join("0.log"), "").unwrap();
+        }
+
+        let auto_scanner = AutoScanner::new(
+            "finance-dev".into(),
+            dir.to_str().unwrap().into(),
+            Runtime::Cri,
+        );
+        let result = auto_scanner.prepare().unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].pod_name, "web-0");
+        assert_eq!(result[0].container_name, "web");
+    }
+
     #[test]
     fn event_it_works() {
         assert_eq!(PathEvent::Remove.as_ref(), "NeedClose");
@@ -1,49 +1,205 @@
-use super::{run_task, stop_task, tasks_json, Task};
+use super::sse::{self, Parser};
+use super::{reconcile_tasks, reject_task, run_task, stop_task, tasks_json, Task};
+use common::{health, Result};
 use rocket::get;
+use rocket::http::Status;
+use rocket::response::content::Plain;
+use rocket::response::status::Custom;
 use rocket_contrib::json::JsonValue;
 use serde::{Deserialize, Serialize};
-use sse_client::EventSource;
+use std::cmp;
+use std::fs;
+use std::io::{BufRead, ErrorKind};
+use std::thread;
+use std::time::Duration;
 
 const RUN: &'static str = "run";
 const STOP: &'static str = "stop";
 
-pub(crate) fn recv_tasks(addr: &str, node_name: &str) {
-    let event_sources = match EventSource::new(addr) {
+// the first wait before reconnecting to the api server, doubled while it fails
+const BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// the loops /healthz and /readyz expect, the outputs are checked once registered,
+// api_server is the source of the tasks, the event stream or the desired tasks
+const COMPONENTS: [&str; 4] = ["scanner", "db", "tasks", "api_server"];
+
+// recv_tasks follows the task stream of the api server for as long as the agent runs,
+// a broken or silent stream is reconnected with backoff and resumed after its last event,
+// and the tasks of the node are resynced from tasks_url, when given, after every connect
+pub(crate) fn recv_tasks(addr: &str, node_name: &str, heartbeat: Duration, tasks_url: &str) {
+    let probe = health::Probe::new("api_server");
+    let mut parser = Parser::default();
+    let mut backoff = BACKOFF;
+    loop {
+        probe.unready("connecting");
+        match sse::connect(addr, parser.last_event_id.as_deref(), heartbeat) {
+            Ok(reader) => {
+                probe.ready();
+                if !tasks_url.is_empty() {
+                    if let Err(e) = resync(tasks_url, node_name, heartbeat) {
+                        eprintln!("resync tasks from {} error: {}", tasks_url, e);
+                    }
+                }
+                let (lines, e) = follow(reader, &mut parser, node_name);
+                // a stream that delivered anything was healthy, start over with the first backoff
+                if lines > 0 {
+                    backoff = parser.retry.unwrap_or(BACKOFF);
+                }
+                eprintln!("api server stream {} broken: {}", addr, e);
+                probe.unready(&e);
+            }
+            Err(e) => {
+                eprintln!("connect api server {} error: {}", addr, e);
+                probe.unready(&e.to_string());
+            }
+        }
+        thread::sleep(backoff);
+        backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+// handle the events of the stream until it breaks, the lines read and why it broke
+fn follow(mut reader: sse::Stream, parser: &mut Parser, node_name: &str) -> (usize, String) {
+    let mut lines = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => return (lines, "closed by the api server".to_string()),
+            Ok(_) => lines += 1,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return (lines, "no heartbeat within the timeout".to_string())
+            }
+            Err(e) => return (lines, e.to_string()),
+        }
+        if let Some(event) = parser.line(&line) {
+            println!("recv task {:?}", event);
+            handle(&event.data, node_name);
+        }
+    }
+}
+
+fn handle(data: &str, node_name: &str) {
+    let request = match serde_json::from_str::<ApiServerRequest>(data) {
         Ok(it) => it,
         Err(e) => {
-            eprintln!("{:?}", e);
+            eprintln!("recv event parse json error: {:?} \n data: {:?}", e, data);
             return;
         }
     };
+    if !request.has_node_events(node_name) {
+        return;
+    }
+    if let Err(e) = request.check() {
+        eprintln!(
+            "recv api server invalid task: {}, request: {:?}",
+            e, request
+        );
+        for task in rejected_tasks(&request, node_name, &e.to_string()) {
+            reject_task(&task);
+        }
+        return;
+    }
 
-    for event in event_sources.receiver().iter() {
-        println!("recv task {:?}", event);
-        let request = match serde_json::from_str::<ApiServerRequest>(&event.data) {
-            Ok(it) => it,
+    output::registry_output(&request.output.to_output());
+
+    for task in request.to_pod_tasks() {
+        if request.op == RUN {
+            run_task(&task);
+        } else if request.op == STOP {
+            stop_task(&task);
+        } else {
+            println!("recv api server unknown event: {:?}", request)
+        }
+    }
+}
+
+// tasks_url lists the desired tasks of the node, reconciling them after a connect
+// makes up for the run and stop events missed while the stream was down
+fn resync(tasks_url: &str, node_name: &str, timeout: Duration) -> Result<()> {
+    let (desired, rejected) = desired_tasks(&sse::get(tasks_url, timeout)?, node_name)?;
+    for task in rejected.iter() {
+        reject_task(task);
+    }
+    reconcile_tasks(desired);
+    Ok(())
+}
+
+// follow_desired_tasks reconciles the tasks of the node with the desired ones every interval
+// until the process exits, source is a url of the api server or a local file listing them
+pub(crate) fn follow_desired_tasks(
+    source: &str,
+    node_name: &str,
+    interval: Duration,
+    timeout: Duration,
+) {
+    let probe = health::Probe::new("api_server");
+    loop {
+        let body = if source.starts_with("http://") || source.starts_with("https://") {
+            sse::get(source, timeout)
+        } else {
+            fs::read_to_string(source).map_err(|e| e.into())
+        };
+        // the tasks are left as they are while the source is unavailable
+        match body.and_then(|body| desired_tasks(&body, node_name)) {
+            Ok((desired, rejected)) => {
+                probe.ready();
+                for task in rejected.iter() {
+                    reject_task(task);
+                }
+                reconcile_tasks(desired);
+            }
             Err(e) => {
-                eprintln!(
-                    "recv event parse json error or connect to api server error: {:?} \n data: {:?}",
-                    e, &event.data
-                );
-                continue;
+                eprintln!("desired tasks from {} error: {}", source, e);
+                probe.unready(&e.to_string());
             }
-        };
-        if !request.has_node_events(&node_name) {
-            continue;
         }
+        thread::sleep(interval);
+    }
+}
 
-        output::registry_output(request.output);
-
-        for task in request.to_pod_tasks() {
-            if request.op == RUN {
-                run_task(&task);
-            } else if request.op == STOP {
-                stop_task(&task);
-            } else {
-                println!("recv api server unknown event: {:?}", request)
+// the tasks of the pods of the node in a json list of run requests, the full set of tasks
+// that should run on it, and the tasks of the requests with invalid rules that are left out
+pub(crate) fn desired_tasks(body: &str, node_name: &str) -> Result<(Vec<Task>, Vec<Task>)> {
+    let requests = serde_json::from_str::<Vec<ApiServerRequest>>(body)?;
+    let mut desired = vec![];
+    let mut rejected = vec![];
+    for request in requests.iter() {
+        if request.op != RUN {
+            eprintln!("desired task is not a run request: {:?}", request);
+            continue;
+        }
+        if !request.has_node_events(node_name) {
+            continue;
+        }
+        if let Err(e) = request.check() {
+            eprintln!("desired task invalid: {}, request: {:?}", e, request);
+            rejected.extend(rejected_tasks(request, node_name, &e.to_string()));
+            continue;
+        }
+        output::registry_output(&request.output.to_output());
+        for (pod, task) in request.pods.iter().zip(request.to_pod_tasks()) {
+            if pod.node == node_name {
+                desired.push(task);
             }
         }
     }
+    Ok((desired, rejected))
+}
+
+// the tasks of the pods of the node carrying the error that rejected the request
+fn rejected_tasks(request: &ApiServerRequest, node_name: &str, error: &str) -> Vec<Task> {
+    request
+        .pods
+        .iter()
+        .zip(request.to_pod_tasks())
+        .filter(|(pod, _)| pod.node == node_name)
+        .map(|(_, mut task)| {
+            task.error = error.to_string();
+            task
+        })
+        .collect::<Vec<Task>>()
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -56,16 +212,39 @@ pub(crate) struct RequestPod<'a> {
 
 //{"op":"run","ns":"default","service_name":"xx_service","rules":"","output":"fake_output","pods":[{"node":"node1","pod":"xx","ips":["127.0.0.1"],"offset":0}]}
 //{"op":"stop","ns":"default","service_name":"xx_service","rules":"","output":"fake_output","pods":[{"node":"node1","pod":"xx","ips":["127.0.0.1"],"offset":0}]}
+// rules is a json encoded file::Rules, e.g. joining java stack traces:
+//{"op":"run","ns":"default","service_name":"xx_service","rules":"{\"multiline\":{\"continuation\":\"^\\\\s+at \"}}","output":"fake_output","pods":[{"node":"node1","pod":"xx","ips":["127.0.0.1"],"offset":0}]}
+// output is a channel or a list of channels the logs go to at the same time:
+//{"op":"run","ns":"default","service_name":"xx_service","rules":"","output":["kafka:logs@kafka:9092","file:/data/harvest/{ns}/{pod}.log"],"pods":[{"node":"node1","pod":"xx","ips":["127.0.0.1"],"offset":0}]}
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct ApiServerRequest<'a> {
     op: &'a str,
     pub(crate) ns: &'a str,
-    pub(crate) output: &'a str,
+    #[serde(borrow)]
+    pub(crate) output: RequestOutput<'a>,
     pub(crate) rules: &'a str,
     pub(crate) service_name: &'a str,
     pub(crate) pods: Vec<RequestPod<'a>>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum RequestOutput<'a> {
+    Channel(&'a str),
+    #[serde(borrow)]
+    Channels(Vec<&'a str>),
+}
+
+impl<'a> RequestOutput<'a> {
+    // the pod output, see output::channels
+    pub fn to_output(&self) -> String {
+        match self {
+            RequestOutput::Channel(channel) => channel.to_string(),
+            RequestOutput::Channels(channels) => output::join_channels(channels),
+        }
+    }
+}
+
 impl<'a> ApiServerRequest<'a> {
     pub fn to_pod_tasks(&self) -> Vec<Task> {
         self.pods
@@ -73,7 +252,7 @@ impl<'a> ApiServerRequest<'a> {
             .map(|req_pod| {
                 let mut task = Task::from(req_pod.clone());
                 task.pod.ns = self.ns.to_string();
-                task.pod.output = self.output.to_string();
+                task.pod.output = self.output.to_output();
                 task.pod.service_name = self.service_name.to_string();
                 task.pod.filter = self.rules.to_string();
                 task
@@ -83,6 +262,17 @@ impl<'a> ApiServerRequest<'a> {
 }
 
 impl<'a> ApiServerRequest<'a> {
+    // a task to run must carry valid rules, stopping never depends on them
+    pub fn check(&self) -> Result<()> {
+        if self.op != RUN {
+            return Ok(());
+        }
+        if let Err(e) = file::Rules::parse(self.rules) {
+            return Err(format!("parse rules {:?} error: {}", self.rules, e).into());
+        }
+        Ok(())
+    }
+
     pub fn has_node_events(&self, node_name: &str) -> bool {
         for pod in self.pods.iter() {
             if pod.node == node_name {
@@ -112,6 +302,56 @@ pub(crate) fn query_pod() -> JsonValue {
     json!(db::all_to_json())
 }
 
+#[get("/outputs")]
+pub(crate) fn query_outputs() -> JsonValue {
+    json!(output::stats())
+}
+
+#[get("/masking")]
+pub(crate) fn query_masking() -> JsonValue {
+    json!(file::masked())
+}
+
+#[get("/limits")]
+pub(crate) fn query_limits() -> JsonValue {
+    json!(file::rate_limited())
+}
+
+// every metric of the agent in the prometheus text format
+#[get("/metrics")]
+pub(crate) fn query_metrics() -> Plain<String> {
+    Plain(common::metrics::render())
+}
+
+// 200 while every loop is live, 503 with the dead ones otherwise
+#[get("/healthz")]
+pub(crate) fn query_healthz() -> Custom<JsonValue> {
+    let (live, _, components) = health::check(&COMPONENTS);
+    Custom(
+        probe_status(live),
+        json!({ "ok": live, "components": components }),
+    )
+}
+
+// 200 while every loop is live and ready, e.g. the api server stream is open
+// and no output is failing, 503 otherwise
+#[get("/readyz")]
+pub(crate) fn query_readyz() -> Custom<JsonValue> {
+    let (_, ready, components) = health::check(&COMPONENTS);
+    Custom(
+        probe_status(ready),
+        json!({ "ok": ready, "components": components }),
+    )
+}
+
+fn probe_status(ok: bool) -> Status {
+    if ok {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    }
+}
+
 #[catch(404)]
 pub(crate) fn not_found() -> JsonValue {
     json!({
@@ -122,6 +362,41 @@ pub(crate) fn not_found() -> JsonValue {
 
 #[cfg(test)]
 mod tests {
+    use super::{desired_tasks, ApiServerRequest};
+
     #[test]
     fn it_works() {}
+
+    #[test]
+    fn it_parses_request_outputs() {
+        let request = r#"{"op":"run","ns":"default","service_name":"xx_service","rules":"","output":"fake_output","pods":[]}"#;
+        let request = serde_json::from_str::<ApiServerRequest>(request).unwrap();
+        assert_eq!(request.output.to_output(), "fake_output");
+
+        let request = r#"{"op":"run","ns":"default","service_name":"xx_service","rules":"","output":["fake_output","counter_output"],"pods":[]}"#;
+        let request = serde_json::from_str::<ApiServerRequest>(request).unwrap();
+        assert_eq!(
+            output::channels(&request.output.to_output()),
+            vec!["fake_output", "counter_output"]
+        );
+    }
+
+    #[test]
+    fn it_parses_desired_tasks() {
+        let body = r#"[
+            {"op":"run","ns":"default","service_name":"a","rules":"","output":"fake_output","pods":[{"node":"node1","pod":"a-0","ips":[],"offset":0},{"node":"node2","pod":"a-1","ips":[],"offset":0}]},
+            {"op":"run","ns":"default","service_name":"b","rules":"{","output":"fake_output","pods":[{"node":"node1","pod":"b-0","ips":[],"offset":0}]},
+            {"op":"stop","ns":"default","service_name":"c","rules":"","output":"fake_output","pods":[{"node":"node1","pod":"c-0","ips":[],"offset":0}]}
+        ]"#;
+        let (desired, rejected) = desired_tasks(body, "node1").unwrap();
+        assert_eq!(desired.len(), 1);
+        assert_eq!(desired[0].pod.pod_name, "a-0");
+        assert_eq!(desired[0].pod.output, "fake_output");
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].pod.pod_name, "b-0");
+        assert!(rejected[0].error.starts_with("parse rules"));
+
+        assert!(desired_tasks("[]", "node1").unwrap().0.is_empty());
+        assert!(desired_tasks("{}", "node1").is_err());
+    }
 }
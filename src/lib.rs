@@ -10,7 +10,9 @@ extern crate lazy_static;
 mod api;
 mod handle;
 mod server;
+mod sse;
 
+use common::health;
 use db::Pod;
 use event::{Dispatch, Listener};
 pub use serde_json;
@@ -52,6 +54,9 @@ pub(crate) trait GetTask {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub(crate) struct Task {
     pod: Pod,
+    // why the last run request of the pod was rejected, cleared once a request runs
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    error: String,
 }
 
 impl GetTask for Task {
@@ -60,6 +65,17 @@ impl GetTask for Task {
     }
 }
 
+impl Task {
+    // whether the other task ships the same pod the same way
+    fn same_spec(&self, other: &Task) -> bool {
+        self.pod.compare_ns_pod(&other.pod)
+            && self.pod.service_name == other.pod.service_name
+            && self.pod.output == other.pod.output
+            && self.pod.filter == other.pod.filter
+            && self.pod.ips == other.pod.ips
+    }
+}
+
 impl<'a> From<RequestPod<'a>> for Task {
     fn from(a: RequestPod) -> Self {
         let ips = a
@@ -74,6 +90,7 @@ impl<'a> From<RequestPod<'a>> for Task {
                 ips,
                 ..Default::default()
             },
+            error: "".to_string(),
         }
     }
 }
@@ -82,6 +99,7 @@ impl Default for Task {
     fn default() -> Self {
         Self {
             pod: Pod::default(),
+            error: "".to_string(),
         }
     }
 }
@@ -90,6 +108,10 @@ impl Default for Task {
 enum TaskMessage {
     Run(Task),
     Stop(Task),
+    // the full set of tasks that should run on the node
+    Reconcile(Vec<Task>),
+    // a task the api server asked to run with an invalid request, its error tells why
+    Reject(Task),
     Close,
 }
 #[derive(AsRefStr, Debug, Clone)]
@@ -155,59 +177,33 @@ impl TaskStorage {
         let thread_tasks = Arc::clone(&data);
         let t_dispatchers = Arc::clone(&dispatchers);
         task::spawn(async move {
+            let _probe = health::Probe::new("tasks");
             while let Ok(task_message) = rx.recv() {
-                match task_message {
-                    TaskMessage::Close => {
-                        return;
+                if let TaskMessage::Close = task_message {
+                    return;
+                }
+                let mut tasks = match thread_tasks.write() {
+                    Ok(it) => it,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
                     }
-                    TaskMessage::Run(mut task) => {
-                        let mut tasks = match thread_tasks.write() {
-                            Ok(it) => it,
-                            Err(e) => {
-                                eprintln!("{}", e);
-                                continue;
-                            }
-                        };
-                        for (_, mut pod) in
-                            db::get_slice_with_ns_pod(&task.pod.ns, &task.pod.pod_name)
-                        {
-                            pod.merge_with(&task.pod);
-                            pod.upload();
-                            pod.set_state_run();
-
-                            task.pod = pod;
-                            tasks
-                                .entry(task.pod.pod_name.clone())
-                                .or_insert(task.clone());
-                            match t_dispatchers.write() {
-                                Ok(mut dispatch) => dispatch.dispatch_run_event(&task),
-                                Err(e) => eprintln!("{}", e),
-                            }
+                };
+                match task_message {
+                    TaskMessage::Run(task) => run(&mut tasks, &t_dispatchers, &task),
+                    TaskMessage::Stop(task) => stop(&mut tasks, &t_dispatchers, &task),
+                    TaskMessage::Reject(task) => reject(&mut tasks, &task),
+                    TaskMessage::Reconcile(desired) => {
+                        let (to_stop, to_run) = plan(&tasks, &desired);
+                        for task in to_stop.iter() {
+                            stop(&mut tasks, &t_dispatchers, task);
                         }
-                    }
-                    TaskMessage::Stop(mut task) => {
-                        let mut tasks = match thread_tasks.write() {
-                            Ok(it) => it,
-                            Err(e) => {
-                                eprintln!("{}", e);
-                                continue;
-                            }
-                        };
-                        for (_, mut pod) in
-                            db::get_slice_with_ns_pod(&task.pod.ns, &task.pod.pod_name)
-                        {
-                            pod.un_upload();
-                            pod.set_state_stop();
-                            task.pod = pod;
-                            tasks
-                                .entry(task.pod.pod_name.clone())
-                                .or_insert(task.clone());
-                            match t_dispatchers.write() {
-                                Ok(mut dispatch) => dispatch.dispatch_stop_event(&task),
-                                Err(e) => eprintln!("{}", e),
-                            }
+                        for task in to_run.iter() {
+                            run(&mut tasks, &t_dispatchers, task);
                         }
                     }
+                    // returned above
+                    TaskMessage::Close => {}
                 }
             }
         });
@@ -219,6 +215,110 @@ impl TaskStorage {
     }
 }
 
+// run the task on every file of its pod, the task is recorded as requested, not as merged
+// into the pods of the files, so that plan compares it with the desired task it came from
+fn run(
+    tasks: &mut HashMap<String, Task>,
+    dispatchers: &Arc<RwLock<TaskStorageEventDispatcher>>,
+    task: &Task,
+) {
+    let pods = db::get_slice_with_ns_pod(&task.pod.ns, &task.pod.pod_name);
+    if pods.is_empty() {
+        return;
+    }
+    let mut recorded = task.clone();
+    recorded.pod.upload();
+    recorded.pod.set_state_run();
+    tasks.insert(recorded.pod.pod_name.clone(), recorded);
+
+    for (_, mut pod) in pods {
+        // keep the checkpointed offset when the api server sends none
+        let offset = pod.offset;
+        pod.merge_with(&task.pod);
+        if task.pod.offset == 0 {
+            pod.offset = offset;
+        }
+        pod.upload();
+        pod.set_state_run();
+
+        let task = Task {
+            pod,
+            ..Default::default()
+        };
+        match dispatchers.write() {
+            Ok(mut dispatch) => dispatch.dispatch_run_event(&task),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+fn stop(
+    tasks: &mut HashMap<String, Task>,
+    dispatchers: &Arc<RwLock<TaskStorageEventDispatcher>>,
+    task: &Task,
+) {
+    let pods = db::get_slice_with_ns_pod(&task.pod.ns, &task.pod.pod_name);
+    if pods.is_empty() {
+        return;
+    }
+    let mut recorded = match tasks.get(&task.pod.pod_name) {
+        Some(current) => current.clone(),
+        None => task.clone(),
+    };
+    recorded.pod.un_upload();
+    recorded.pod.set_state_stop();
+    tasks.insert(recorded.pod.pod_name.clone(), recorded);
+
+    for (_, mut pod) in pods {
+        pod.un_upload();
+        pod.set_state_stop();
+
+        let task = Task {
+            pod,
+            ..Default::default()
+        };
+        match dispatchers.write() {
+            Ok(mut dispatch) => dispatch.dispatch_stop_event(&task),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+// record why the task was not run so that /tasks shows it, a task of the pod that
+// runs already keeps running as it is
+fn reject(tasks: &mut HashMap<String, Task>, task: &Task) {
+    let mut recorded = match tasks.get(&task.pod.pod_name) {
+        Some(current) => current.clone(),
+        None => task.clone(),
+    };
+    recorded.error = task.error.clone();
+    tasks.insert(recorded.pod.pod_name.clone(), recorded);
+}
+
+// the tasks to stop and the tasks to run so that exactly the desired tasks run,
+// a desired task that runs with another output, rules or ips is stopped and run again
+fn plan(tasks: &HashMap<String, Task>, desired: &[Task]) -> (Vec<Task>, Vec<Task>) {
+    let mut to_stop = vec![];
+    let mut to_run = vec![];
+    for task in desired.iter() {
+        match tasks.get(&task.pod.pod_name) {
+            Some(current) if current.pod.is_running() && current.same_spec(task) => continue,
+            Some(current) if current.pod.is_running() => to_stop.push(current.clone()),
+            _ => {}
+        }
+        to_run.push(task.clone());
+    }
+    for current in tasks.values().filter(|task| task.pod.is_running()) {
+        if !desired
+            .iter()
+            .any(|task| current.pod.compare_ns_pod(&task.pod))
+        {
+            to_stop.push(current.clone());
+        }
+    }
+    (to_stop, to_run)
+}
+
 lazy_static! {
     static ref TASKS: TaskStorage = {
         let task_storage = TaskStorage::new(new_arc_rwlock(TaskStorageEventDispatcher::new()));
@@ -234,6 +334,15 @@ pub(crate) fn stop_task(task: &Task) {
     TASKS.tx.send(TaskMessage::Stop(task.clone())).unwrap();
 }
 
+pub(crate) fn reject_task(task: &Task) {
+    TASKS.tx.send(TaskMessage::Reject(task.clone())).unwrap();
+}
+
+// reconcile the running tasks with the desired ones of the node, see plan
+pub(crate) fn reconcile_tasks(desired: Vec<Task>) {
+    TASKS.tx.send(TaskMessage::Reconcile(desired)).unwrap();
+}
+
 pub(crate) fn task_close() {
     TASKS.tx.send(TaskMessage::Close).unwrap();
 }
@@ -284,8 +393,129 @@ pub(crate) fn get_pod_task(pod_name: &str) -> Option<Task> {
 
 #[cfg(test)]
 mod tests {
+    use super::{new_arc_rwlock, plan, reject, run, Task, TaskStorageEventDispatcher};
+    use db::Pod;
+    use std::collections::HashMap;
+    use std::thread;
+    use std::time::Duration;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn task(pod_name: &str, output: &str, running: bool) -> Task {
+        let mut pod = Pod {
+            ns: "default".to_string(),
+            pod_name: pod_name.to_string(),
+            output: output.to_string(),
+            ..Default::default()
+        };
+        if running {
+            pod.set_state_run();
+        }
+        Task {
+            pod,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_plans_reconciliation() {
+        let tasks = vec![
+            task("same", "fake_output", true),
+            task("changed", "fake_output", true),
+            task("stopped", "fake_output", false),
+            task("undesired", "fake_output", true),
+        ]
+        .into_iter()
+        .map(|task| (task.pod.pod_name.clone(), task))
+        .collect::<HashMap<String, Task>>();
+        let desired = vec![
+            task("same", "fake_output", false),
+            task("changed", "counter_output", false),
+            task("stopped", "fake_output", false),
+            task("new", "fake_output", false),
+        ];
+
+        let (to_stop, to_run) = plan(&tasks, &desired);
+        let names = |tasks: &[Task]| {
+            let mut names = tasks
+                .iter()
+                .map(|task| task.pod.pod_name.clone())
+                .collect::<Vec<String>>();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&to_stop), vec!["changed", "undesired"]);
+        assert_eq!(names(&to_run), vec!["changed", "new", "stopped"]);
+        assert_eq!(
+            to_run
+                .iter()
+                .find(|task| task.pod.pod_name == "changed")
+                .map(|task| task.pod.output.as_str()),
+            Some("counter_output")
+        );
+
+        // a desired task that runs as it should is left alone
+        let (to_stop, to_run) = plan(&tasks, &[task("same", "fake_output", false)]);
+        assert_eq!(
+            (names(&to_stop), names(&to_run)),
+            (vec!["changed".to_string(), "undesired".to_string()], vec![])
+        );
+    }
+
+    #[test]
+    fn it_keeps_running_tasks_on_reconcile() {
+        // the scanner knows neither the ips nor, with cri, the service of the pod
+        let scanned = Pod {
+            ns: "test-reconcile".to_string(),
+            pod_name: "web-0".to_string(),
+            container: "web".to_string(),
+            path: "/var/log/pods/test-reconcile_web-0/web/0.log".to_string(),
+            ..Default::default()
+        };
+        db::insert(&scanned);
+        for _ in 0..100 {
+            if db::get(&scanned.path).is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut desired = Task {
+            pod: Pod {
+                ns: "test-reconcile".to_string(),
+                pod_name: "web-0".to_string(),
+                service_name: "web".to_string(),
+                output: "fake_output".to_string(),
+                ips: vec!["10.0.0.1".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let dispatchers = new_arc_rwlock(TaskStorageEventDispatcher::new());
+        let mut tasks = HashMap::new();
+        run(&mut tasks, &dispatchers, &desired);
+        assert!(tasks["web-0"].pod.is_running());
+
+        // a rejected request leaves the running task as it is and records why
+        let rejected = Task {
+            error: "parse rules error".to_string(),
+            ..desired.clone()
+        };
+        reject(&mut tasks, &rejected);
+        assert!(tasks["web-0"].pod.is_running());
+        assert_eq!(tasks["web-0"].error, "parse rules error");
+        run(&mut tasks, &dispatchers, &desired);
+        assert_eq!(tasks["web-0"].error, "");
+
+        let (to_stop, to_run) = plan(&tasks, &[desired.clone()]);
+        assert!(to_stop.is_empty() && to_run.is_empty());
+
+        // a changed output restarts it
+        desired.pod.output = "counter_output".to_string();
+        let (to_stop, to_run) = plan(&tasks, &[desired]);
+        assert_eq!((to_stop.len(), to_run.len()), (1, 1));
+    }
 }
@@ -1,5 +1,10 @@
-use common::Result;
+use common::{Result, Runtime};
+use file::{MaskingRule, RateLimitRule};
 use harvest::Harvest;
+use output::{Route, SpoolConfig};
+use std::collections::HashMap;
+use std::fs;
+use std::time::Duration;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -12,13 +17,86 @@ pub struct ServerOptions {
     #[structopt(short = "s", long)]
     api_server: String,
 
-    // short and long flags (-d, --docker-dir) will be deduced from the field's name
+    // short and long flags (-d, --docker-dir) will be deduced from the field's name,
+    // with the cri runtime this is the kubelet pod log dir, e.g. /var/log/pods
     #[structopt(short = "d", long)]
     docker_dir: String,
 
+    // short and long flags (-r, --runtime) will be deduced from the field's name
+    #[structopt(short = "r", long, default_value = "docker")]
+    runtime: Runtime,
+
     // short and long flags (-h, --node) will be deduced from the field's name
     #[structopt(short = "h", long)]
     host: String,
+
+    // long flag (--state-dir) will be deduced from the field's name
+    #[structopt(long, default_value = "/var/lib/harvest")]
+    state_dir: String,
+
+    // long flag (--spool-dir) will be deduced from the field's name,
+    // outputs spool to disk below it while their queue is full, no spool when empty
+    #[structopt(long, default_value = "")]
+    spool_dir: String,
+
+    // long flag (--spool-max-bytes) will be deduced from the field's name, per output
+    #[structopt(long, default_value = "1073741824")]
+    spool_max_bytes: u64,
+
+    // long flag (--spool-max-age) will be deduced from the field's name, in seconds
+    #[structopt(long, default_value = "86400")]
+    spool_max_age: u64,
+
+    // long flag (--routes) will be deduced from the field's name,
+    // a json file of output::Route sending matching logs to more outputs, no routes when empty
+    #[structopt(long, default_value = "")]
+    routes: String,
+
+    // long flag (--masking) will be deduced from the field's name,
+    // a json file of namespace to file::MaskingRule, `*` is any other namespace, no masking when empty
+    #[structopt(long, default_value = "")]
+    masking: String,
+
+    // long flag (--rate-limits) will be deduced from the field's name,
+    // a json file of file::RateLimitRule limiting the pods matching them, no limits when empty
+    #[structopt(long, default_value = "")]
+    rate_limits: String,
+
+    // long flag (--read-workers) will be deduced from the field's name,
+    // the threads following the log files, one per cpu when 0
+    #[structopt(long, default_value = "0")]
+    read_workers: usize,
+
+    // long flag (--lag-interval) will be deduced from the field's name,
+    // how often the tracked files are stat'ed for their lag, in seconds
+    #[structopt(long, default_value = "10")]
+    lag_interval: u64,
+
+    // long flag (--lag-threshold) will be deduced from the field's name,
+    // a file whose lag grows for longer than this is flagged lagging, in seconds
+    #[structopt(long, default_value = "300")]
+    lag_threshold: u64,
+
+    // long flag (--heartbeat-timeout) will be deduced from the field's name, in seconds,
+    // the api server stream is reconnected when not even a heartbeat arrives for this long
+    #[structopt(long, default_value = "60")]
+    heartbeat_timeout: u64,
+
+    // long flag (--tasks-url) will be deduced from the field's name, a url of the api server
+    // listing the run requests of every task that should be running, resynced after every
+    // connect of the stream, no resync when empty
+    #[structopt(long, default_value = "")]
+    tasks_url: String,
+
+    // long flag (--desired-tasks) will be deduced from the field's name, a url of the api server
+    // or a local file listing the run requests of every task that should be running, polled and
+    // reconciled instead of following the run and stop events of the api server, off when empty
+    #[structopt(long, default_value = "")]
+    desired_tasks: String,
+
+    // long flag (--desired-interval) will be deduced from the field's name, in seconds
+    #[structopt(long, default_value = "30")]
+    desired_interval: u64,
 }
 // cargo run -- --namespace default --docker_dir /var/log/container --api-server http://localhost:9999/ --host node1
 
@@ -26,5 +104,52 @@ fn main() -> Result<()> {
     let opt = ServerOptions::from_args();
     println!("recv args {:?}", opt);
 
-    Harvest::new(&opt.namespace, &opt.docker_dir, &opt.api_server, &opt.host).start()
+    let spool = match opt.spool_dir.as_str() {
+        "" => None,
+        dir => Some(SpoolConfig {
+            dir: dir.to_string(),
+            max_bytes: opt.spool_max_bytes,
+            max_age: Duration::from_secs(opt.spool_max_age),
+            ..Default::default()
+        }),
+    };
+
+    let routes = match opt.routes.as_str() {
+        "" => vec![],
+        path => Route::parse(&fs::read_to_string(path)?)?,
+    };
+
+    let masking = match opt.masking.as_str() {
+        "" => HashMap::new(),
+        path => MaskingRule::parse_namespaces(&fs::read_to_string(path)?)?,
+    };
+
+    let rate_limits = match opt.rate_limits.as_str() {
+        "" => vec![],
+        path => RateLimitRule::parse(&fs::read_to_string(path)?)?,
+    };
+
+    Harvest::new(
+        &opt.namespace,
+        &opt.docker_dir,
+        &opt.api_server,
+        &opt.host,
+        &opt.state_dir,
+        opt.runtime,
+        spool,
+    )
+    .read_workers(opt.read_workers)
+    .routes(routes)
+    .masking(masking)
+    .rate_limits(rate_limits)
+    .lag(
+        Duration::from_secs(opt.lag_interval),
+        Duration::from_secs(opt.lag_threshold),
+    )
+    .task_stream(Duration::from_secs(opt.heartbeat_timeout), &opt.tasks_url)
+    .desired_tasks(
+        &opt.desired_tasks,
+        Duration::from_secs(opt.desired_interval),
+    )
+    .start()
 }
@@ -1,16 +1,33 @@
 use super::*;
 use async_std::task;
-use common::new_arc_mutex;
-use file::FileReaderWriter;
+use common::{new_arc_mutex, Runtime};
+use file::{FileReaderWriter, MaskingRule, RateLimitRule};
+use output::{Route, SpoolConfig};
 use rocket::config::{Config, Environment};
 use rocket::routes;
 use scan::AutoScanner;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
 
 pub struct Harvest<'a> {
     node_name: &'a str,
     namespace: &'a str,
     docker_dir: &'a str,
     api_server_addr: &'a str,
+    state_dir: &'a str,
+    runtime: Runtime,
+    spool: Option<SpoolConfig>,
+    routes: Vec<Route>,
+    masking: HashMap<String, MaskingRule>,
+    rate_limits: Vec<RateLimitRule>,
+    lag_interval: Duration,
+    lag_threshold: Duration,
+    heartbeat_timeout: Duration,
+    tasks_url: &'a str,
+    desired_tasks: &'a str,
+    desired_interval: Duration,
+    read_workers: usize,
 }
 
 impl<'a> Harvest<'a> {
@@ -19,22 +36,100 @@ impl<'a> Harvest<'a> {
         docker_dir: &'a str,
         api_server_addr: &'a str,
         node_name: &'a str,
+        state_dir: &'a str,
+        runtime: Runtime,
+        spool: Option<SpoolConfig>,
     ) -> Self {
         Self {
             namespace,
             docker_dir,
             node_name,
             api_server_addr,
+            state_dir,
+            runtime,
+            spool,
+            routes: vec![],
+            masking: HashMap::new(),
+            rate_limits: vec![],
+            lag_interval: Duration::from_secs(10),
+            lag_threshold: Duration::from_secs(300),
+            heartbeat_timeout: Duration::from_secs(60),
+            tasks_url: "",
+            desired_tasks: "",
+            desired_interval: Duration::from_secs(30),
+            read_workers: 0,
         }
     }
 
+    // follow the log files with this many threads, one per cpu when 0
+    pub fn read_workers(mut self, read_workers: usize) -> Self {
+        self.read_workers = read_workers;
+        self
+    }
+
+    // route the logs of every pod to the outputs of the matching routes too
+    pub fn routes(mut self, routes: Vec<Route>) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    // mask the logs of the namespaces whose tasks have no masking of their own
+    pub fn masking(mut self, masking: HashMap<String, MaskingRule>) -> Self {
+        self.masking = masking;
+        self
+    }
+
+    // limit the lines and bytes the matching pods ship when their tasks have no limit
+    pub fn rate_limits(mut self, rate_limits: Vec<RateLimitRule>) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    // stat the tracked files every interval, flag those whose lag grows for longer than threshold
+    pub fn lag(mut self, interval: Duration, threshold: Duration) -> Self {
+        self.lag_interval = interval;
+        self.lag_threshold = threshold;
+        self
+    }
+
+    // reconnect the task stream once no heartbeat arrived for heartbeat_timeout,
+    // resync the tasks from tasks_url after every connect when it is not empty
+    pub fn task_stream(mut self, heartbeat_timeout: Duration, tasks_url: &'a str) -> Self {
+        self.heartbeat_timeout = heartbeat_timeout;
+        self.tasks_url = tasks_url;
+        self
+    }
+
+    // reconcile the tasks with the desired ones listed by source, a url or a local file,
+    // every interval instead of following the run and stop events, off when source is empty
+    pub fn desired_tasks(mut self, source: &'a str, interval: Duration) -> Self {
+        self.desired_tasks = source;
+        self.desired_interval = interval;
+        self
+    }
+
     pub fn start(&mut self) -> Result<()> {
+        // reload checkpointed offsets before the scanner inserts any pod
+        db::open_checkpoint(self.state_dir)?;
+
+        // outputs registered by the tasks spool to disk while their queue is full
+        if let Some(spool) = self.spool.take() {
+            output::enable_spool(spool);
+        }
+        // after the spool so that the outputs of the routes spool as well
+        output::set_routes(std::mem::take(&mut self.routes));
+
         let scanner = new_arc_rwlock(AutoScanner::new(
             String::from(self.namespace),
             String::from(self.docker_dir),
+            self.runtime,
         ));
 
-        let frw = new_arc_mutex(FileReaderWriter::new(0));
+        let frw = new_arc_mutex(
+            FileReaderWriter::new(self.read_workers, self.runtime)
+                .masking(std::mem::take(&mut self.masking))
+                .rate_limits(std::mem::take(&mut self.rate_limits)),
+        );
 
         if let Ok(mut scan) = scanner.write() {
             // registry scanner event handle
@@ -51,9 +146,22 @@ impl<'a> Harvest<'a> {
         registry_task_run_event_listener(TaskRunEvent(frw.clone()));
         registry_task_stop_event_listener(TaskStopEvent(frw.clone()));
 
+        // report how far behind the end of its file every pod is
+        let (interval, threshold) = (self.lag_interval, self.lag_threshold);
+        thread::spawn(move || {
+            let mut lag = db::LagTracker::new(threshold);
+            loop {
+                lag.check();
+                thread::sleep(interval);
+            }
+        });
+
         let mut tasks = vec![];
         // start auto scanner with a new async
         tasks.push(task::spawn(async move {
+            // dead once the watch loop returns or panics
+            let probe = health::Probe::new("scanner");
+            probe.unready("preparing");
             let mut scan = match scanner.write() {
                 Ok(it) => it,
                 Err(e) => {
@@ -74,6 +182,7 @@ impl<'a> Harvest<'a> {
             for item in res.iter() {
                 db::insert(&item.to_pod())
             }
+            probe.ready();
 
             if let Err(e) = scan.watch_start() {
                 panic!("{:?}", e);
@@ -88,14 +197,40 @@ impl<'a> Harvest<'a> {
                 .unwrap();
 
             rocket::custom(cfg)
-                .mount("/", routes![query_pod, query_tasks])
+                .mount(
+                    "/",
+                    routes![
+                        query_pod,
+                        query_tasks,
+                        query_outputs,
+                        query_masking,
+                        query_limits,
+                        query_metrics,
+                        query_healthz,
+                        query_readyz
+                    ],
+                )
                 .register(catchers![not_found])
                 .launch();
         }));
 
         let api_server_addr = self.api_server_addr.to_string().clone();
         let node_name = self.node_name.to_string().clone();
-        recv_tasks(&api_server_addr, &node_name);
+        // follows the tasks of the node until the process exits
+        match self.desired_tasks {
+            "" => recv_tasks(
+                &api_server_addr,
+                &node_name,
+                self.heartbeat_timeout,
+                self.tasks_url,
+            ),
+            source => follow_desired_tasks(
+                source,
+                &node_name,
+                self.desired_interval,
+                self.heartbeat_timeout,
+            ),
+        }
 
         for _ in tasks {}
 
@@ -0,0 +1,196 @@
+use common::Result;
+use std::io::{BufReader, Read};
+use std::time::Duration;
+
+// Event is one server-sent event, see https://html.spec.whatwg.org/multipage/server-sent-events.html
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Event {
+    pub(crate) id: String,
+    pub(crate) event: String,
+    pub(crate) data: String,
+}
+
+// Parser builds the events of a stream line by line, the id of the last event
+// and the reconnection time the server asked for outlive the events
+#[derive(Debug, Default)]
+pub(crate) struct Parser {
+    pub(crate) last_event_id: Option<String>,
+    pub(crate) retry: Option<Duration>,
+    event: String,
+    data: Vec<String>,
+}
+
+impl Parser {
+    // the event completed by the line, an empty line ends an event
+    pub(crate) fn line(&mut self, line: &str) -> Option<Event> {
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if line.is_empty() {
+            let event = std::mem::take(&mut self.event);
+            let data = std::mem::take(&mut self.data);
+            if data.is_empty() {
+                return None;
+            }
+            return Some(Event {
+                id: self.last_event_id.clone().unwrap_or_default(),
+                event: if event.is_empty() {
+                    "message".to_string()
+                } else {
+                    event
+                },
+                data: data.join("\n"),
+            });
+        }
+        // a comment, e.g. the heartbeat of the server
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "data" => self.data.push(value.to_string()),
+            "event" => self.event = value.to_string(),
+            "id" if !value.contains('\0') => self.last_event_id = Some(value.to_string()),
+            "retry" => {
+                if let Ok(ms) = value.parse::<u64>() {
+                    self.retry = Some(Duration::from_millis(ms));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+// Stream is the body of a response, decoded when it is chunked
+pub(crate) type Stream = BufReader<Box<dyn Read + Send + Sync>>;
+
+// open the event stream of url, resuming after last_event_id when there is one,
+// a read waiting longer than timeout for a line, even a heartbeat, fails
+pub(crate) fn connect(url: &str, last_event_id: Option<&str>, timeout: Duration) -> Result<Stream> {
+    let mut headers = vec![
+        ("Accept", "text/event-stream"),
+        ("Cache-Control", "no-cache"),
+    ];
+    if let Some(id) = last_event_id {
+        headers.push(("Last-Event-ID", id));
+    }
+    request(url, &headers, timeout)
+}
+
+// the body of a GET of url
+pub(crate) fn get(url: &str, timeout: Duration) -> Result<String> {
+    let mut reader = request(url, &[("Accept", "application/json")], timeout)?;
+    let mut body = String::new();
+    reader.read_to_string(&mut body)?;
+    Ok(body)
+}
+
+// a GET of an http or https url answered with 200
+fn request(url: &str, headers: &[(&str, &str)], timeout: Duration) -> Result<Stream> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .timeout_write(timeout)
+        .build();
+    let mut request = agent.get(url);
+    for (name, value) in headers.iter() {
+        request = request.set(name, value);
+    }
+    let response = request.call()?;
+    if response.status() != 200 {
+        return Err(format!(
+            "GET {} answered {} {}",
+            url,
+            response.status(),
+            response.status_text()
+        )
+        .into());
+    }
+    Ok(BufReader::new(response.into_reader()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, Event, Parser};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn it_parses_events() {
+        let mut parser = Parser::default();
+        let events =
+            ": ping\nretry: 2000\nid: 7\nevent: run\ndata: {\"op\":\ndata: \"run\"}\n\ndata: x\n\n"
+                .lines()
+                .chain(std::iter::once(""))
+                .filter_map(|line| parser.line(line))
+                .collect::<Vec<Event>>();
+        assert_eq!(
+            events,
+            vec![
+                Event {
+                    id: "7".to_string(),
+                    event: "run".to_string(),
+                    data: "{\"op\":\n\"run\"}".to_string(),
+                },
+                Event {
+                    id: "7".to_string(),
+                    event: "message".to_string(),
+                    data: "x".to_string(),
+                },
+            ]
+        );
+        assert_eq!(parser.retry, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn it_resumes_after_the_last_event() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                request.push_str(&line);
+            }
+            stream
+                .write_all(
+                    // the chunks split the data line
+                    b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nTransfer-Encoding: chunked\r\n\r\n8\r\nid: 8\nda\r\n7\r\nta: y\n\n\r\n",
+                )
+                .unwrap();
+            // hold the connection open past the heartbeat timeout
+            let _ = stream.read(&mut [0; 1]);
+            request
+        });
+
+        let url = format!("http://{}/tasks", addr);
+        let mut reader = connect(&url, Some("7"), Duration::from_millis(200)).unwrap();
+        let mut parser = Parser::default();
+        let mut line = String::new();
+        let event = loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if let Some(event) = parser.line(&line) {
+                break event;
+            }
+        };
+        assert_eq!((event.id.as_str(), event.data.as_str()), ("8", "y"));
+        // no heartbeat within the timeout
+        assert!(reader.read_line(&mut line).is_err());
+        drop(reader);
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /tasks HTTP/1.1\r\n"));
+        assert!(request.to_lowercase().contains("last-event-id: 7\r\n"));
+    }
+}